@@ -0,0 +1,498 @@
+use crate::auth::in_prefix;
+use crate::connection::{ClientSubject, ConnectionInfo};
+use crate::{AccessConfig, AccessRules};
+use hyper::header::HeaderValue;
+use hyper::{HeaderMap, StatusCode};
+use std::net::{IpAddr, SocketAddr};
+
+// ip based access control. Within a set of rules the most specific matching range wins, with deny
+// winning ties. An address matching no range at all is allowed, unless there are allow ranges.
+// Rules can additionally require a verified client certificate with a given subject.
+pub struct Access {
+    global: Rules,
+    paths: Vec<(String, Rules)>,
+    trusted_proxies: Vec<Cidr>,
+}
+
+struct Rules {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    subjects: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Cidr {
+    network: u128,
+    prefix_len: u8,
+}
+
+impl Access {
+    pub fn new(cfg: &AccessConfig) -> std::io::Result<Access> {
+        let mut paths = cfg
+            .paths
+            .iter()
+            .map(|p| Ok((p.prefix.clone(), Rules::new(&p.rules)?)))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        // longest prefix first, so the most specific path wins
+        paths.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+
+        Ok(Access {
+            global: Rules::new(&cfg.global)?,
+            paths,
+            trusted_proxies: parse_all(&cfg.trusted_proxies)?,
+        })
+    }
+
+    // called for every new connection, false means it is dropped without a response. Connections
+    // from trusted proxies are let through, as their clients are only known per request.
+    pub fn accepts(&self, peer: &SocketAddr) -> bool {
+        let ip = peer.ip();
+        if self.is_trusted(&ip) {
+            return true;
+        }
+
+        let allowed = self.global.allows(&ip);
+        if !allowed {
+            log::warn!("dropping connection from {}", peer);
+        }
+        allowed
+    }
+
+    // path is expected to be normalized
+    pub fn check(
+        &self,
+        path: &str,
+        connection: &ConnectionInfo,
+        headers: &HeaderMap<HeaderValue>,
+    ) -> Result<(), StatusCode> {
+        let subject = connection.client.as_ref();
+        if !self.global.allows_subject(subject) {
+            log::warn!("refusing client certificate {:?}", subject);
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        let rules = self
+            .paths
+            .iter()
+            .find(|(prefix, _)| in_prefix(path, prefix));
+        if let Some((prefix, rules)) = rules {
+            if !rules.allows_subject(subject) {
+                log::warn!(
+                    "refusing client certificate {:?} access to {}",
+                    subject,
+                    prefix
+                );
+                return Err(StatusCode::FORBIDDEN);
+            }
+        }
+
+        let peer = match connection.peer {
+            Some(p) => p.ip(),
+            None => return Ok(()),
+        };
+
+        let client = if self.is_trusted(&peer) {
+            let client = self.forwarded_client(peer, headers)?;
+            if !self.global.allows(&client) {
+                log::warn!("refusing {} forwarded by {}", client, peer);
+                return Err(StatusCode::FORBIDDEN);
+            }
+            client
+        } else {
+            peer
+        };
+
+        match rules {
+            Some((prefix, rules)) if !rules.allows(&client) => {
+                log::warn!("refusing {} access to {}", client, prefix);
+                Err(StatusCode::FORBIDDEN)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|c| c.contains(ip))
+    }
+
+    // walks X-Forwarded-For from the nearest hop outwards, the first address not belonging to a
+    // trusted proxy is the client. Anything further out could have been made up by that client.
+    // A hop that isn't an address before reaching the client refuses the request, as the client
+    // can't be told.
+    fn forwarded_client(
+        &self,
+        peer: IpAddr,
+        headers: &HeaderMap<HeaderValue>,
+    ) -> Result<IpAddr, StatusCode> {
+        let hops: Vec<Option<&str>> = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .flat_map(|v| match v.to_str() {
+                Ok(v) => v.split(',').map(Some).collect(),
+                Err(_) => vec![None],
+            })
+            .collect();
+
+        let mut client = peer;
+        for hop in hops.into_iter().rev() {
+            client = match hop.and_then(|h| h.trim().parse::<IpAddr>().ok()) {
+                Some(ip) => ip,
+                None => {
+                    log::warn!("refusing malformed X-Forwarded-For from {}", peer);
+                    return Err(StatusCode::FORBIDDEN);
+                }
+            };
+            if !self.is_trusted(&client) {
+                break;
+            }
+        }
+        Ok(client)
+    }
+}
+
+impl Rules {
+    fn new(cfg: &AccessRules) -> std::io::Result<Rules> {
+        Ok(Rules {
+            allow: parse_all(&cfg.allow)?,
+            deny: parse_all(&cfg.deny)?,
+            subjects: cfg.subjects.clone(),
+        })
+    }
+
+    fn allows_subject(&self, subject: Option<&ClientSubject>) -> bool {
+        if self.subjects.is_empty() {
+            return true;
+        }
+        match subject {
+            Some(subject) => self.subjects.iter().any(|s| subject.matches(s)),
+            None => false,
+        }
+    }
+
+    fn allows(&self, ip: &IpAddr) -> bool {
+        let best = |ranges: &[Cidr]| {
+            ranges
+                .iter()
+                .filter(|c| c.contains(ip))
+                .map(|c| c.prefix_len)
+                .max()
+        };
+
+        match (best(&self.allow), best(&self.deny)) {
+            (Some(allow), Some(deny)) => allow > deny,
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => self.allow.is_empty(),
+        }
+    }
+}
+
+impl Cidr {
+    fn parse(s: &str) -> Option<Cidr> {
+        let (ip, len) = match s.split_once('/') {
+            Some((ip, len)) => (ip.parse::<IpAddr>().ok()?, Some(len.parse::<u8>().ok()?)),
+            None => (s.parse::<IpAddr>().ok()?, None),
+        };
+
+        // v4 addresses are stored as v4 mapped v6 addresses, so one comparison covers both
+        let (bits, prefix_len) = match ip {
+            IpAddr::V4(_) => {
+                let len = len.unwrap_or(32);
+                if len > 32 {
+                    return None;
+                }
+                (to_bits(&ip), len + 96)
+            }
+            IpAddr::V6(_) => {
+                let len = len.unwrap_or(128);
+                if len > 128 {
+                    return None;
+                }
+                (to_bits(&ip), len)
+            }
+        };
+
+        Some(Cidr {
+            network: bits & mask(prefix_len),
+            prefix_len,
+        })
+    }
+
+    fn contains(&self, ip: &IpAddr) -> bool {
+        to_bits(ip) & mask(self.prefix_len) == self.network
+    }
+}
+
+fn to_bits(ip: &IpAddr) -> u128 {
+    match ip {
+        IpAddr::V4(v4) => u128::from(v4.to_ipv6_mapped()),
+        IpAddr::V6(v6) => u128::from(*v6),
+    }
+}
+
+fn mask(prefix_len: u8) -> u128 {
+    match prefix_len {
+        0 => 0,
+        n => !0u128 << (128 - u32::from(n)),
+    }
+}
+
+fn parse_all(ranges: &[String]) -> std::io::Result<Vec<Cidr>> {
+    ranges
+        .iter()
+        .map(|r| {
+            Cidr::parse(r).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("invalid address range {}", r),
+                )
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PathAccess;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn peer(s: &str) -> SocketAddr {
+        SocketAddr::new(ip(s), 1234)
+    }
+
+    fn from(s: &str) -> ConnectionInfo {
+        ConnectionInfo {
+            peer: Some(peer(s)),
+            client: None,
+        }
+    }
+
+    fn strings(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| String::from(*s)).collect()
+    }
+
+    fn rules(allow: &[&str], deny: &[&str]) -> AccessRules {
+        AccessRules {
+            allow: strings(allow),
+            deny: strings(deny),
+            subjects: vec![],
+        }
+    }
+
+    #[test]
+    fn cidr() {
+        let c = Cidr::parse("10.1.0.0/16").unwrap();
+        assert!(c.contains(&ip("10.1.2.3")));
+        assert!(!c.contains(&ip("10.2.0.1")));
+        assert!(c.contains(&ip("::ffff:10.1.2.3")));
+
+        let c = Cidr::parse("fd00::/8").unwrap();
+        assert!(c.contains(&ip("fd12::1")));
+        assert!(!c.contains(&ip("fe80::1")));
+
+        let c = Cidr::parse("192.168.1.1").unwrap();
+        assert!(c.contains(&ip("192.168.1.1")));
+        assert!(!c.contains(&ip("192.168.1.2")));
+
+        assert!(Cidr::parse("0.0.0.0/0").unwrap().contains(&ip("1.2.3.4")));
+        assert_eq!(None, Cidr::parse("10.0.0.0/33"));
+        assert_eq!(None, Cidr::parse("not an address"));
+    }
+
+    #[test]
+    fn most_specific_wins() {
+        let r = Rules::new(&rules(&["10.1.2.3"], &["10.0.0.0/8"])).unwrap();
+        assert!(r.allows(&ip("10.1.2.3")));
+        assert!(!r.allows(&ip("10.1.2.4")));
+        assert!(!r.allows(&ip("11.0.0.1")));
+
+        let r = Rules::new(&rules(&["0.0.0.0/0"], &["10.0.0.0/8"])).unwrap();
+        assert!(!r.allows(&ip("10.1.2.3")));
+        assert!(r.allows(&ip("11.0.0.1")));
+
+        let r = Rules::new(&rules(&["10.0.0.0/8"], &[])).unwrap();
+        assert!(r.allows(&ip("10.1.2.3")));
+        assert!(!r.allows(&ip("11.0.0.1")));
+    }
+
+    #[test]
+    fn connections_are_filtered() {
+        let access = Access::new(&AccessConfig {
+            global: rules(&[], &["192.168.0.0/16"]),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert!(access.accepts(&peer("10.0.0.1")));
+        assert!(!access.accepts(&peer("192.168.1.1")));
+    }
+
+    #[test]
+    fn path_rules() {
+        let access = Access::new(&AccessConfig {
+            paths: vec![
+                PathAccess {
+                    prefix: String::from("/internal"),
+                    rules: rules(&["10.0.0.0/8"], &[]),
+                },
+                PathAccess {
+                    prefix: String::from("/internal/open"),
+                    rules: rules(&[], &[]),
+                },
+            ],
+            ..Default::default()
+        })
+        .unwrap();
+        let headers = HeaderMap::new();
+
+        assert_eq!(
+            Ok(()),
+            access.check("/internal/x", &from("10.0.0.1"), &headers)
+        );
+        assert_eq!(
+            Err(StatusCode::FORBIDDEN),
+            access.check("/internal/x", &from("8.8.8.8"), &headers)
+        );
+        assert_eq!(
+            Ok(()),
+            access.check("/internal/open/x", &from("8.8.8.8"), &headers)
+        );
+        assert_eq!(Ok(()), access.check("/public", &from("8.8.8.8"), &headers));
+    }
+
+    #[test]
+    fn forwarded_for_trusted_proxies_only() {
+        let access = Access::new(&AccessConfig {
+            global: rules(&[], &["203.0.113.0/24"]),
+            paths: vec![PathAccess {
+                prefix: String::from("/internal"),
+                rules: rules(&["10.0.0.0/8"], &[]),
+            }],
+            trusted_proxies: strings(&["127.0.0.1", "172.16.0.0/12"]),
+        })
+        .unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("10.0.0.5, 203.0.113.7, 172.16.0.2"),
+        );
+
+        // a trusted proxy is never dropped, its client is judged per request
+        assert!(access.accepts(&peer("127.0.0.1")));
+        assert_eq!(
+            Err(StatusCode::FORBIDDEN),
+            access.check("/", &from("127.0.0.1"), &headers)
+        );
+
+        // untrusted peers can't claim to be someone else
+        assert_eq!(
+            Err(StatusCode::FORBIDDEN),
+            access.check("/internal", &from("8.8.8.8"), &headers)
+        );
+
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("203.0.113.7, 10.0.0.5"),
+        );
+        assert_eq!(
+            Ok(()),
+            access.check("/internal", &from("127.0.0.1"), &headers)
+        );
+
+        // hops beyond the client don't matter, even when they aren't addresses
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("x, 10.0.0.5, 172.16.0.2"),
+        );
+        assert_eq!(
+            Ok(()),
+            access.check("/internal", &from("127.0.0.1"), &headers)
+        );
+    }
+
+    #[test]
+    fn malformed_forwarded_for() {
+        let access = Access::new(&AccessConfig {
+            paths: vec![PathAccess {
+                prefix: String::from("/internal"),
+                rules: rules(&["10.0.0.0/8"], &[]),
+            }],
+            trusted_proxies: strings(&["10.0.0.1"]),
+            ..Default::default()
+        })
+        .unwrap();
+
+        // the proxy appends the real client to whatever that client sent
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("x, 8.8.8.8"));
+        assert_eq!(
+            Err(StatusCode::FORBIDDEN),
+            access.check("/internal", &from("10.0.0.1"), &headers)
+        );
+
+        // the proxy itself must not be mistaken for the client
+        headers.insert("x-forwarded-for", HeaderValue::from_static("x"));
+        assert_eq!(
+            Err(StatusCode::FORBIDDEN),
+            access.check("/internal", &from("10.0.0.1"), &headers)
+        );
+        assert_eq!(
+            Err(StatusCode::FORBIDDEN),
+            access.check("/public", &from("10.0.0.1"), &headers)
+        );
+    }
+
+    #[test]
+    fn client_subjects() {
+        let access = Access::new(&AccessConfig {
+            paths: vec![PathAccess {
+                prefix: String::from("/admin"),
+                rules: AccessRules {
+                    subjects: strings(&["admin", "C=FY, O=kdv, CN=operator"]),
+                    ..Default::default()
+                },
+            }],
+            ..Default::default()
+        })
+        .unwrap();
+        let headers = HeaderMap::new();
+        let with = |subject: &str, common_name: &str| ConnectionInfo {
+            peer: Some(peer("10.0.0.1")),
+            client: Some(ClientSubject {
+                subject: String::from(subject),
+                common_name: Some(String::from(common_name)),
+            }),
+        };
+
+        assert_eq!(Ok(()), access.check("/public", &from("10.0.0.1"), &headers));
+        assert_eq!(
+            Err(StatusCode::FORBIDDEN),
+            access.check("/admin", &from("10.0.0.1"), &headers)
+        );
+        assert_eq!(
+            Ok(()),
+            access.check("/admin", &with("C=FY, O=kdv, CN=admin", "admin"), &headers)
+        );
+        assert_eq!(
+            Ok(()),
+            access.check(
+                "/admin/x",
+                &with("C=FY, O=kdv, CN=operator", "operator"),
+                &headers
+            )
+        );
+        assert_eq!(
+            Err(StatusCode::FORBIDDEN),
+            access.check(
+                "/admin",
+                &with("C=FY, O=other, CN=operator", "operator"),
+                &headers
+            )
+        );
+    }
+}
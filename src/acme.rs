@@ -0,0 +1,1183 @@
+extern crate base64;
+extern crate hyper_rustls;
+extern crate ring;
+extern crate rustls;
+extern crate serde;
+extern crate serde_json;
+extern crate untrusted;
+extern crate webpki;
+extern crate x509_parser;
+
+use crate::keys::{create_private_dir, load_identity, pem_blocks, pem_encode, write_file};
+use crate::tls::SharedConfig;
+use crate::{selfsigned, x509, AcmeConfig};
+use futures::{Future, Stream};
+use hyper::{header, Body, Request, StatusCode};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+use rustls::sign::CertifiedKey;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+// obtaining certificates from an ACME (rfc 8555) certificate authority. The client runs on its own
+// thread and blocks, which keeps the protocol readable; the server only sees the challenges it has
+// to answer and, once a certificate is stored, a reload of the tls configuration.
+
+pub const HTTP_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+
+const ACCOUNT_KEY: &str = "account-key.pem";
+const CERTIFICATE: &str = "cert.pem";
+const KEY: &str = "key.pem";
+
+// renew when less than this is left, let's encrypt certificates last 90 days
+const RENEW_DAYS: u64 = 30;
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const POLL_ATTEMPTS: usize = 60;
+
+// the challenges currently waiting to be validated, shared between the client and the server
+#[derive(Clone, Default)]
+pub struct Challenges {
+    // token to key authorization
+    http: Arc<RwLock<HashMap<String, String>>>,
+    // domain to challenge certificate
+    tls_alpn: Arc<RwLock<HashMap<String, CertifiedKey>>>,
+}
+
+impl Challenges {
+    // the key authorization to answer an http-01 challenge for path with
+    pub fn http(&self, path: &str) -> Option<String> {
+        let token = path.strip_prefix(HTTP_CHALLENGE_PREFIX)?;
+        self.http.read().unwrap().get(token).cloned()
+    }
+
+    fn tls_alpn(&self, server_name: &str) -> Option<CertifiedKey> {
+        self.tls_alpn
+            .read()
+            .unwrap()
+            .get(&server_name.to_ascii_lowercase())
+            .cloned()
+    }
+
+    fn publish(
+        &self,
+        kind: &str,
+        domain: &str,
+        token: &str,
+        key_authorization: &str,
+    ) -> Result<Published> {
+        match kind {
+            "http-01" => {
+                self.http
+                    .write()
+                    .unwrap()
+                    .insert(token.to_string(), key_authorization.to_string());
+            }
+            _ => {
+                let (cert, pkcs8) = x509::challenge_certificate(domain, key_authorization)?;
+                let key = rustls::sign::any_ecdsa_type(&rustls::PrivateKey(pkcs8))
+                    .map_err(|_| error(String::from("unsupported challenge key")))?;
+                self.tls_alpn.write().unwrap().insert(
+                    domain.to_ascii_lowercase(),
+                    CertifiedKey::new(vec![rustls::Certificate(cert)], Arc::new(key)),
+                );
+            }
+        }
+
+        Ok(Published {
+            challenges: self.clone(),
+            domain: domain.to_ascii_lowercase(),
+            token: token.to_string(),
+        })
+    }
+}
+
+// withdraws the challenge when dropped, whatever the outcome
+struct Published {
+    challenges: Challenges,
+    domain: String,
+    token: String,
+}
+
+impl Drop for Published {
+    fn drop(&mut self) {
+        self.challenges.http.write().unwrap().remove(&self.token);
+        self.challenges
+            .tls_alpn
+            .write()
+            .unwrap()
+            .remove(&self.domain);
+    }
+}
+
+// serves the challenge certificate to clients asking for a domain with a pending tls-alpn-01
+// challenge. rustls picks the certificate before it looks at alpn, so for those few seconds that
+// includes regular clients, which is why http-01 is the default.
+pub struct ChallengeResolver {
+    inner: Arc<dyn rustls::ResolvesServerCert>,
+    challenges: Challenges,
+}
+
+impl ChallengeResolver {
+    pub fn new(
+        inner: Arc<dyn rustls::ResolvesServerCert>,
+        challenges: Challenges,
+    ) -> ChallengeResolver {
+        ChallengeResolver { inner, challenges }
+    }
+}
+
+impl rustls::ResolvesServerCert for ChallengeResolver {
+    fn resolve(
+        &self,
+        server_name: Option<webpki::DNSNameRef>,
+        sigschemes: &[rustls::SignatureScheme],
+    ) -> Option<CertifiedKey> {
+        let name: Option<&str> = server_name.map(|n| n.into());
+        match name.and_then(|n| self.challenges.tls_alpn(n)) {
+            Some(key) => {
+                log::info!("answering tls-alpn-01 challenge for {:?}", name);
+                Some(key)
+            }
+            None => self.inner.resolve(server_name, sigschemes),
+        }
+    }
+}
+
+pub fn certificate_file(cfg: &AcmeConfig) -> String {
+    path(&cfg.cache_dir, CERTIFICATE)
+}
+
+pub fn private_key_file(cfg: &AcmeConfig) -> String {
+    path(&cfg.cache_dir, KEY)
+}
+
+// the settings needed to get a certificate at all
+pub fn validate(cfg: &AcmeConfig) -> Result<()> {
+    if cfg.domains.is_empty() {
+        return Err(error(String::from("acme needs at least one domain")));
+    }
+    match cfg.challenge.as_str() {
+        "http-01" | "tls-alpn-01" => {}
+        other => {
+            return Err(error(format!(
+                "unsupported acme challenge {}, expected http-01 or tls-alpn-01",
+                other
+            )))
+        }
+    }
+    if cfg.cache_dir.is_empty() {
+        return Err(error(String::from(
+            "acme needs a cache_dir to keep its private keys in",
+        )));
+    }
+    if !cfg.agree_tos {
+        return Err(error(String::from(
+            "acme needs agreeing to the terms of service of the certificate authority (agree_tos)",
+        )));
+    }
+    Ok(())
+}
+
+// makes sure there is a certificate to start with, a self signed placeholder if needed
+pub fn prepare(cfg: &AcmeConfig) -> Result<()> {
+    validate(cfg)?;
+    create_private_dir(Path::new(&cfg.cache_dir))?;
+
+    if load_identity(&certificate_file(cfg), Some(&private_key_file(cfg)), None).is_err() {
+        log::info!("no certificate yet, serving a self signed one until it is issued");
+        let dir = PathBuf::from(&cfg.cache_dir).join("placeholder");
+        let placeholder = selfsigned::generate(&cfg.domains, &dir, false)?;
+        copy(&placeholder.private_key_file, &private_key_file(cfg), true)?;
+        copy(&placeholder.certificate_file, &certificate_file(cfg), false)?;
+    }
+    Ok(())
+}
+
+// why the current certificate should be replaced, None if it is fine
+fn renewal_reason(cfg: &AcmeConfig) -> Option<String> {
+    let (certs, _) = match load_identity(&certificate_file(cfg), Some(&private_key_file(cfg)), None)
+    {
+        Ok(identity) => identity,
+        Err(e) => return Some(e.to_string()),
+    };
+    let cert = match x509_parser::parse_x509_certificate(&certs[0].0) {
+        Ok((_, cert)) => cert,
+        Err(e) => return Some(e.to_string()),
+    };
+
+    if selfsigned::is_generated(&cert) {
+        Some(String::from("only a self signed placeholder"))
+    } else if x509::hostnames(&cert) != Some(x509::normalize(&cfg.domains)) {
+        Some(String::from("the domains changed"))
+    } else if x509::expires_within(&cert, x509::now(), RENEW_DAYS) {
+        Some(String::from("it is about to expire"))
+    } else {
+        None
+    }
+}
+
+// checks the certificate now and then, requesting a new one when needed and reloading tls with it.
+// Stops once stop fires or is dropped.
+pub fn spawn(
+    cfg: AcmeConfig,
+    challenges: Challenges,
+    tls: SharedConfig,
+    stop: std::sync::mpsc::Receiver<()>,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || loop {
+        let wait = match renewal_reason(&cfg) {
+            Some(reason) => {
+                log::info!(
+                    "requesting a certificate for {}: {}",
+                    cfg.domains.join(", "),
+                    reason
+                );
+                match obtain(&cfg, &challenges) {
+                    Ok(()) => {
+                        if let Err(e) = tls.reload() {
+                            log::error!("failed to use the obtained certificate: {}", e);
+                        }
+                        CHECK_INTERVAL
+                    }
+                    Err(e) => {
+                        log::error!("failed to obtain a certificate: {}", e);
+                        RETRY_INTERVAL
+                    }
+                }
+            }
+            None => CHECK_INTERVAL,
+        };
+        match stop.recv_timeout(wait) {
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+            _ => {
+                log::debug!(
+                    "stopped checking the certificate for {}",
+                    cfg.domains.join(", ")
+                );
+                return;
+            }
+        }
+    })
+}
+
+// runs an order from start to finish and stores the certificate and its key
+pub fn obtain(cfg: &AcmeConfig, challenges: &Challenges) -> Result<()> {
+    let mut client = Client::new(cfg)?;
+    client.register(cfg)?;
+
+    let identifiers: Vec<serde_json::Value> = cfg
+        .domains
+        .iter()
+        .map(|d| serde_json::json!({"type": "dns", "value": d}))
+        .collect();
+    let new_order = client.directory.new_order.clone();
+    let reply = client.post(
+        &new_order,
+        Some(serde_json::json!({ "identifiers": identifiers })),
+    )?;
+    let order_url = reply.location(&new_order)?;
+    let order: Order = reply.json()?;
+
+    for authorization in &order.authorizations {
+        client.authorize(authorization, &cfg.challenge, challenges)?;
+    }
+
+    let rng = SystemRandom::new();
+    let pkcs8 = x509::generate_key(&rng)?;
+    let key = x509::key_pair(&pkcs8)?;
+    let csr = x509::certificate_request(&key, &rng, &cfg.domains)?;
+    client.post(
+        &order.finalize,
+        Some(serde_json::json!({ "csr": b64(&csr) })),
+    )?;
+
+    let order: Order = client.poll(&order_url, |o: &Order| &o.status)?;
+    if order.status != "valid" {
+        return Err(error(format!("order {} is {}", order_url, order.status)));
+    }
+    let certificate = order
+        .certificate
+        .ok_or_else(|| error(format!("order {} has no certificate", order_url)))?;
+    let chain = client.post(&certificate, None)?.body;
+
+    check_chain(cfg, &certificate, &chain, &key)?;
+    write_file(
+        Path::new(&private_key_file(cfg)),
+        &pem_encode("PRIVATE KEY", &pkcs8),
+        true,
+    )?;
+    write_file(
+        Path::new(&certificate_file(cfg)),
+        &String::from_utf8_lossy(&chain),
+        false,
+    )?;
+    log::info!("stored certificate for {}", cfg.domains.join(", "));
+    Ok(())
+}
+
+// the chain should start with a certificate for our key and domains
+fn check_chain(cfg: &AcmeConfig, url: &str, chain: &[u8], key: &EcdsaKeyPair) -> Result<()> {
+    let blocks = pem_blocks(url, chain)?;
+    let leaf = blocks
+        .iter()
+        .find(|b| b.label == "CERTIFICATE")
+        .ok_or_else(|| error(format!("no certificate at {}", url)))?;
+    let (_, cert) = x509_parser::parse_x509_certificate(&leaf.der)
+        .map_err(|e| error(format!("invalid certificate at {}: {}", url, e)))?;
+
+    if cert.public_key().subject_public_key.data.as_ref() != key.public_key().as_ref() {
+        return Err(error(format!("certificate at {} is for another key", url)));
+    }
+    if x509::hostnames(&cert) != Some(x509::normalize(&cfg.domains)) {
+        return Err(error(format!(
+            "certificate at {} is for {:?}",
+            url,
+            x509::hostnames(&cert)
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    #[serde(default)]
+    token: String,
+    error: Option<Problem>,
+}
+
+#[derive(Deserialize)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+struct Reply {
+    status: StatusCode,
+    location: Option<String>,
+    body: Vec<u8>,
+}
+
+impl Reply {
+    fn json<T: serde::de::DeserializeOwned>(&self) -> Result<T> {
+        serde_json::from_slice(&self.body)
+            .map_err(|e| error(format!("unexpected acme reply: {}", e)))
+    }
+
+    fn location(&self, url: &str) -> Result<String> {
+        self.location
+            .clone()
+            .ok_or_else(|| error(format!("no location in reply from {}", url)))
+    }
+
+    fn problem(&self) -> Option<Problem> {
+        if self.status.is_success() {
+            None
+        } else {
+            Some(self.json().unwrap_or(Problem {
+                kind: String::new(),
+                detail: format!("{}", self.status),
+            }))
+        }
+    }
+}
+
+struct Client {
+    runtime: tokio::runtime::current_thread::Runtime,
+    http: hyper::Client<hyper_rustls::HttpsConnector<hyper::client::HttpConnector>>,
+    directory: Directory,
+    rng: SystemRandom,
+    key: EcdsaKeyPair,
+    jwk: serde_json::Value,
+    thumbprint: String,
+    account: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Client {
+    fn new(cfg: &AcmeConfig) -> Result<Client> {
+        let connector = match &cfg.directory_ca_file {
+            Some(ca_file) => {
+                let mut http = hyper::client::HttpConnector::new(1);
+                http.enforce_http(false);
+                let mut tls = rustls::ClientConfig::new();
+                let file = std::fs::File::open(ca_file)
+                    .map_err(|e| error(format!("failed to open {}: {}", ca_file, e)))?;
+                tls.root_store
+                    .add_pem_file(&mut std::io::BufReader::new(file))
+                    .map_err(|_| error(format!("invalid certificates in {}", ca_file)))?;
+                hyper_rustls::HttpsConnector::from((http, tls))
+            }
+            None => hyper_rustls::HttpsConnector::new(1),
+        };
+
+        let key = account_key(cfg)?;
+        let point = key.public_key().as_ref();
+        // members in lexicographic order and without whitespace, as the thumbprint needs them
+        let jwk = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+            b64(&point[1..33]),
+            b64(&point[33..65])
+        );
+        let thumbprint = b64(ring::digest::digest(&ring::digest::SHA256, jwk.as_bytes()).as_ref());
+
+        let mut client = Client {
+            runtime: tokio::runtime::current_thread::Runtime::new()?,
+            http: hyper::Client::builder().build(connector),
+            directory: Directory {
+                new_nonce: String::new(),
+                new_account: String::new(),
+                new_order: String::new(),
+            },
+            rng: SystemRandom::new(),
+            key,
+            jwk: serde_json::from_str(&jwk).unwrap(),
+            thumbprint,
+            account: None,
+            nonce: None,
+        };
+
+        let reply = client.send(Request::get(cfg.directory_url.as_str()).body(Body::empty()))?;
+        if !reply.status.is_success() {
+            return Err(error(format!(
+                "failed to get acme directory {}: {}",
+                cfg.directory_url, reply.status
+            )));
+        }
+        client.directory = reply.json()?;
+        Ok(client)
+    }
+
+    // finds or creates the account for our key
+    fn register(&mut self, cfg: &AcmeConfig) -> Result<()> {
+        validate(cfg)?;
+        let url = self.directory.new_account.clone();
+        let reply = self.post(
+            &url,
+            Some(serde_json::json!({
+                "termsOfServiceAgreed": cfg.agree_tos,
+                "contact": cfg.contact,
+            })),
+        )?;
+        self.account = Some(reply.location(&url)?);
+        log::debug!("using acme account {:?}", self.account);
+        Ok(())
+    }
+
+    fn authorize(&mut self, url: &str, kind: &str, challenges: &Challenges) -> Result<()> {
+        let authorization: Authorization = self.post(url, None)?.json()?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+
+        let domain = authorization.identifier.value;
+        let challenge = authorization
+            .challenges
+            .iter()
+            .find(|c| c.kind == kind)
+            .ok_or_else(|| error(format!("no {} challenge offered for {}", kind, domain)))?;
+        let key_authorization = format!("{}.{}", challenge.token, self.thumbprint);
+
+        log::info!("answering {} challenge for {}", kind, domain);
+        let _published = challenges.publish(kind, &domain, &challenge.token, &key_authorization)?;
+        self.post(&challenge.url, Some(serde_json::json!({})))?;
+
+        let authorization: Authorization = self.poll(url, |a: &Authorization| &a.status)?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let detail = authorization
+            .challenges
+            .iter()
+            .filter_map(|c| c.error.as_ref())
+            .map(|p| p.detail.clone())
+            .next()
+            .unwrap_or_default();
+        Err(error(format!(
+            "{} challenge for {} failed: {}",
+            kind, domain, detail
+        )))
+    }
+
+    // fetches url until it is no longer pending or processing
+    fn poll<T, F>(&mut self, url: &str, status: F) -> Result<T>
+    where
+        T: serde::de::DeserializeOwned,
+        F: Fn(&T) -> &String,
+    {
+        for _ in 0..POLL_ATTEMPTS {
+            let reply: T = self.post(url, None)?.json()?;
+            match status(&reply).as_str() {
+                "pending" | "processing" => std::thread::sleep(POLL_INTERVAL),
+                _ => return Ok(reply),
+            }
+        }
+        Err(error(format!("gave up waiting for {}", url)))
+    }
+
+    // a jws signed request, or a POST-as-GET without payload. Retries once with a fresh nonce
+    // when the server turns the one we had down.
+    fn post(&mut self, url: &str, payload: Option<serde_json::Value>) -> Result<Reply> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce()?,
+            };
+
+            let mut protected = serde_json::json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            match &self.account {
+                Some(account) => protected["kid"] = serde_json::json!(account),
+                None => protected["jwk"] = self.jwk.clone(),
+            }
+            let protected = b64(protected.to_string().as_bytes());
+            let payload = payload
+                .as_ref()
+                .map(|p| b64(p.to_string().as_bytes()))
+                .unwrap_or_default();
+            let signature = self
+                .key
+                .sign(
+                    &self.rng,
+                    untrusted::Input::from(format!("{}.{}", protected, payload).as_bytes()),
+                )
+                .map_err(|_| error(String::from("failed to sign acme request")))?;
+            let body = serde_json::json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": b64(signature.as_ref()),
+            });
+
+            let reply = self.send(
+                Request::post(url)
+                    .header(header::CONTENT_TYPE, "application/jose+json")
+                    .body(Body::from(body.to_string())),
+            )?;
+            match reply.problem() {
+                None => return Ok(reply),
+                Some(p) if p.kind == "urn:ietf:params:acme:error:badNonce" && !retried => {
+                    log::debug!("acme server wants a fresh nonce");
+                    retried = true;
+                }
+                Some(p) => {
+                    return Err(error(format!(
+                        "acme request to {} failed: {} {}",
+                        url, p.kind, p.detail
+                    )))
+                }
+            }
+        }
+    }
+
+    fn new_nonce(&mut self) -> Result<String> {
+        let url = self.directory.new_nonce.clone();
+        self.send(Request::head(url.as_str()).body(Body::empty()))?;
+        self.nonce
+            .take()
+            .ok_or_else(|| error(format!("no nonce from {}", url)))
+    }
+
+    fn send(&mut self, request: hyper::http::Result<Request<Body>>) -> Result<Reply> {
+        let request = request.map_err(|e| error(format!("invalid acme request: {}", e)))?;
+        let url = request.uri().to_string();
+
+        let reply = self
+            .runtime
+            .block_on(self.http.request(request).and_then(|response| {
+                let (parts, body) = response.into_parts();
+                body.concat2().map(move |body| (parts, body.to_vec()))
+            }))
+            .map_err(|e| error(format!("acme request to {} failed: {}", url, e)))?;
+        let (parts, body) = reply;
+
+        let header = |name| {
+            parts
+                .headers
+                .get(name)
+                .and_then(|v: &header::HeaderValue| v.to_str().ok())
+                .map(String::from)
+        };
+        if let Some(nonce) = header("replay-nonce") {
+            self.nonce = Some(nonce);
+        }
+        Ok(Reply {
+            status: parts.status,
+            location: header("location"),
+            body,
+        })
+    }
+}
+
+// the account key is kept, so later runs use the same account
+fn account_key(cfg: &AcmeConfig) -> Result<EcdsaKeyPair> {
+    let file = path(&cfg.cache_dir, ACCOUNT_KEY);
+    let pkcs8 = match std::fs::read(&file) {
+        Ok(content) => pem_blocks(&file, &content)?
+            .into_iter()
+            .next()
+            .map(|b| b.der)
+            .ok_or_else(|| error(format!("no key in {}", file)))?,
+        Err(_) => {
+            log::info!("creating acme account key {}", file);
+            let pkcs8 = x509::generate_key(&SystemRandom::new())?;
+            write_file(Path::new(&file), &pem_encode("PRIVATE KEY", &pkcs8), true)?;
+            pkcs8
+        }
+    };
+
+    EcdsaKeyPair::from_pkcs8(
+        &ECDSA_P256_SHA256_FIXED_SIGNING,
+        untrusted::Input::from(&pkcs8),
+    )
+    .map_err(|e| error(format!("invalid acme account key {}: {}", file, e)))
+}
+
+fn copy(from: &str, to: &str, private: bool) -> Result<()> {
+    let content = std::fs::read_to_string(from)
+        .map_err(|e| error(format!("failed to read {}: {}", from, e)))?;
+    write_file(Path::new(to), &content, private)
+}
+
+fn b64(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn path(dir: &str, name: &str) -> String {
+    PathBuf::from(dir).join(name).to_string_lossy().into_owned()
+}
+
+fn error(err: String) -> std::io::Error {
+    std::io::Error::other(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Response;
+    use std::sync::Mutex;
+
+    // looks up what is published for an http-01 token
+    type Validate = Box<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+    // a minimal acme server: checks signatures and nonces like a real one would, validates
+    // http-01 challenges and issues certificates from its own authority
+    struct Mock {
+        base: String,
+        validate: Validate,
+        ca: EcdsaKeyPair,
+        state: Mutex<MockState>,
+    }
+
+    #[derive(Default)]
+    struct MockState {
+        nonces: Vec<String>,
+        issued: usize,
+        account: Option<Vec<u8>>,
+        thumbprint: String,
+        domains: Vec<String>,
+        validated: Vec<String>,
+        certificate: Option<String>,
+    }
+
+    impl Mock {
+        fn nonce(&self) -> String {
+            let mut state = self.state.lock().unwrap();
+            state.issued += 1;
+            let nonce = format!("nonce-{}", state.issued);
+            // the first one is never accepted, to make the client retry
+            if state.issued > 1 {
+                state.nonces.push(nonce.clone());
+            }
+            nonce
+        }
+
+        fn respond(&self, method: &hyper::Method, path: &str, body: &[u8]) -> Response<Body> {
+            let url = format!("{}{}", self.base, path);
+            let (status, location, reply) = match (method.as_str(), path) {
+                ("GET", "/directory") => (
+                    200,
+                    None,
+                    serde_json::json!({
+                        "newNonce": format!("{}/nonce", self.base),
+                        "newAccount": format!("{}/account", self.base),
+                        "newOrder": format!("{}/order", self.base),
+                    })
+                    .to_string(),
+                ),
+                ("HEAD", "/nonce") => (200, None, String::new()),
+                ("POST", _) => match self.verify(&url, body) {
+                    Ok(payload) => self.post(path, payload),
+                    Err(problem) => (
+                        400,
+                        None,
+                        serde_json::json!({ "type": problem, "detail": problem }).to_string(),
+                    ),
+                },
+                _ => (404, None, String::new()),
+            };
+
+            let mut response = Response::builder();
+            response.status(status).header("replay-nonce", self.nonce());
+            if let Some(location) = location {
+                response.header("location", location);
+            }
+            response.body(Body::from(reply)).unwrap()
+        }
+
+        // checks the jws, returning its payload
+        fn verify(&self, url: &str, body: &[u8]) -> std::result::Result<String, String> {
+            let decode = |s: &str| base64::decode_config(s, base64::URL_SAFE_NO_PAD).unwrap();
+            let jws: serde_json::Value = serde_json::from_slice(body).unwrap();
+            let (protected, payload, signature) = (
+                jws["protected"].as_str().unwrap(),
+                jws["payload"].as_str().unwrap(),
+                jws["signature"].as_str().unwrap(),
+            );
+            let header: serde_json::Value = serde_json::from_slice(&decode(protected)).unwrap();
+            assert_eq!("ES256", header["alg"]);
+            assert_eq!(url, header["url"]);
+
+            let mut state = self.state.lock().unwrap();
+            let nonce = header["nonce"].as_str().unwrap();
+            match state.nonces.iter().position(|n| n == nonce) {
+                Some(i) => state.nonces.remove(i),
+                None => return Err(String::from("urn:ietf:params:acme:error:badNonce")),
+            };
+
+            let point = match header.get("jwk") {
+                Some(jwk) => {
+                    let mut point = vec![0x04];
+                    point.extend(decode(jwk["x"].as_str().unwrap()));
+                    point.extend(decode(jwk["y"].as_str().unwrap()));
+                    let canonical = format!(
+                        r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#,
+                        jwk["x"].as_str().unwrap(),
+                        jwk["y"].as_str().unwrap()
+                    );
+                    state.thumbprint = b64(ring::digest::digest(
+                        &ring::digest::SHA256,
+                        canonical.as_bytes(),
+                    )
+                    .as_ref());
+                    state.account = Some(point.clone());
+                    point
+                }
+                None => {
+                    assert_eq!(format!("{}/account/1", self.base), header["kid"]);
+                    state.account.clone().unwrap()
+                }
+            };
+            ring::signature::verify(
+                &ring::signature::ECDSA_P256_SHA256_FIXED,
+                untrusted::Input::from(&point),
+                untrusted::Input::from(format!("{}.{}", protected, payload).as_bytes()),
+                untrusted::Input::from(&decode(signature)),
+            )
+            .map_err(|_| String::from("urn:ietf:params:acme:error:malformed"))?;
+
+            Ok(String::from_utf8(decode(payload)).unwrap())
+        }
+
+        fn post(&self, path: &str, payload: String) -> (u16, Option<String>, String) {
+            let base = &self.base;
+            let mut state = self.state.lock().unwrap();
+            let order = |state: &MockState| {
+                serde_json::json!({
+                    "status": if state.certificate.is_some() { "valid" } else { "pending" },
+                    "authorizations": state.domains.iter()
+                        .map(|d| format!("{}/authz/{}", base, d))
+                        .collect::<Vec<_>>(),
+                    "finalize": format!("{}/finalize", base),
+                    "certificate": state.certificate.as_ref().map(|_| format!("{}/certificate", base)),
+                })
+                .to_string()
+            };
+
+            if path == "/account" {
+                let request: serde_json::Value = serde_json::from_str(&payload).unwrap();
+                assert_eq!(true, request["termsOfServiceAgreed"]);
+                (201, Some(format!("{}/account/1", base)), String::from("{}"))
+            } else if path == "/order" {
+                let request: serde_json::Value = serde_json::from_str(&payload).unwrap();
+                state.domains = request["identifiers"]
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .map(|i| i["value"].as_str().unwrap().to_string())
+                    .collect();
+                (201, Some(format!("{}/order/1", base)), order(&state))
+            } else if path == "/order/1" {
+                (200, None, order(&state))
+            } else if let Some(domain) = path.strip_prefix("/authz/") {
+                let status = if state.validated.iter().any(|d| d == domain) {
+                    "valid"
+                } else {
+                    "pending"
+                };
+                let reply = serde_json::json!({
+                    "status": status,
+                    "identifier": { "type": "dns", "value": domain },
+                    "challenges": [{
+                        "type": "http-01",
+                        "url": format!("{}/challenge/{}", base, domain),
+                        "token": format!("token-{}", domain),
+                        "status": status,
+                    }],
+                });
+                (200, None, reply.to_string())
+            } else if let Some(domain) = path.strip_prefix("/challenge/") {
+                let expected = format!("token-{}.{}", domain, state.thumbprint);
+                let published = (self.validate)(&format!("token-{}", domain));
+                if published == Some(expected) {
+                    state.validated.push(domain.to_string());
+                }
+                (200, None, String::from("{}"))
+            } else if path == "/finalize" {
+                assert_eq!(state.domains.len(), state.validated.len());
+                let request: serde_json::Value = serde_json::from_str(&payload).unwrap();
+                let csr = base64::decode_config(
+                    request["csr"].as_str().unwrap(),
+                    base64::URL_SAFE_NO_PAD,
+                )
+                .unwrap();
+                state.certificate = Some(issue(&self.ca, &csr, &state.domains));
+                (200, None, order(&state))
+            } else if path == "/certificate" {
+                (200, None, state.certificate.clone().unwrap())
+            } else {
+                (404, None, String::new())
+            }
+        }
+    }
+
+    const MOCK_CA: &str = "mock acme";
+
+    // signs the public key of the request
+    fn issue(ca: &EcdsaKeyPair, csr: &[u8], domains: &[String]) -> String {
+        use x509_parser::prelude::FromDer;
+
+        let (_, request) =
+            x509_parser::certification_request::X509CertificationRequest::from_der(csr).unwrap();
+        let public_key = request
+            .certification_request_info
+            .subject_pki
+            .subject_public_key
+            .data
+            .to_vec();
+
+        let rng = SystemRandom::new();
+        let leaf = x509::certificate(
+            ca,
+            &rng,
+            &x509::name(MOCK_CA),
+            &x509::name(&domains[0]),
+            &public_key,
+            x509::now(),
+            90,
+            &x509::server_extensions(domains),
+        )
+        .unwrap();
+        pem_encode("CERTIFICATE", &leaf)
+    }
+
+    // returns the directory base url and the certificate of the authority it issues from
+    fn start_mock(validate: Validate) -> (String, Vec<u8>) {
+        let rng = SystemRandom::new();
+        let ca = x509::key_pair(&x509::generate_key(&rng).unwrap()).unwrap();
+        let name = x509::name(MOCK_CA);
+        let extensions = vec![x509::extension(
+            x509::BASIC_CONSTRAINTS_OID,
+            true,
+            &crate::keys::der_write(0x30, &[0x01, 0x01, 0xff]),
+        )];
+        let ca_certificate = x509::certificate(
+            &ca,
+            &rng,
+            &name,
+            &name,
+            ca.public_key().as_ref(),
+            x509::now(),
+            1,
+            &extensions,
+        )
+        .unwrap();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        std::thread::spawn(move || {
+            hyper::rt::run(futures::future::lazy(move || {
+                let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+                let base = format!("http://{}", listener.local_addr().unwrap());
+
+                let mock = Arc::new(Mock {
+                    base: base.clone(),
+                    validate,
+                    ca,
+                    state: Mutex::new(MockState::default()),
+                });
+                let service = move || {
+                    let mock = mock.clone();
+                    hyper::service::service_fn(move |request: Request<Body>| {
+                        let mock = mock.clone();
+                        let (parts, body) = request.into_parts();
+                        body.concat2()
+                            .map(move |body| mock.respond(&parts.method, parts.uri.path(), &body))
+                    })
+                };
+                let server = hyper::Server::from_tcp(listener)
+                    .unwrap()
+                    .serve(service)
+                    .map_err(|e| panic!("mock acme server: {}", e));
+                tx.send(base).unwrap();
+                server
+            }));
+        });
+
+        (rx.recv().unwrap(), ca_certificate)
+    }
+
+    fn temp_dir(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("httprust-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn obtains_certificate() {
+        let challenges = Challenges::default();
+        let published = challenges.clone();
+        let (base, _) = start_mock(Box::new(move |token| {
+            published.http(&format!("{}{}", HTTP_CHALLENGE_PREFIX, token))
+        }));
+        let cfg = AcmeConfig {
+            directory_url: format!("{}/directory", base),
+            domains: vec![
+                String::from("example.test"),
+                String::from("www.example.test"),
+            ],
+            agree_tos: true,
+            cache_dir: temp_dir("acme"),
+            ..Default::default()
+        };
+
+        let refused = AcmeConfig {
+            agree_tos: false,
+            ..cfg.clone()
+        };
+        assert!(prepare(&refused).is_err());
+        assert!(obtain(&refused, &challenges).is_err());
+        let nowhere = AcmeConfig {
+            cache_dir: String::new(),
+            ..cfg.clone()
+        };
+        assert!(prepare(&nowhere).is_err());
+
+        prepare(&cfg).unwrap();
+        assert_eq!(
+            Some(String::from("only a self signed placeholder")),
+            renewal_reason(&cfg)
+        );
+
+        obtain(&cfg, &challenges).unwrap();
+        assert_eq!(None, renewal_reason(&cfg));
+        assert!(challenges.http.read().unwrap().is_empty());
+
+        let other = AcmeConfig {
+            domains: vec![String::from("example.test")],
+            ..cfg.clone()
+        };
+        assert_eq!(
+            Some(String::from("the domains changed")),
+            renewal_reason(&other)
+        );
+
+        let _ = std::fs::remove_dir_all(&cfg.cache_dir);
+    }
+
+    #[test]
+    fn stops_checking_when_told() {
+        let cfg = AcmeConfig {
+            // nothing listens there, so every attempt fails right away
+            directory_url: format!("http://127.0.0.1:{}/directory", free_port()),
+            domains: vec![String::from("example.test")],
+            agree_tos: true,
+            cache_dir: temp_dir("acme-stop"),
+            ..Default::default()
+        };
+        prepare(&cfg).unwrap();
+        let tls = SharedConfig::new(crate::TlsConfig {
+            certificate_file: certificate_file(&cfg),
+            private_key_file: Some(private_key_file(&cfg)),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let (stop, stopped) = std::sync::mpsc::channel();
+        let thread = spawn(cfg.clone(), Challenges::default(), tls, stopped);
+        drop(stop);
+        thread.join().unwrap();
+
+        let _ = std::fs::remove_dir_all(&cfg.cache_dir);
+    }
+
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    // a plain http get, for what the mock fetches from the challenge listener
+    fn http_get(port: u16, path: &str) -> Option<String> {
+        use std::io::{Read, Write};
+
+        let mut socket = std::net::TcpStream::connect(("127.0.0.1", port)).ok()?;
+        write!(
+            socket,
+            "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+            path
+        )
+        .ok()?;
+        let mut response = String::new();
+        socket.read_to_string(&mut response).ok()?;
+
+        let (head, body) = response.split_once("\r\n\r\n")?;
+        if head.starts_with("HTTP/1.1 200") {
+            Some(body.to_string())
+        } else {
+            None
+        }
+    }
+
+    // the certificate a tls client that trusts ca gets served for localhost
+    fn served_certificate(port: u16, ca: &[u8]) -> Option<rustls::Certificate> {
+        use std::io::Write;
+
+        let mut cfg = rustls::ClientConfig::new();
+        cfg.root_store
+            .add(&rustls::Certificate(ca.to_vec()))
+            .unwrap();
+        let name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut session = rustls::ClientSession::new(&Arc::new(cfg), name);
+        let mut socket = std::net::TcpStream::connect(("127.0.0.1", port)).ok()?;
+        let mut stream = rustls::Stream::new(&mut session, &mut socket);
+        stream.write_all(b"HEAD / HTTP/1.1\r\n\r\n").ok()?;
+
+        use rustls::Session;
+        session.get_peer_certificates()?.into_iter().next()
+    }
+
+    #[test]
+    fn serves_obtained_certificate() {
+        let http_port = free_port();
+        let (base, ca) = start_mock(Box::new(move |token| {
+            http_get(http_port, &format!("{}{}", HTTP_CHALLENGE_PREFIX, token))
+        }));
+        let acme = AcmeConfig {
+            directory_url: format!("{}/directory", base),
+            domains: vec![String::from("localhost")],
+            agree_tos: true,
+            cache_dir: temp_dir("acme-server"),
+            http_port,
+            ..Default::default()
+        };
+
+        let cfg = crate::Config {
+            port: 0,
+            local_only: true,
+            root: String::from(env!("CARGO_MANIFEST_DIR")),
+            tls: Some(crate::TlsConfig {
+                certificate_file: acme.certificate_file(),
+                private_key_file: Some(acme.private_key_file()),
+                ..Default::default()
+            }),
+            acme: Some(acme.clone()),
+            ..Default::default()
+        };
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            crate::run_notify(cfg, move |addresses| tx.send(addresses).unwrap())
+        });
+        let port = rx.recv().unwrap()[0].port();
+
+        // issuing takes a moment, until then the placeholder is served which ca doesn't trust
+        let mut served = None;
+        for _ in 0..50 {
+            served = served_certificate(port, &ca);
+            if served.is_some() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        let stored = std::fs::read(acme.certificate_file()).unwrap();
+        let stored = pem_blocks("cert.pem", &stored).unwrap().remove(0).der;
+        assert_eq!(Some(rustls::Certificate(stored)), served);
+
+        let _ = std::fs::remove_dir_all(&acme.cache_dir);
+    }
+
+    #[test]
+    fn challenges_are_withdrawn() {
+        let challenges = Challenges::default();
+        let path = format!("{}token", HTTP_CHALLENGE_PREFIX);
+
+        let published = challenges
+            .publish("http-01", "example.test", "token", "token.thumbprint")
+            .unwrap();
+        assert_eq!(
+            Some(String::from("token.thumbprint")),
+            challenges.http(&path)
+        );
+        assert_eq!(None, challenges.http("/token"));
+        drop(published);
+        assert_eq!(None, challenges.http(&path));
+
+        let published = challenges
+            .publish("tls-alpn-01", "Example.test", "token", "token.thumbprint")
+            .unwrap();
+        assert!(challenges.tls_alpn("example.TEST").is_some());
+        drop(published);
+        assert!(challenges.tls_alpn("example.test").is_none());
+    }
+}
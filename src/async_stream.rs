@@ -134,7 +134,7 @@ mod tests {
         }
 
         fn err(msg: &str) -> Part {
-            Part::Err(std::io::Error::new(std::io::ErrorKind::Other, msg))
+            Part::Err(std::io::Error::other(msg))
         }
     }
 
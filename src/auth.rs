@@ -0,0 +1,393 @@
+extern crate base64;
+extern crate pwhash;
+extern crate ring;
+
+use crate::{BasicAuthConfig, Realm};
+use hyper::header::HeaderValue;
+use hyper::{header, Body, HeaderMap, Response, StatusCode};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+// how long credentials that were verified are trusted without checking them again, and how many
+// are remembered
+const VERIFIED_FOR: Duration = Duration::from_secs(300);
+const MAX_VERIFIED: usize = 1024;
+
+// http basic authentication (rfc 7617) against an apache style htpasswd file. Supported hash
+// formats are bcrypt ($2y$, $2b$, $2a$), sha256-crypt ($5$), sha512-crypt ($6$) and {SHA}.
+pub struct BasicAuth {
+    htpasswd_file: String,
+    realms: Vec<Realm>,
+    users: Mutex<Users>,
+}
+
+// the htpasswd file as it was last read, read again when it changes
+struct Users {
+    stamp: Option<(SystemTime, u64)>,
+    hashes: HashMap<String, String>,
+    // digests of credentials that were verified before and when, bcrypt is slow by design and we
+    // don't want to pay for it on every request
+    verified: HashMap<Vec<u8>, Instant>,
+}
+
+impl BasicAuth {
+    pub fn new(cfg: &BasicAuthConfig) -> std::io::Result<BasicAuth> {
+        let users = Users::load(&cfg.htpasswd_file)?;
+        // no realms would leave every path open
+        if cfg.realms.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "basic authentication with {} has no realms",
+                    cfg.htpasswd_file
+                ),
+            ));
+        }
+
+        let mut realms = cfg.realms.clone();
+        // longest prefix first, so the most specific realm wins
+        realms.sort_by_key(|r| std::cmp::Reverse(r.prefix.len()));
+
+        Ok(BasicAuth {
+            htpasswd_file: cfg.htpasswd_file.clone(),
+            realms,
+            users: Mutex::new(users),
+        })
+    }
+
+    // path is expected to be normalized, so that ../ can not be used to escape a realm
+    pub fn check(
+        &self,
+        path: &str,
+        headers: &HeaderMap<HeaderValue>,
+    ) -> Result<(), Box<Response<Body>>> {
+        let realm = match self.realm(path) {
+            Some(r) => r,
+            None => return Ok(()),
+        };
+
+        match headers.get(header::AUTHORIZATION) {
+            Some(credentials) if self.authorized(credentials.as_bytes()) => Ok(()),
+            Some(_) => {
+                log::warn!("invalid credentials for realm {}", realm.name);
+                Err(Box::new(unauthorized(realm)))
+            }
+            None => {
+                log::info!("no credentials for realm {}", realm.name);
+                Err(Box::new(unauthorized(realm)))
+            }
+        }
+    }
+
+    fn realm(&self, path: &str) -> Option<&Realm> {
+        self.realms.iter().find(|r| in_prefix(path, &r.prefix))
+    }
+
+    fn authorized(&self, credentials: &[u8]) -> bool {
+        let digest = ring::digest::digest(&ring::digest::SHA256, credentials);
+        let digest = Vec::from(digest.as_ref());
+
+        let (user, password, hash) = {
+            let mut users = self.users.lock().unwrap();
+            self.refresh(&mut users);
+            if let Some(at) = users.verified.get(&digest) {
+                if at.elapsed() < VERIFIED_FOR {
+                    return true;
+                }
+            }
+
+            let (user, password) = match decode_credentials(credentials) {
+                Some(c) => c,
+                None => return false,
+            };
+            match users.hashes.get(&user) {
+                Some(hash) => (user, password, hash.clone()),
+                None => {
+                    log::debug!("unknown user {}", user);
+                    return false;
+                }
+            }
+        };
+
+        // without holding the lock, so one slow hash doesn't hold up every other request
+        if !verify_password(&password, &hash) {
+            return false;
+        }
+        log::debug!("authenticated {}", user);
+
+        let mut users = self.users.lock().unwrap();
+        // unless the file changed in the meantime
+        if users.hashes.get(&user) == Some(&hash) {
+            users.remember(digest);
+        }
+        true
+    }
+
+    // reads the htpasswd file again when it changed, forgetting what was verified against the
+    // previous one. Keeps the previous users when it can't be read.
+    fn refresh(&self, users: &mut Users) {
+        let stamp = stamp(&self.htpasswd_file);
+        if stamp == users.stamp {
+            return;
+        }
+        match Users::load(&self.htpasswd_file) {
+            Ok(loaded) => *users = loaded,
+            Err(e) => {
+                log::error!("keeping the previous users: {}", e);
+                users.stamp = stamp;
+            }
+        }
+    }
+}
+
+impl Users {
+    fn load(htpasswd_file: &str) -> std::io::Result<Users> {
+        let stamp = stamp(htpasswd_file);
+        let content = std::fs::read_to_string(htpasswd_file).map_err(|e| {
+            std::io::Error::new(e.kind(), format!("failed to read {}: {}", htpasswd_file, e))
+        })?;
+        let hashes = parse_htpasswd(&content);
+        log::info!("loaded {} users from {}", hashes.len(), htpasswd_file);
+
+        Ok(Users {
+            stamp,
+            hashes,
+            verified: HashMap::new(),
+        })
+    }
+
+    fn remember(&mut self, digest: Vec<u8>) {
+        if self.verified.len() >= MAX_VERIFIED {
+            self.verified.retain(|_, at| at.elapsed() < VERIFIED_FOR);
+        }
+        if self.verified.len() >= MAX_VERIFIED {
+            self.verified.clear();
+        }
+        self.verified.insert(digest, Instant::now());
+    }
+}
+
+fn stamp(file: &str) -> Option<(SystemTime, u64)> {
+    std::fs::metadata(file)
+        .and_then(|m| Ok((m.modified()?, m.len())))
+        .ok()
+}
+
+// true if path is prefix itself or lies below it, so /priv matches /priv/x but not /private
+pub fn in_prefix(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+fn unauthorized(realm: &Realm) -> Response<Body> {
+    let challenge = format!(
+        "Basic realm=\"{}\", charset=\"UTF-8\"",
+        realm.name.replace('"', "'")
+    );
+
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(header::WWW_AUTHENTICATE, challenge)
+        .body(Body::from(
+            StatusCode::UNAUTHORIZED.canonical_reason().unwrap_or(""),
+        ))
+        .unwrap()
+}
+
+fn decode_credentials(header: &[u8]) -> Option<(String, String)> {
+    let header = std::str::from_utf8(header).ok()?;
+    let mut parts = header.trim().splitn(2, ' ');
+    let scheme = parts.next()?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+
+    let decoded = base64::decode(parts.next()?.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((String::from(user), String::from(password)))
+}
+
+fn parse_htpasswd(content: &str) -> HashMap<String, String> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (user, hash) = line.split_once(':')?;
+            if !is_supported(hash) {
+                log::warn!("unsupported password hash for user {}, ignoring", user);
+                return None;
+            }
+            Some((String::from(user), String::from(hash)))
+        })
+        .collect()
+}
+
+fn is_supported(hash: &str) -> bool {
+    ["$2y$", "$2b$", "$2a$", "$5$", "$6$", "{SHA}"]
+        .iter()
+        .any(|p| hash.starts_with(p))
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    match hash.strip_prefix("{SHA}") {
+        Some(expected) => {
+            let digest = ring::digest::digest(&ring::digest::SHA1, password.as_bytes());
+            let actual = base64::encode(digest.as_ref());
+            ring::constant_time::verify_slices_are_equal(actual.as_bytes(), expected.as_bytes())
+                .is_ok()
+        }
+        None => pwhash::unix::verify(password, hash),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_auth(realms: Vec<(&str, &str)>) -> BasicAuth {
+        BasicAuth::new(&auth_config(realms)).expect("load htpasswd")
+    }
+
+    fn auth_config(realms: Vec<(&str, &str)>) -> BasicAuthConfig {
+        let cargo_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR to be set");
+        let htpasswd = std::path::PathBuf::from(cargo_dir)
+            .join("tests")
+            .join("sample_auth")
+            .join("htpasswd");
+
+        BasicAuthConfig {
+            htpasswd_file: String::from(htpasswd.to_str().unwrap()),
+            realms: realms
+                .into_iter()
+                .map(|(prefix, name)| Realm {
+                    prefix: String::from(prefix),
+                    name: String::from(name),
+                })
+                .collect(),
+            allow_insecure: true,
+        }
+    }
+
+    fn basic(user: &str, password: &str) -> HeaderMap<HeaderValue> {
+        let value = format!(
+            "Basic {}",
+            base64::encode(&format!("{}:{}", user, password))
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(&value).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn hash_variants() {
+        let auth = make_auth(vec![("/", "all")]);
+
+        assert!(auth.check("/", &basic("alice", "wonderland")).is_ok());
+        assert!(auth.check("/", &basic("bob", "builder")).is_ok());
+        assert!(auth.check("/", &basic("carol", "secret")).is_ok());
+        assert!(auth.check("/", &basic("dave", "daisy")).is_ok());
+    }
+
+    #[test]
+    fn wrong_password() {
+        let auth = make_auth(vec![("/", "all")]);
+
+        assert!(auth.check("/", &basic("alice", "builder")).is_err());
+        assert!(auth.check("/", &basic("carol", "wonderland")).is_err());
+        assert!(auth.check("/", &basic("nobody", "wonderland")).is_err());
+    }
+
+    #[test]
+    fn unsupported_hashes_are_ignored() {
+        let auth = make_auth(vec![("/", "all")]);
+
+        assert!(!auth.users.lock().unwrap().hashes.contains_key("eve"));
+        assert!(auth.check("/", &basic("eve", "eve")).is_err());
+    }
+
+    #[test]
+    fn challenge_names_realm() {
+        let auth = make_auth(vec![("/", "outer"), ("/private", "inner")]);
+
+        let response = auth.check("/private/x", &HeaderMap::new()).unwrap_err();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        assert_eq!(
+            "Basic realm=\"inner\", charset=\"UTF-8\"",
+            response.headers()[header::WWW_AUTHENTICATE]
+        );
+
+        let response = auth.check("/privateer", &HeaderMap::new()).unwrap_err();
+        assert_eq!(
+            "Basic realm=\"outer\", charset=\"UTF-8\"",
+            response.headers()[header::WWW_AUTHENTICATE]
+        );
+    }
+
+    #[test]
+    fn follows_changes_to_the_htpasswd_file() {
+        let original = auth_config(vec![]).htpasswd_file;
+        let htpasswd =
+            std::env::temp_dir().join(format!("httprust-htpasswd-{}", std::process::id()));
+        std::fs::copy(&original, &htpasswd).unwrap();
+        let auth = BasicAuth::new(&BasicAuthConfig {
+            htpasswd_file: String::from(htpasswd.to_str().unwrap()),
+            ..auth_config(vec![("/", "all")])
+        })
+        .unwrap();
+        assert!(auth.check("/", &basic("carol", "secret")).is_ok());
+
+        // carol is gone, even though she was verified before
+        let content = std::fs::read_to_string(&original).unwrap();
+        let without: Vec<&str> = content
+            .lines()
+            .filter(|l| !l.starts_with("carol:"))
+            .collect();
+        std::fs::write(&htpasswd, without.join("\n")).unwrap();
+        assert!(auth.check("/", &basic("carol", "secret")).is_err());
+        assert!(auth.check("/", &basic("dave", "daisy")).is_ok());
+
+        std::fs::remove_file(&htpasswd).unwrap();
+    }
+
+    #[test]
+    fn remembers_a_limited_number_of_credentials() {
+        let auth = make_auth(vec![("/", "all")]);
+        let mut users = auth.users.lock().unwrap();
+        for i in 0..MAX_VERIFIED * 2 {
+            users.remember(i.to_string().into_bytes());
+            assert!(users.verified.len() <= MAX_VERIFIED);
+        }
+    }
+
+    #[test]
+    fn needs_a_realm() {
+        assert!(BasicAuth::new(&auth_config(vec![])).is_err());
+    }
+
+    #[test]
+    fn paths_outside_realms_are_open() {
+        let auth = make_auth(vec![("/private", "inner")]);
+
+        assert!(auth.check("/public/x", &HeaderMap::new()).is_ok());
+        assert!(auth.check("/private", &HeaderMap::new()).is_err());
+    }
+
+    #[test]
+    fn malformed_credentials() {
+        assert_eq!(None, decode_credentials(b"Bearer abc"));
+        assert_eq!(None, decode_credentials(b"Basic !!!"));
+        assert_eq!(
+            Some((String::from("a"), String::from("b:c"))),
+            decode_credentials(format!("basic {}", base64::encode("a:b:c")).as_bytes())
+        );
+    }
+}
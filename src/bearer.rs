@@ -0,0 +1,570 @@
+extern crate jsonwebtoken;
+extern crate ring;
+extern crate serde_json;
+
+use crate::auth::in_prefix;
+use crate::BearerAuthConfig;
+use hyper::header::HeaderValue;
+use hyper::{header, Body, HeaderMap, Method, Response, StatusCode};
+use jsonwebtoken::jwk::{AlgorithmParameters, EllipticCurve, JwkSet};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+
+// bearer token authentication (rfc 6750). Tokens are either jwts signed with HS256, RS256 or
+// ES256, or static api tokens listed in a file. Either way a token carries permissions of the form
+// "read:/prefix" or "write:/prefix", where write implies read.
+pub struct BearerAuth {
+    keys: Vec<Key>,
+    audience: Option<String>,
+    issuer: Option<String>,
+    claim: String,
+    tokens: Vec<StaticToken>,
+}
+
+struct Key {
+    id: Option<String>,
+    key: DecodingKey,
+    algorithm: Algorithm,
+}
+
+struct StaticToken {
+    digest: Vec<u8>,
+    permissions: Vec<Permission>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+struct Permission {
+    prefix: String,
+    write: bool,
+}
+
+impl BearerAuth {
+    pub fn new(cfg: &BearerAuthConfig) -> std::io::Result<BearerAuth> {
+        let keys = match &cfg.jwt_key_file {
+            Some(f) => load_keys(f)?,
+            None => vec![],
+        };
+        let tokens = match &cfg.tokens_file {
+            Some(f) => load_tokens(f)?,
+            None => vec![],
+        };
+
+        if keys.is_empty() && tokens.is_empty() {
+            return Err(invalid(
+                "bearer authentication needs a jwt key or api tokens",
+            ));
+        }
+        log::info!(
+            "bearer authentication with {} jwt keys and {} api tokens",
+            keys.len(),
+            tokens.len()
+        );
+
+        Ok(BearerAuth {
+            keys,
+            audience: cfg.audience.clone(),
+            issuer: cfg.issuer.clone(),
+            claim: cfg.permissions_claim.clone(),
+            tokens,
+        })
+    }
+
+    // path is expected to be normalized, so that ../ can not be used to escape a permission
+    pub fn check(
+        &self,
+        path: &str,
+        method: &Method,
+        headers: &HeaderMap<HeaderValue>,
+    ) -> Result<(), Box<Response<Body>>> {
+        let token = match bearer_token(headers) {
+            Some(t) => t,
+            None => {
+                log::info!("no bearer token");
+                return Err(Box::new(challenge(StatusCode::UNAUTHORIZED, None)));
+            }
+        };
+
+        let permissions = match self.permissions(token) {
+            Some(p) => p,
+            None => {
+                return Err(Box::new(challenge(
+                    StatusCode::UNAUTHORIZED,
+                    Some("invalid_token"),
+                )))
+            }
+        };
+
+        let write = !is_read(method);
+        if permissions
+            .iter()
+            .any(|p| in_prefix(path, &p.prefix) && (p.write || !write))
+        {
+            Ok(())
+        } else {
+            log::warn!(
+                "token does not grant {} access to {}",
+                if write { "write" } else { "read" },
+                path
+            );
+            Err(Box::new(challenge(
+                StatusCode::FORBIDDEN,
+                Some("insufficient_scope"),
+            )))
+        }
+    }
+
+    fn permissions(&self, token: &str) -> Option<Vec<Permission>> {
+        let digest = ring::digest::digest(&ring::digest::SHA256, token.as_bytes());
+        let fixed = self.tokens.iter().find(|t| {
+            ring::constant_time::verify_slices_are_equal(&t.digest, digest.as_ref()).is_ok()
+        });
+        if let Some(t) = fixed {
+            log::debug!("authenticated with api token");
+            return Some(t.permissions.clone());
+        }
+
+        if self.keys.is_empty() {
+            log::info!("unknown api token");
+            return None;
+        }
+
+        let claims = self.decode(token)?;
+        let permissions = match claims.get(&self.claim) {
+            Some(serde_json::Value::String(s)) => parse_permissions(s.split_whitespace()),
+            Some(serde_json::Value::Array(a)) => {
+                parse_permissions(a.iter().filter_map(serde_json::Value::as_str))
+            }
+            _ => vec![],
+        };
+        log::debug!(
+            "authenticated {} with {:?}",
+            claims
+                .get("sub")
+                .and_then(serde_json::Value::as_str)
+                .unwrap_or("anonymous jwt"),
+            permissions
+        );
+        Some(permissions)
+    }
+
+    fn decode(&self, token: &str) -> Option<serde_json::Map<String, serde_json::Value>> {
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|e| log::info!("invalid jwt: {}", e))
+            .ok()?;
+
+        let key = match &header.kid {
+            Some(kid) => self
+                .keys
+                .iter()
+                .find(|k| k.id.as_ref() == Some(kid))
+                .or_else(|| self.single_key()),
+            None => self.single_key(),
+        };
+        let key = match key {
+            Some(k) => k,
+            None => {
+                log::info!("no key to verify jwt with key id {:?}", header.kid);
+                return None;
+            }
+        };
+
+        // pinning the algorithm to the key prevents tokens from choosing how they get verified
+        let mut validation = Validation::new(key.algorithm);
+        validation.validate_nbf = true;
+        validation.validate_aud = self.audience.is_some();
+        if let Some(aud) = &self.audience {
+            validation.set_audience(&[aud]);
+        }
+        if let Some(iss) = &self.issuer {
+            validation.set_issuer(&[iss]);
+        }
+
+        jsonwebtoken::decode::<serde_json::Map<String, serde_json::Value>>(
+            token,
+            &key.key,
+            &validation,
+        )
+        .map(|data| data.claims)
+        .map_err(|e| log::info!("rejected jwt: {}", e))
+        .ok()
+    }
+
+    fn single_key(&self) -> Option<&Key> {
+        match self.keys.len() {
+            1 => self.keys.first(),
+            _ => None,
+        }
+    }
+}
+
+fn is_read(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+fn bearer_token(headers: &HeaderMap<HeaderValue>) -> Option<&str> {
+    let header = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = header.trim().split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+fn challenge(code: StatusCode, error: Option<&str>) -> Response<Body> {
+    let challenge = match error {
+        Some(e) => format!("Bearer realm=\"httprust\", error=\"{}\"", e),
+        None => String::from("Bearer realm=\"httprust\""),
+    };
+
+    Response::builder()
+        .status(code)
+        .header(header::WWW_AUTHENTICATE, challenge)
+        .body(Body::from(code.canonical_reason().unwrap_or("")))
+        .unwrap()
+}
+
+fn parse_permissions<'a, I: Iterator<Item = &'a str>>(items: I) -> Vec<Permission> {
+    items
+        .filter_map(|item| {
+            let (access, prefix) = item.split_once(':')?;
+            let write = match access {
+                "read" => false,
+                "write" => true,
+                _ => return None,
+            };
+            if !prefix.starts_with('/') {
+                return None;
+            }
+            Some(Permission {
+                prefix: String::from(prefix),
+                write,
+            })
+        })
+        .collect()
+}
+
+// the key file is either a jwks document, a pem encoded rsa or ec public key, or else the raw
+// secret for HS256
+fn load_keys(filename: &str) -> std::io::Result<Vec<Key>> {
+    let content = std::fs::read(filename).map_err(|e| {
+        std::io::Error::new(e.kind(), format!("failed to read {}: {}", filename, e))
+    })?;
+
+    if let Ok(set) = serde_json::from_slice::<JwkSet>(&content) {
+        let keys: Vec<Key> = set
+            .keys
+            .iter()
+            .filter_map(|jwk| {
+                let algorithm = match &jwk.algorithm {
+                    AlgorithmParameters::RSA(_) => Algorithm::RS256,
+                    AlgorithmParameters::EllipticCurve(ec) if ec.curve == EllipticCurve::P256 => {
+                        Algorithm::ES256
+                    }
+                    AlgorithmParameters::OctetKey(_) => Algorithm::HS256,
+                    _ => {
+                        log::warn!("skipping unsupported jwk {:?}", jwk.common.key_id);
+                        return None;
+                    }
+                };
+                let key = DecodingKey::from_jwk(jwk)
+                    .map_err(|e| log::warn!("skipping invalid jwk {:?}: {}", jwk.common.key_id, e))
+                    .ok()?;
+                Some(Key {
+                    id: jwk.common.key_id.clone(),
+                    key,
+                    algorithm,
+                })
+            })
+            .collect();
+
+        log::debug!("loaded {} keys from jwks {}", keys.len(), filename);
+        return Ok(keys);
+    }
+
+    let key = if content.starts_with(b"-----BEGIN") {
+        DecodingKey::from_rsa_pem(&content)
+            .map(|k| (k, Algorithm::RS256))
+            .or_else(|_| DecodingKey::from_ec_pem(&content).map(|k| (k, Algorithm::ES256)))
+            .map_err(|e| invalid(&format!("no rsa or ec public key in {}: {}", filename, e)))?
+    } else {
+        let secret = std::str::from_utf8(&content)
+            .map(|s| s.trim_end().as_bytes())
+            .unwrap_or(&content);
+        (DecodingKey::from_secret(secret), Algorithm::HS256)
+    };
+
+    log::debug!("loaded {:?} key from {}", key.1, filename);
+    Ok(vec![Key {
+        id: None,
+        key: key.0,
+        algorithm: key.1,
+    }])
+}
+
+fn load_tokens(filename: &str) -> std::io::Result<Vec<StaticToken>> {
+    let content = std::fs::read_to_string(filename).map_err(|e| {
+        std::io::Error::new(e.kind(), format!("failed to read {}: {}", filename, e))
+    })?;
+
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut parts = line.split_whitespace();
+            let token = parts.next()?;
+            let digest = ring::digest::digest(&ring::digest::SHA256, token.as_bytes());
+            Some(StaticToken {
+                digest: Vec::from(digest.as_ref()),
+                permissions: parse_permissions(parts),
+            })
+        })
+        .collect())
+}
+
+fn invalid(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+
+    fn sample(name: &str) -> String {
+        let cargo_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR to be set");
+        let path = std::path::PathBuf::from(cargo_dir)
+            .join("tests")
+            .join("sample_auth")
+            .join(name);
+        String::from(path.to_str().unwrap())
+    }
+
+    fn config(key: Option<&str>, tokens: Option<&str>) -> BearerAuthConfig {
+        BearerAuthConfig {
+            jwt_key_file: key.map(sample),
+            tokens_file: tokens.map(sample),
+            audience: Some(String::from("httprust")),
+            issuer: Some(String::from("tests")),
+            ..Default::default()
+        }
+    }
+
+    fn now() -> u64 {
+        jsonwebtoken::get_current_timestamp()
+    }
+
+    fn claims(scope: &str) -> serde_json::Value {
+        serde_json::json!({
+            "sub": "tester",
+            "aud": "httprust",
+            "iss": "tests",
+            "exp": now() + 600,
+            "scope": scope,
+        })
+    }
+
+    fn sign(
+        header: Header,
+        claims: &serde_json::Value,
+        key: &EncodingKey,
+    ) -> HeaderMap<HeaderValue> {
+        let token = jsonwebtoken::encode(&header, claims, key).expect("sign jwt");
+        bearer(&token)
+    }
+
+    fn bearer(token: &str) -> HeaderMap<HeaderValue> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", token)).unwrap(),
+        );
+        headers
+    }
+
+    fn hs256() -> EncodingKey {
+        EncodingKey::from_secret(b"correct horse battery staple")
+    }
+
+    fn rs256() -> EncodingKey {
+        let pem = std::fs::read(sample("jwt-rs256-key.pem")).unwrap();
+        EncodingKey::from_rsa_pem(&pem).unwrap()
+    }
+
+    fn es256() -> EncodingKey {
+        let pem = std::fs::read(sample("jwt-es256-key.pem")).unwrap();
+        EncodingKey::from_ec_pem(&pem).unwrap()
+    }
+
+    fn status(result: Result<(), Box<Response<Body>>>) -> StatusCode {
+        match result {
+            Ok(()) => StatusCode::OK,
+            Err(response) => response.status(),
+        }
+    }
+
+    #[test]
+    fn hs256_token() {
+        let auth = BearerAuth::new(&config(Some("jwt-hs256.key"), None)).unwrap();
+        let headers = sign(
+            Header::new(Algorithm::HS256),
+            &claims("read:/docs"),
+            &hs256(),
+        );
+
+        assert_eq!(
+            StatusCode::OK,
+            status(auth.check("/docs/a", &Method::GET, &headers))
+        );
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            status(auth.check("/other", &Method::GET, &headers))
+        );
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            status(auth.check("/docs/a", &Method::PATCH, &headers))
+        );
+    }
+
+    #[test]
+    fn rs256_token() {
+        let auth = BearerAuth::new(&config(Some("jwt-rs256-pub.pem"), None)).unwrap();
+        let headers = sign(Header::new(Algorithm::RS256), &claims("write:/"), &rs256());
+
+        assert_eq!(
+            StatusCode::OK,
+            status(auth.check("/x", &Method::PATCH, &headers))
+        );
+    }
+
+    #[test]
+    fn es256_token() {
+        let auth = BearerAuth::new(&config(Some("jwt-es256-pub.pem"), None)).unwrap();
+        let headers = sign(Header::new(Algorithm::ES256), &claims("read:/"), &es256());
+
+        assert_eq!(
+            StatusCode::OK,
+            status(auth.check("/x", &Method::GET, &headers))
+        );
+    }
+
+    #[test]
+    fn jwks_selects_key_by_id() {
+        let auth = BearerAuth::new(&config(Some("jwks.json"), None)).unwrap();
+
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some(String::from("ec-1"));
+        let headers = sign(header, &claims("read:/"), &es256());
+        assert_eq!(
+            StatusCode::OK,
+            status(auth.check("/x", &Method::GET, &headers))
+        );
+
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(String::from("rsa-1"));
+        let headers = sign(header, &claims("read:/"), &rs256());
+        assert_eq!(
+            StatusCode::OK,
+            status(auth.check("/x", &Method::GET, &headers))
+        );
+
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(String::from("ec-1"));
+        let headers = sign(header, &claims("read:/"), &rs256());
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            status(auth.check("/x", &Method::GET, &headers))
+        );
+    }
+
+    #[test]
+    fn algorithm_is_pinned_to_key() {
+        // an HS256 token using the public key as secret must not be accepted
+        let auth = BearerAuth::new(&config(Some("jwt-rs256-pub.pem"), None)).unwrap();
+        let public = std::fs::read(sample("jwt-rs256-pub.pem")).unwrap();
+        let headers = sign(
+            Header::new(Algorithm::HS256),
+            &claims("read:/"),
+            &EncodingKey::from_secret(&public),
+        );
+
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            status(auth.check("/x", &Method::GET, &headers))
+        );
+    }
+
+    #[test]
+    fn claims_are_validated() {
+        let auth = BearerAuth::new(&config(Some("jwt-hs256.key"), None)).unwrap();
+        let check = |claims: serde_json::Value| {
+            let headers = sign(Header::new(Algorithm::HS256), &claims, &hs256());
+            status(auth.check("/x", &Method::GET, &headers))
+        };
+
+        let mut expired = claims("read:/");
+        expired["exp"] = serde_json::json!(now() - 3600);
+        assert_eq!(StatusCode::UNAUTHORIZED, check(expired));
+
+        let mut early = claims("read:/");
+        early["nbf"] = serde_json::json!(now() + 3600);
+        assert_eq!(StatusCode::UNAUTHORIZED, check(early));
+
+        let mut audience = claims("read:/");
+        audience["aud"] = serde_json::json!("someone-else");
+        assert_eq!(StatusCode::UNAUTHORIZED, check(audience));
+
+        let mut issuer = claims("read:/");
+        issuer["iss"] = serde_json::json!("someone-else");
+        assert_eq!(StatusCode::UNAUTHORIZED, check(issuer));
+
+        let mut list = claims("");
+        list["scope"] = serde_json::json!(["read:/a", "read:/x"]);
+        assert_eq!(StatusCode::OK, check(list));
+    }
+
+    #[test]
+    fn static_tokens() {
+        let auth = BearerAuth::new(&config(None, Some("api-tokens"))).unwrap();
+
+        let reader = bearer("reader-token");
+        assert_eq!(
+            StatusCode::OK,
+            status(auth.check("/x", &Method::GET, &reader))
+        );
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            status(auth.check("/files", &Method::POST, &reader))
+        );
+
+        let uploader = bearer("uploader-token");
+        assert_eq!(
+            StatusCode::OK,
+            status(auth.check("/files/abc", &Method::PATCH, &uploader))
+        );
+
+        assert_eq!(
+            StatusCode::UNAUTHORIZED,
+            status(auth.check("/x", &Method::GET, &bearer("no-such-token")))
+        );
+    }
+
+    #[test]
+    fn missing_token_is_challenged() {
+        let auth = BearerAuth::new(&config(None, Some("api-tokens"))).unwrap();
+
+        let response = auth
+            .check("/x", &Method::GET, &HeaderMap::new())
+            .unwrap_err();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        assert_eq!(
+            "Bearer realm=\"httprust\"",
+            response.headers()[header::WWW_AUTHENTICATE]
+        );
+    }
+
+    #[test]
+    fn needs_keys_or_tokens() {
+        assert!(BearerAuth::new(&config(None, None)).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+extern crate toml;
+
+use crate::Config;
+use std::io::Result;
+
+// a toml file holding any part of a Config, with the same names. Anything left out keeps its
+// default; anything not known is an error.
+pub fn load(path: &str) -> Result<Config> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| error(format!("error reading {}: {}", path, e)))?;
+    parse(&text).map_err(|e| error(format!("error in {}: {}", path, e)))
+}
+
+pub fn parse(text: &str) -> Result<Config> {
+    toml::from_str(text).map_err(|e| error(e.to_string()))
+}
+
+pub fn to_string(cfg: &Config) -> Result<String> {
+    toml::to_string_pretty(cfg).map_err(|e| error(e.to_string()))
+}
+
+// secrets are shown as being set, not what they are, so printing a configuration doesn't leak them
+pub fn redacted<S: serde::Serializer>(
+    secret: &Option<String>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match secret {
+        Some(_) => serializer.serialize_some("<redacted>"),
+        None => serializer.serialize_none(),
+    }
+}
+
+// durations as a whole number of seconds
+pub mod seconds {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => serializer.serialize_some(&duration.as_secs()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<u64>::deserialize(deserializer)?.map(Duration::from_secs))
+    }
+}
+
+fn error(err: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn parses_every_section() {
+        let cfg = parse(
+            r#"
+            port = 8443
+            bind = ["127.0.0.1", "[::1]:9000"]
+            root = "/srv/www"
+            redirect_port = 8080
+            drain_timeout = 5
+            compress = false
+
+            [headers]
+            "Cache-Control" = "no-cache"
+
+            [tls]
+            certificate_file = "cert.pem"
+            private_key_file = "key.pem"
+            watch_interval = 60
+            cipher_suites = ["TLS13_AES_256_GCM_SHA384"]
+
+            [tls.client_auth]
+            ca_file = "ca.pem"
+
+            [[tls.server_names]]
+            server_name = "example.com"
+            certificate_file = "example.pem"
+
+            [[listeners]]
+            port = 8081
+            local_only = true
+
+            [[listeners]]
+            unix = { path = "/run/httprust.sock", mode = 0o660 }
+
+            [limits]
+            max_body_size = 1024
+
+            [uploads]
+            endpoint = "/uploads"
+
+            [basic_auth]
+            htpasswd_file = "htpasswd"
+            realms = [{ prefix = "/", name = "httprust" }]
+
+            [access.global]
+            deny = ["10.0.0.0/8"]
+
+            [[access.paths]]
+            prefix = "/private"
+            rules = { allow = ["127.0.0.1"] }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(8443, cfg.port);
+        assert_eq!("/srv/www", cfg.root);
+        assert_eq!(Some(Duration::from_secs(5)), cfg.drain_timeout);
+        assert!(!cfg.compress);
+        assert_eq!("no-cache", cfg.headers["Cache-Control"]);
+        let tls = cfg.tls.unwrap();
+        assert_eq!(Some(Duration::from_secs(60)), tls.watch_interval);
+        assert!(!tls.client_auth.unwrap().optional);
+        assert_eq!("example.com", tls.server_names[0].server_name);
+        assert_eq!(2, cfg.listeners.len());
+        assert_eq!(Some(0o660), cfg.listeners[1].unix.as_ref().unwrap().mode);
+        assert_eq!(Some(1024), cfg.limits.max_body_size);
+        assert_eq!(".tus-staging", cfg.uploads.unwrap().staging_dir);
+        assert_eq!("httprust", cfg.basic_auth.unwrap().realms[0].name);
+        assert_eq!(vec!["127.0.0.1"], cfg.access.paths[0].rules.allow);
+    }
+
+    #[test]
+    fn loads_the_example() {
+        let example = concat!(env!("CARGO_MANIFEST_DIR"), "/config.example.toml");
+        let cfg = load(example).unwrap();
+        assert_eq!(format!("{:?}", Config::default()), format!("{:?}", cfg));
+    }
+
+    #[test]
+    fn keeps_defaults() {
+        let cfg = parse("root = \"/srv/www\"").unwrap();
+        let default = Config::default();
+        assert_eq!(default.port, cfg.port);
+        assert_eq!(default.drain_timeout, cfg.drain_timeout);
+        assert!(cfg.tls.is_none());
+    }
+
+    #[test]
+    fn basic_auth_covers_everything_by_default() {
+        let cfg = parse("[basic_auth]\nhtpasswd_file = \"htpasswd\"").unwrap();
+        let realms = cfg.basic_auth.unwrap().realms;
+        assert_eq!(1, realms.len());
+        assert_eq!("/", realms[0].prefix);
+    }
+
+    #[test]
+    fn rejects_mistakes() {
+        assert!(parse("prot = 8080").is_err());
+        assert!(parse("port = \"8080\"").is_err());
+        assert!(parse("port = 100000").is_err());
+        assert!(parse("[acme]\ndomains = [\"example.com\"]\nchallenge_type = \"dns\"").is_err());
+    }
+
+    #[test]
+    fn hides_passwords() {
+        let cfg = Config {
+            tls: Some(crate::TlsConfig {
+                certificate_file: String::from("bundle.p12"),
+                pkcs12_password: Some(String::from("hunter2")),
+                server_names: vec![crate::ServerCertificate {
+                    server_name: String::from("example.com"),
+                    certificate_file: String::from("example.p12"),
+                    private_key_file: None,
+                    pkcs12_password: Some(String::from("hunter3")),
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let toml = to_string(&cfg).unwrap();
+        assert!(!toml.contains("hunter"), "{}", toml);
+        assert_eq!(2, toml.matches("pkcs12_password = \"<redacted>\"").count());
+    }
+
+    #[test]
+    fn round_trips() {
+        let cfg = Config {
+            tls: Some(crate::TlsConfig {
+                certificate_file: String::from("cert.pem"),
+                watch_interval: Some(Duration::from_secs(10)),
+                ..Default::default()
+            }),
+            listeners: vec![crate::ListenerConfig {
+                port: 8081,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let parsed = parse(&to_string(&cfg).unwrap()).unwrap();
+        assert_eq!(format!("{:?}", cfg), format!("{:?}", parsed));
+    }
+}
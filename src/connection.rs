@@ -0,0 +1,115 @@
+extern crate rustls;
+extern crate tokio_rustls;
+extern crate x509_parser;
+
+use rustls::Session;
+use std::net::SocketAddr;
+use tokio::net::{TcpStream, UnixStream};
+
+// what the handler gets to know about the connection a request came in on
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionInfo {
+    pub peer: Option<SocketAddr>,
+    // set when the client authenticated with a certificate the tls layer verified
+    pub client: Option<ClientSubject>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientSubject {
+    pub subject: String,
+    pub common_name: Option<String>,
+}
+
+impl ClientSubject {
+    pub fn from_der(der: &[u8]) -> Option<ClientSubject> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+        let subject = cert.subject();
+        let common_name = subject
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok())
+            .map(String::from);
+
+        Some(ClientSubject {
+            subject: subject.to_string(),
+            common_name,
+        })
+    }
+
+    // name is either the full distinguished name or just the common name
+    pub fn matches(&self, name: &str) -> bool {
+        self.subject == name || self.common_name.as_deref() == Some(name)
+    }
+}
+
+pub trait Connection {
+    fn info(&self) -> ConnectionInfo;
+}
+
+// no ip address to tell about, the socket's permissions decide who gets to connect
+impl Connection for UnixStream {
+    fn info(&self) -> ConnectionInfo {
+        ConnectionInfo::default()
+    }
+}
+
+impl Connection for TcpStream {
+    fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            peer: self.peer_addr().ok(),
+            client: None,
+        }
+    }
+}
+
+impl Connection for tokio_rustls::TlsStream<TcpStream, rustls::ServerSession> {
+    fn info(&self) -> ConnectionInfo {
+        let (stream, session) = self.get_ref();
+        let mut info = stream.info();
+
+        // the end entity certificate comes first, rustls already verified the chain
+        info.client = session
+            .get_peer_certificates()
+            .and_then(|certs| certs.first().and_then(|c| ClientSubject::from_der(&c.0)));
+        if let Some(client) = &info.client {
+            log::info!("client {:?} authenticated as {}", info.peer, client.subject);
+        }
+
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client_cert() -> Vec<u8> {
+        let cargo_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR to be set");
+        let pem = std::path::PathBuf::from(cargo_dir)
+            .join("tests")
+            .join("sample_tls")
+            .join("httprust-test-client-cert.pem");
+        let mut reader = std::io::BufReader::new(std::fs::File::open(pem).unwrap());
+        rustls::internal::pemfile::certs(&mut reader).unwrap()[0]
+            .0
+            .clone()
+    }
+
+    #[test]
+    fn subject_from_certificate() {
+        let client = ClientSubject::from_der(&client_cert()).expect("parse certificate");
+
+        assert_eq!(
+            "C=FY, ST=Fryslan, O=kdv, CN=httprust-test-client",
+            client.subject
+        );
+        assert_eq!(
+            Some(String::from("httprust-test-client")),
+            client.common_name
+        );
+        assert!(client.matches("httprust-test-client"));
+        assert!(client.matches("C=FY, ST=Fryslan, O=kdv, CN=httprust-test-client"));
+        assert!(!client.matches("httprust-test"));
+        assert_eq!(None, ClientSubject::from_der(b"not a certificate"));
+    }
+}
@@ -0,0 +1,177 @@
+use futures::future::{ExecuteError, Executor, Shared};
+use futures::Future;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+type Abort = Box<dyn Future<Item = (), Error = ()> + Send>;
+
+// open connections of every listener, and how they ended once stopping
+#[derive(Default)]
+pub struct Connections {
+    open: AtomicUsize,
+    stopping: AtomicBool,
+    aborting: AtomicBool,
+    drained: AtomicUsize,
+    aborted: AtomicUsize,
+}
+
+impl Connections {
+    pub fn open(self: &Arc<Self>) -> Guard {
+        self.open.fetch_add(1, Ordering::SeqCst);
+        Guard(self.clone())
+    }
+
+    pub fn stop(&self, timeout: Option<Duration>) {
+        self.stopping.store(true, Ordering::SeqCst);
+        let open = self.open.load(Ordering::SeqCst);
+        match timeout {
+            Some(timeout) if open > 0 => log::info!(
+                "waiting up to {:?} for {} open connections to finish",
+                timeout,
+                open
+            ),
+            _ if open > 0 => log::info!("waiting for {} open connections to finish", open),
+            _ => {}
+        }
+    }
+
+    fn abort(&self) {
+        self.aborting.store(true, Ordering::SeqCst);
+        let open = self.open.load(Ordering::SeqCst);
+        if open > 0 {
+            log::warn!("closing {} open connections", open);
+        }
+    }
+
+    pub fn report(&self) {
+        let drained = self.drained.load(Ordering::SeqCst);
+        let aborted = self.aborted.load(Ordering::SeqCst);
+        if aborted > 0 {
+            log::warn!(
+                "{} connections finished while stopping, {} were closed",
+                drained,
+                aborted
+            );
+        } else if drained > 0 {
+            log::info!("{} connections finished while stopping", drained);
+        }
+    }
+}
+
+// held by a connection for as long as it is open
+pub struct Guard(Arc<Connections>);
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let connections = &self.0;
+        connections.open.fetch_sub(1, Ordering::SeqCst);
+        if connections.aborting.load(Ordering::SeqCst) {
+            connections.aborted.fetch_add(1, Ordering::SeqCst);
+        } else if connections.stopping.load(Ordering::SeqCst) {
+            connections.drained.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+// runs connections on the default executor until they finish or abort fires, whichever comes
+// first. Dropping a connection closes it.
+#[derive(Clone)]
+pub struct Drain {
+    abort: Shared<Abort>,
+}
+
+impl Drain {
+    // aborts when abort fires, or timeout after stopping. Either is optional.
+    pub fn new<A, S>(
+        connections: Arc<Connections>,
+        abort: A,
+        stopping: S,
+        timeout: Option<Duration>,
+    ) -> Drain
+    where
+        A: Future<Item = ()> + Send + 'static,
+        S: Future<Item = ()> + Send + 'static,
+    {
+        let timeout: Abort = match timeout {
+            Some(timeout) => Box::new(stopping.then(move |_| {
+                tokio::timer::Delay::new(Instant::now() + timeout)
+                    .map(move |_| log::warn!("connections still open after {:?}", timeout))
+                    .map_err(|e| log::error!("drain timer error: {}", e))
+            })),
+            None => Box::new(futures::future::empty()),
+        };
+        let abort: Abort = Box::new(abort.then(|_| Ok(())).select(timeout).then(move |_| {
+            connections.abort();
+            Ok(())
+        }));
+        Drain {
+            abort: abort.shared(),
+        }
+    }
+}
+
+impl<F> Executor<F> for Drain
+where
+    F: Future<Item = (), Error = ()> + Send + 'static,
+{
+    fn execute(&self, future: F) -> Result<(), ExecuteError<F>> {
+        let abort = self.abort.clone().then(|_| Ok(()));
+        tokio::spawn(future.select(abort).then(|_| Ok(())));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_how_connections_end() {
+        let connections = Arc::new(Connections::default());
+        let before = connections.open();
+        let first = connections.open();
+        let second = connections.open();
+        let third = connections.open();
+        drop(before);
+
+        connections.stop(None);
+        drop(first);
+        connections.abort();
+        drop(second);
+        drop(third);
+
+        assert_eq!(0, connections.open.load(Ordering::SeqCst));
+        assert_eq!(1, connections.drained.load(Ordering::SeqCst));
+        assert_eq!(2, connections.aborted.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn aborts_after_timeout() {
+        let connections = Arc::new(Connections::default());
+        let drain = Drain::new(
+            connections.clone(),
+            futures::future::empty::<(), ()>(),
+            futures::future::ok::<(), ()>(()),
+            Some(Duration::from_millis(50)),
+        );
+
+        let guard = connections.open();
+        let (tx, rx) = futures::sync::oneshot::channel();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.spawn(futures::future::lazy(move || {
+            // a connection that never finishes by itself
+            let stuck = futures::future::empty::<(), ()>().then(move |_| {
+                drop(guard);
+                Ok(())
+            });
+            drain.execute(stuck).map_err(|_| ()).unwrap();
+            drain.abort.clone().then(move |_| tx.send(()))
+        }));
+
+        rx.wait().unwrap();
+        runtime.shutdown_on_idle().wait().unwrap();
+        assert_eq!(0, connections.open.load(Ordering::SeqCst));
+        assert_eq!(1, connections.aborted.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,31 @@
+use std::fmt;
+use std::io;
+
+// why a server could not start
+#[derive(Debug)]
+pub enum Error {
+    // the configuration is invalid, or refers to files that can't be used
+    Config(io::Error),
+    // a listener could not be opened, like when its address is in use
+    Listen(io::Error),
+    // the runtime to serve on could not be started
+    Runtime(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Config(e) => write!(f, "invalid configuration: {}", e),
+            Error::Listen(e) => write!(f, "error listening: {}", e),
+            Error::Runtime(e) => write!(f, "error starting the runtime: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Config(e) | Error::Listen(e) | Error::Runtime(e) => Some(e),
+        }
+    }
+}
@@ -1,29 +1,194 @@
 extern crate path_abs;
 
 use super::log;
+use crate::access::Access;
+use crate::acme::Challenges;
 use crate::async_stream::AsyncStream;
+use crate::auth::BasicAuth;
+use crate::bearer::BearerAuth;
 use crate::compressed_read::*;
+use crate::connection::ConnectionInfo;
+use crate::limits;
 use crate::meta_info::*;
+use crate::signed::SignedUrls;
+use crate::tus::Uploads;
+use crate::{
+    AccessConfig, BasicAuthConfig, BearerAuthConfig, LimitsConfig, SigningConfig, UploadConfig,
+};
 use futures::{future, Future};
-use hyper::header::HeaderValue;
+use hyper::header::{HeaderName, HeaderValue};
 use hyper::{header, Body, HeaderMap, Method, Request, Response, StatusCode};
-use path_abs::{PathDir, PathFile};
+use path_abs::{PathAbs, PathDir, PathFile};
 
-type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+pub type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
 
 pub struct Handler {
     root: PathDir,
+    limits: LimitsConfig,
+    compress: bool,
+    headers: HeaderMap<HeaderValue>,
+    uploads: Option<Uploads>,
+    basic_auth: Option<BasicAuth>,
+    bearer_auth: Option<BearerAuth>,
+    signed_urls: Option<SignedUrls>,
+    access: Option<Access>,
+    challenges: Option<Challenges>,
 }
 
 impl Handler {
     pub fn new(root: &str) -> std::io::Result<Handler> {
+        // path_abs can't resolve "." by itself
+        let root = std::fs::canonicalize(root).map_err(|e| {
+            std::io::Error::new(e.kind(), format!("error opening root {}: {}", root, e))
+        })?;
         let root = PathDir::new(root)?.canonicalize()?;
         log::info!("new handler for root at {:?}", root);
 
-        Ok(Handler { root })
+        Ok(Handler {
+            root,
+            limits: LimitsConfig::default(),
+            compress: true,
+            headers: HeaderMap::new(),
+            uploads: None,
+            basic_auth: None,
+            bearer_auth: None,
+            signed_urls: None,
+            access: None,
+            challenges: None,
+        })
+    }
+
+    pub fn with_limits(mut self, limits: LimitsConfig) -> Handler {
+        log::debug!("using request limits {:?}", limits);
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_compression(mut self, compress: bool) -> Handler {
+        self.compress = compress;
+        self
+    }
+
+    pub fn with_headers(
+        mut self,
+        headers: &std::collections::BTreeMap<String, String>,
+    ) -> std::io::Result<Handler> {
+        for (name, value) in headers {
+            match (
+                HeaderName::from_bytes(name.as_bytes()),
+                HeaderValue::from_str(value),
+            ) {
+                (Ok(name), Ok(value)) => {
+                    self.headers.insert(name, value);
+                }
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("invalid header {}: {}", name, value),
+                    ))
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    pub fn with_uploads(mut self, cfg: &UploadConfig) -> std::io::Result<Handler> {
+        self.uploads = Some(Uploads::new(&self.root, cfg)?);
+        Ok(self)
     }
 
-    pub fn handle(&self, request: Request<Body>) -> ResponseFuture {
+    pub fn with_basic_auth(mut self, cfg: &BasicAuthConfig) -> std::io::Result<Handler> {
+        self.basic_auth = Some(BasicAuth::new(cfg)?);
+        Ok(self)
+    }
+
+    pub fn with_bearer_auth(mut self, cfg: &BearerAuthConfig) -> std::io::Result<Handler> {
+        self.bearer_auth = Some(BearerAuth::new(cfg)?);
+        Ok(self)
+    }
+
+    pub fn with_signed_urls(mut self, cfg: &SigningConfig) -> std::io::Result<Handler> {
+        self.signed_urls = Some(SignedUrls::new(cfg)?);
+        Ok(self)
+    }
+
+    pub fn with_access(mut self, cfg: &AccessConfig) -> std::io::Result<Handler> {
+        self.access = Some(Access::new(cfg)?);
+        Ok(self)
+    }
+
+    pub fn with_challenges(mut self, challenges: Challenges) -> Handler {
+        self.challenges = Some(challenges);
+        self
+    }
+
+    pub fn accepts(&self, peer: &std::net::SocketAddr) -> bool {
+        match &self.access {
+            Some(access) => access.accepts(peer),
+            None => true,
+        }
+    }
+
+    fn authenticate(&self, path: &str, request: &Request<Body>) -> Result<(), Box<Response<Body>>> {
+        if let Some(auth) = &self.basic_auth {
+            auth.check(path, request.headers())?;
+        }
+
+        if let Some(auth) = &self.bearer_auth {
+            auth.check(path, request.method(), request.headers())?;
+        }
+
+        Ok(())
+    }
+
+    // access rules and authentication, for a request on path
+    fn authorize(
+        &self,
+        path: &str,
+        request: &Request<Body>,
+        connection: &ConnectionInfo,
+    ) -> Result<(), Box<Response<Body>>> {
+        if let Some(access) = &self.access {
+            access
+                .check(path, connection, request.headers())
+                .map_err(|code| Box::new(raw_direct_response(code)))?;
+        }
+        self.authenticate(path, request)
+    }
+
+    // answers pending acme http-01 challenges, which need no access rules or authentication
+    pub fn challenge(&self, request: &Request<Body>) -> Option<ResponseFuture> {
+        if request.method() != Method::GET {
+            return None;
+        }
+        let key_authorization = self.challenges.as_ref()?.http(request.uri().path())?;
+        log::info!("answering http-01 challenge {}", request.uri().path());
+
+        let response = Response::builder()
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .body(Body::from(key_authorization))
+            .unwrap();
+        Some(Box::new(future::ok(response)))
+    }
+
+    pub fn handle(&self, request: Request<Body>, connection: &ConnectionInfo) -> ResponseFuture {
+        let response = self.respond(request, connection);
+        if self.headers.is_empty() {
+            return response;
+        }
+
+        let headers = self.headers.clone();
+        Box::new(response.map(move |mut response| {
+            for (name, value) in &headers {
+                if !response.headers().contains_key(name) {
+                    response.headers_mut().insert(name, value.clone());
+                }
+            }
+            response
+        }))
+    }
+
+    fn respond(&self, request: Request<Body>, connection: &ConnectionInfo) -> ResponseFuture {
         log::info!(
             "handling {} request for {}",
             request.method(),
@@ -31,6 +196,48 @@ impl Handler {
         );
         log::debug!("{:#?}", request);
 
+        if let Err(code) = limits::check_request(&self.limits, request.headers()) {
+            return direct_response(code);
+        }
+
+        if let Some(response) = self.challenge(&request) {
+            return response;
+        }
+
+        let normalized = normalize_path(request.uri().path());
+
+        if let Some(access) = &self.access {
+            if let Err(code) = access.check(&normalized, connection, request.headers()) {
+                return direct_response(code);
+            }
+        }
+
+        let signed = match &self.signed_urls {
+            Some(signed_urls) => {
+                match signed_urls.check(&normalized, request.method(), request.uri().query()) {
+                    Ok(signed) => signed,
+                    Err(code) => return direct_response(code),
+                }
+            }
+            None => false,
+        };
+
+        if !signed {
+            if let Err(response) = self.authenticate(&normalized, &request) {
+                return Box::new(future::ok(*response));
+            }
+        }
+
+        if let Some(uploads) = &self.uploads {
+            if uploads.matches(request.uri().path()) {
+                // a new upload is also checked against the file it is going to write
+                let authorize = |target: &str, request: &Request<Body>| {
+                    self.authorize(target, request, connection)
+                };
+                return uploads.handle(request, &self.limits, &authorize);
+            }
+        }
+
         match *request.method() {
             Method::GET => {}
             Method::HEAD => {}
@@ -40,19 +247,16 @@ impl Handler {
             }
         }
 
-        let path = String::from(request.uri().path());
-        let path = path.trim_start_matches('/');
-        let path = match self.root.join(path).absolute() {
+        let path = match resolve_path(&self.root, request.uri().path()) {
             Ok(p) => p,
-            Err(e) => {
-                log::warn!("failed to absolute: {}", e);
-                return direct_response(StatusCode::BAD_REQUEST);
-            }
+            Err(code) => return direct_response(code),
         };
 
-        if !path.as_path().starts_with(self.root.to_owned()) {
-            log::warn!("attempted directory traversal: {:?}", path);
-            return direct_response(StatusCode::FORBIDDEN);
+        if let Some(uploads) = &self.uploads {
+            if uploads.is_staging(&path) {
+                log::info!("refusing to serve from staging area: {:?}", path);
+                return direct_response(StatusCode::NOT_FOUND);
+            }
         }
 
         let path = match PathFile::new(path) {
@@ -63,8 +267,41 @@ impl Handler {
             }
         };
 
-        serve_file(path, request)
+        serve_file(path, request, self.compress)
+    }
+}
+
+// lexically resolves . and .. segments, so that path based rules see the path as it will be served
+pub fn normalize_path(path: &str) -> String {
+    let mut segments: Vec<&str> = vec![];
+    for segment in path.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            s => segments.push(s),
+        }
     }
+    format!("/{}", segments.join("/"))
+}
+
+pub fn resolve_path(root: &PathDir, path: &str) -> Result<PathAbs, StatusCode> {
+    let path = path.trim_start_matches('/');
+    let path = match root.join(path).absolute() {
+        Ok(p) => p,
+        Err(e) => {
+            log::warn!("failed to absolute: {}", e);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    };
+
+    if !path.as_path().starts_with(root) {
+        log::warn!("attempted directory traversal: {:?}", path);
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Ok(path)
 }
 
 fn should_compress(m: &Mime, headers: &HeaderMap<HeaderValue>) -> bool {
@@ -87,7 +324,7 @@ fn should_compress(m: &Mime, headers: &HeaderMap<HeaderValue>) -> bool {
     }
 }
 
-fn serve_file(path: PathFile, request: Request<Body>) -> ResponseFuture {
+fn serve_file(path: PathFile, request: Request<Body>, compress: bool) -> ResponseFuture {
     log::debug!("serving {:?}", path);
 
     let mut builder = Response::builder();
@@ -95,7 +332,7 @@ fn serve_file(path: PathFile, request: Request<Body>) -> ResponseFuture {
     if let Some(mime) = sniff_mime(path.as_os_str()) {
         builder.header(header::CONTENT_TYPE, mime.to_string());
 
-        if should_compress(&mime, request.headers()) {
+        if compress && should_compress(&mime, request.headers()) {
             log::debug!("compressing {:?}", path);
             builder.header(header::CONTENT_ENCODING, "gzip");
             use_gzip = true;
@@ -128,18 +365,58 @@ fn serve_file(path: PathFile, request: Request<Body>) -> ResponseFuture {
     Box::new(fut)
 }
 
-fn raw_direct_response(code: StatusCode) -> Response<Body> {
+pub fn raw_direct_response(code: StatusCode) -> Response<Body> {
     Response::builder()
         .status(code)
         .body(Body::from(code.canonical_reason().unwrap_or("")))
         .unwrap()
 }
 
-fn direct_response(code: StatusCode) -> ResponseFuture {
+pub fn direct_response(code: StatusCode) -> ResponseFuture {
     let response = raw_direct_response(code);
     Box::new(future::ok(response))
 }
 
+// 308 to the same host and path on https, so the method and body are kept
+pub fn redirect_to_https(request: &Request<Body>, port: u16) -> ResponseFuture {
+    let authority = request.uri().authority_part().cloned().or_else(|| {
+        request
+            .headers()
+            .get(header::HOST)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.parse::<hyper::http::uri::Authority>().ok())
+    });
+    let host = match authority {
+        Some(authority) => String::from(authority.host()),
+        None => return direct_response(StatusCode::BAD_REQUEST),
+    };
+    // a bare ipv6 address needs its brackets back
+    let host = if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]", host)
+    } else {
+        host
+    };
+
+    let origin = match port {
+        443 => format!("https://{}", host),
+        port => format!("https://{}:{}", host, port),
+    };
+    let path = request
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    let location = format!("{}{}", origin, path);
+    log::debug!("redirecting {} to {}", request.uri(), location);
+
+    let response = Response::builder()
+        .status(StatusCode::PERMANENT_REDIRECT)
+        .header(header::LOCATION, location)
+        .body(Body::empty())
+        .unwrap();
+    Box::new(future::ok(response))
+}
+
 #[cfg(test)]
 mod tests {
     extern crate tokio;
@@ -160,11 +437,30 @@ mod tests {
     where
         F: FnOnce(Response<Body>) + Send + 'static,
     {
-        let handler = make_handler();
+        handle_with(make_handler(), request, check);
+    }
 
-        let response_future = handler.handle(request).map(check).map_err(|e| {
-            panic!("error checking: {}", e);
-        });
+    fn handle_with<F>(handler: Handler, request: Request<Body>, check: F)
+    where
+        F: FnOnce(Response<Body>) + Send + 'static,
+    {
+        handle_from(handler, request, ConnectionInfo::default(), check);
+    }
+
+    fn handle_from<F>(
+        handler: Handler,
+        request: Request<Body>,
+        connection: ConnectionInfo,
+        check: F,
+    ) where
+        F: FnOnce(Response<Body>) + Send + 'static,
+    {
+        let response_future = handler
+            .handle(request, &connection)
+            .map(check)
+            .map_err(|e| {
+                panic!("error checking: {}", e);
+            });
 
         current_thread::Runtime::new()
             .expect("new runtime")
@@ -210,4 +506,276 @@ mod tests {
             assert_eq!(StatusCode::METHOD_NOT_ALLOWED, res.status());
         });
     }
+
+    #[test]
+    fn body_too_large() {
+        let handler = make_handler().with_limits(LimitsConfig {
+            max_body_size: Some(4),
+            ..Default::default()
+        });
+        let request = Request::builder()
+            .uri("http://something/index.html")
+            .header(header::CONTENT_LENGTH, "5")
+            .header(header::EXPECT, "100-continue")
+            .body(Body::from("12345"))
+            .unwrap();
+
+        handle_with(handler, request, |res| {
+            assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, res.status());
+        });
+    }
+
+    #[test]
+    fn unsupported_expectation() {
+        let request = Request::builder()
+            .uri("http://something/index.html")
+            .header(header::EXPECT, "200-ok")
+            .body(Body::from(""))
+            .unwrap();
+
+        handle(request, |res| {
+            assert_eq!(StatusCode::EXPECTATION_FAILED, res.status());
+        });
+    }
+
+    #[test]
+    fn normalize() {
+        assert_eq!("/", normalize_path(""));
+        assert_eq!("/", normalize_path("/"));
+        assert_eq!("/a/b", normalize_path("/a//b/"));
+        assert_eq!("/b", normalize_path("/a/../b"));
+        assert_eq!("/b", normalize_path("/../../b/."));
+    }
+
+    fn make_auth_handler() -> Handler {
+        let cargo_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR to be set");
+        let htpasswd = std::path::PathBuf::from(cargo_dir)
+            .join("tests")
+            .join("sample_auth")
+            .join("htpasswd");
+
+        make_handler()
+            .with_basic_auth(&BasicAuthConfig {
+                htpasswd_file: String::from(htpasswd.to_str().unwrap()),
+                realms: vec![crate::Realm {
+                    prefix: String::from("/private"),
+                    name: String::from("private"),
+                }],
+                allow_insecure: true,
+            })
+            .expect("basic auth")
+    }
+
+    #[test]
+    fn auth_required_before_path_resolution() {
+        let request = Request::builder()
+            .uri("http://something/public/../private/no_such_thing")
+            .body(Body::from(""))
+            .unwrap();
+
+        handle_with(make_auth_handler(), request, |res| {
+            assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+            assert!(res.headers().contains_key(header::WWW_AUTHENTICATE));
+        });
+    }
+
+    #[test]
+    fn authorized_request_is_served() {
+        let request = Request::builder()
+            .uri("http://something/private/no_such_thing")
+            .header(header::AUTHORIZATION, "Basic Y2Fyb2w6c2VjcmV0")
+            .body(Body::from(""))
+            .unwrap();
+
+        handle_with(make_auth_handler(), request, |res| {
+            assert_eq!(StatusCode::NOT_FOUND, res.status());
+        });
+    }
+
+    #[test]
+    fn signed_url_bypasses_authentication() {
+        let cargo_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR to be set");
+        let key = std::path::PathBuf::from(cargo_dir)
+            .join("tests")
+            .join("sample_auth")
+            .join("signing.key");
+        let key = String::from(key.to_str().unwrap());
+
+        let path = crate::sign_url(
+            &key,
+            "/private/no_such_thing",
+            std::time::Duration::from_secs(60),
+        )
+        .expect("sign");
+        let handler = make_auth_handler()
+            .with_signed_urls(&SigningConfig {
+                key_file: key,
+                required_prefixes: vec![],
+            })
+            .expect("signed urls");
+        let request = Request::builder()
+            .uri(format!("http://something{}", path))
+            .body(Body::from(""))
+            .unwrap();
+
+        handle_with(handler, request, |res| {
+            assert_eq!(StatusCode::NOT_FOUND, res.status());
+        });
+    }
+
+    #[test]
+    fn access_rules_by_path() {
+        let handler = make_handler()
+            .with_access(&AccessConfig {
+                paths: vec![crate::PathAccess {
+                    prefix: String::from("/internal"),
+                    rules: crate::AccessRules {
+                        allow: vec![String::from("10.0.0.0/8")],
+                        ..Default::default()
+                    },
+                }],
+                ..Default::default()
+            })
+            .expect("access rules");
+        let request = Request::builder()
+            .uri("http://something/internal/no_such_thing")
+            .body(Body::from(""))
+            .unwrap();
+        let connection = ConnectionInfo {
+            peer: Some("192.168.1.1:1234".parse().unwrap()),
+            client: None,
+        };
+
+        handle_from(handler, request, connection, |res| {
+            assert_eq!(StatusCode::FORBIDDEN, res.status());
+        });
+    }
+
+    // files are read on a blocking pool, which the current thread runtime doesn't have
+    fn serve(handler: Handler, request: Request<Body>) -> Response<Body> {
+        tokio::runtime::Runtime::new()
+            .expect("new runtime")
+            .block_on(handler.handle(request, &ConnectionInfo::default()))
+            .expect("response")
+    }
+
+    #[test]
+    fn extra_headers() {
+        let mut headers = std::collections::BTreeMap::new();
+        headers.insert(String::from("Cache-Control"), String::from("no-cache"));
+        headers.insert(String::from("Content-Type"), String::from("text/plain"));
+        let handler = make_handler().with_headers(&headers).unwrap();
+        let request = Request::builder()
+            .uri("http://something/index.html")
+            .body(Body::from(""))
+            .unwrap();
+
+        let res = serve(handler, request);
+        assert_eq!("no-cache", res.headers()[header::CACHE_CONTROL]);
+        // what the response sets itself wins
+        assert_eq!("text/html", res.headers()[header::CONTENT_TYPE]);
+
+        headers.insert(String::from("Bad Name"), String::from("x"));
+        assert!(make_handler().with_headers(&headers).is_err());
+    }
+
+    #[test]
+    fn compression_can_be_switched_off() {
+        let request = || {
+            Request::builder()
+                .uri("http://something/index.html")
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .body(Body::from(""))
+                .unwrap()
+        };
+
+        let res = serve(make_handler(), request());
+        assert_eq!("gzip", res.headers()[header::CONTENT_ENCODING]);
+        let res = serve(make_handler().with_compression(false), request());
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+    }
+
+    #[test]
+    fn uploads_need_write_access_to_their_target() {
+        let root =
+            std::env::temp_dir().join(format!("httprust-upload-auth-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("incoming")).unwrap();
+        let tokens = root.join("tokens");
+        std::fs::write(&tokens, "uploader read:/ write:/files write:/incoming\n").unwrap();
+
+        let make = || {
+            Handler::new(root.to_str().unwrap())
+                .unwrap()
+                .with_uploads(&UploadConfig::default())
+                .unwrap()
+                .with_bearer_auth(&BearerAuthConfig {
+                    tokens_file: Some(String::from(tokens.to_str().unwrap())),
+                    ..Default::default()
+                })
+                .unwrap()
+        };
+        let create = |filename: &str| {
+            Request::builder()
+                .method(Method::POST)
+                .uri("http://something/files")
+                .header(header::AUTHORIZATION, "Bearer uploader")
+                .header("tus-resumable", "1.0.0")
+                .header("upload-length", "5")
+                .header(
+                    "upload-metadata",
+                    format!("filename {}", base64::encode(filename)),
+                )
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        handle_with(make(), create("incoming/a.txt"), |res| {
+            assert_eq!(StatusCode::CREATED, res.status());
+        });
+        handle_with(make(), create("a.txt"), |res| {
+            assert_eq!(StatusCode::FORBIDDEN, res.status());
+        });
+        handle_with(make(), create("incoming/../a.txt"), |res| {
+            assert_eq!(StatusCode::FORBIDDEN, res.status());
+        });
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn redirect_location(request: Request<Body>, port: u16) -> Option<String> {
+        current_thread::Runtime::new()
+            .expect("new runtime")
+            .block_on(redirect_to_https(&request, port))
+            .map(|res| {
+                res.headers()
+                    .get(header::LOCATION)
+                    .map(|l| l.to_str().unwrap().to_string())
+            })
+            .expect("redirect")
+    }
+
+    #[test]
+    fn redirects_to_https() {
+        let request = Request::builder()
+            .uri("/some/thing?x=1")
+            .header(header::HOST, "example.com:80")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            Some(String::from("https://example.com/some/thing?x=1")),
+            redirect_location(request, 443)
+        );
+
+        let request = Request::builder()
+            .uri("http://[::1]:8080/")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(
+            Some(String::from("https://[::1]:8443/")),
+            redirect_location(request, 8443)
+        );
+
+        let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+        assert_eq!(None, redirect_location(request, 443));
+    }
 }
@@ -0,0 +1,443 @@
+extern crate rustls;
+extern crate webpki;
+extern crate x509_parser;
+
+use crate::{x509, ServerCertificate};
+use futures::{Future, Poll};
+use rustls::internal::msgs::enums::AlertDescription;
+use rustls::sign::CertifiedKey;
+use rustls::{ResolvesServerCert, TLSError};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+// why a tls handshake failed, as far as the server can tell
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Failure {
+    NoSharedCipher,
+    Incompatible,
+    BadClientCertificate,
+    SniMismatch,
+    CertificateRejected,
+    Closed,
+    Other,
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let reason = match self {
+            Failure::NoSharedCipher => "no shared cipher suite",
+            Failure::Incompatible => "incompatible protocol",
+            Failure::BadClientCertificate => "bad client certificate",
+            Failure::SniMismatch => "certificate does not match the requested server name",
+            Failure::CertificateRejected => "certificate rejected by the client",
+            Failure::Closed => "connection closed",
+            Failure::Other => "other",
+        };
+        f.write_str(reason)
+    }
+}
+
+// the server name a client asked for, and whether the certificate it got is valid for it. Filled
+// in during the handshake of one connection.
+#[derive(Clone, Default)]
+pub struct Requested(Arc<Mutex<Option<(String, bool)>>>);
+
+impl Requested {
+    fn server_name(&self) -> Option<String> {
+        self.0
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|(name, _)| name.clone())
+    }
+
+    // whether the certificate served is not valid for the name asked for
+    fn mismatched(&self) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|(_, mismatched)| *mismatched)
+    }
+
+    // runs f with the resolver recording into this, for the handshake f drives
+    fn recording<T>(&self, f: impl FnOnce() -> T) -> T {
+        CURRENT.with(|current| *current.borrow_mut() = Some(self.clone()));
+        let result = f();
+        CURRENT.with(|current| *current.borrow_mut() = None);
+        result
+    }
+}
+
+thread_local! {
+    // the connection whose handshake is running on this thread, see Recording
+    static CURRENT: RefCell<Option<Requested>> = const { RefCell::new(None) };
+}
+
+// drives a handshake, noting what its client asks for in requested
+pub struct Recording<F> {
+    handshake: F,
+    requested: Requested,
+}
+
+impl<F: Future> Future for Recording<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let handshake = &mut self.handshake;
+        self.requested.recording(|| handshake.poll())
+    }
+}
+
+pub fn recording<F: Future>(handshake: F) -> (Recording<F>, Requested) {
+    let requested = Requested::default();
+    let recording = Recording {
+        handshake,
+        requested: requested.clone(),
+    };
+    (recording, requested)
+}
+
+// notes the server name asked for in the handshake running on this thread, if any. Names with a
+// certificate of their own are valid by construction, for the others whether the certificate
+// served covers them is worked out once per certificate.
+struct RecordingResolver {
+    inner: Arc<dyn ResolvesServerCert>,
+    server_names: HashSet<String>,
+    names_by_certificate: Mutex<HashMap<Vec<u8>, Vec<String>>>,
+}
+
+impl RecordingResolver {
+    fn covers(&self, key: &CertifiedKey, name: &str) -> bool {
+        let leaf = match key.cert.first() {
+            Some(leaf) => leaf,
+            None => return false,
+        };
+        let mut names_by_certificate = self.names_by_certificate.lock().unwrap();
+        let names = names_by_certificate
+            .entry(leaf.0.clone())
+            .or_insert_with(|| {
+                x509_parser::parse_x509_certificate(&leaf.0)
+                    .ok()
+                    .and_then(|(_, cert)| x509::hostnames(&cert))
+                    .unwrap_or_default()
+            });
+        names.iter().any(|pattern| matches_name(pattern, name))
+    }
+}
+
+impl ResolvesServerCert for RecordingResolver {
+    fn resolve(
+        &self,
+        server_name: Option<webpki::DNSNameRef>,
+        sigschemes: &[rustls::SignatureScheme],
+    ) -> Option<CertifiedKey> {
+        let key = self.inner.resolve(server_name, sigschemes);
+        if let (Some(name), Some(key)) = (server_name, &key) {
+            if let Some(requested) = CURRENT.with(|current| current.borrow().clone()) {
+                let name: &str = name.into();
+                let name = name.to_ascii_lowercase();
+                let mismatched = !self.server_names.contains(&name) && !self.covers(key, &name);
+                *requested.0.lock().unwrap() = Some((name, mismatched));
+            }
+        }
+        key
+    }
+}
+
+// a certificate name, possibly with a wildcard for the leftmost label, against a server name
+fn matches_name(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => name
+            .split_once('.')
+            .is_some_and(|(label, rest)| !label.is_empty() && rest == suffix),
+        None => pattern == name,
+    }
+}
+
+// wraps the resolver of a configuration, to note what clients ask for in handshakes driven by
+// Recording. server_names have certificates of their own.
+pub fn recording_resolver(
+    inner: Arc<dyn ResolvesServerCert>,
+    server_names: &[ServerCertificate],
+) -> Arc<dyn ResolvesServerCert> {
+    Arc::new(RecordingResolver {
+        inner,
+        server_names: server_names
+            .iter()
+            .map(|n| n.server_name.to_ascii_lowercase())
+            .collect(),
+        names_by_certificate: Mutex::new(HashMap::new()),
+    })
+}
+
+pub fn classify(err: &std::io::Error, requested: &Requested) -> Failure {
+    use std::io::ErrorKind;
+
+    let tls = err.get_ref().and_then(|e| e.downcast_ref::<TLSError>());
+    match tls {
+        Some(TLSError::PeerIncompatibleError(hint)) if hint.contains("ciphersuites") => {
+            Failure::NoSharedCipher
+        }
+        Some(TLSError::PeerIncompatibleError(_)) => Failure::Incompatible,
+        Some(TLSError::NoCertificatesPresented) | Some(TLSError::WebPKIError(_)) => {
+            Failure::BadClientCertificate
+        }
+        Some(TLSError::AlertReceived(alert)) => match alert {
+            AlertDescription::BadCertificate
+            | AlertDescription::UnsupportedCertificate
+            | AlertDescription::CertificateExpired
+            | AlertDescription::CertificateUnknown
+            | AlertDescription::UnknownCA => {
+                if requested.mismatched() {
+                    Failure::SniMismatch
+                } else {
+                    Failure::CertificateRejected
+                }
+            }
+            AlertDescription::HandshakeFailure
+            | AlertDescription::ProtocolVersion
+            | AlertDescription::InsufficientSecurity => Failure::Incompatible,
+            _ => Failure::Other,
+        },
+        Some(_) => Failure::Other,
+        None => match err.kind() {
+            ErrorKind::UnexpectedEof | ErrorKind::ConnectionReset | ErrorKind::BrokenPipe => {
+                Failure::Closed
+            }
+            _ => Failure::Other,
+        },
+    }
+}
+
+// failed handshakes by reason, for one listener
+#[derive(Default)]
+pub struct Failures {
+    counts: Mutex<HashMap<Failure, usize>>,
+}
+
+impl Failures {
+    fn count(&self, failure: Failure) -> usize {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(failure).or_insert(0);
+        *count += 1;
+        *count
+    }
+
+    pub fn report(
+        &self,
+        peer: Option<std::net::SocketAddr>,
+        requested: &Requested,
+        err: &std::io::Error,
+    ) -> Failure {
+        let failure = classify(err, requested);
+        let count = self.count(failure);
+
+        let peer = peer_name(peer);
+        let server_name = requested
+            .server_name()
+            .map_or_else(String::new, |name| format!(" for {}", name));
+        // clients hanging up early are common, think of port scanners
+        let level = match failure {
+            Failure::Closed => log::Level::Debug,
+            _ => log::Level::Warn,
+        };
+        log::log!(
+            level,
+            "tls handshake with {}{} failed, {} ({} so far): {}",
+            peer,
+            server_name,
+            failure,
+            count,
+            err
+        );
+        failure
+    }
+
+    // most clients check the server name only after the handshake, and just hang up when it
+    // doesn't match. Returns whether that is about to happen.
+    pub fn check_server_name(
+        &self,
+        peer: Option<std::net::SocketAddr>,
+        requested: &Requested,
+    ) -> bool {
+        if !requested.mismatched() {
+            return false;
+        }
+
+        let count = self.count(Failure::SniMismatch);
+        log::warn!(
+            "tls client {} asked for {}, {} ({} so far)",
+            peer_name(peer),
+            requested.server_name().unwrap_or_default(),
+            Failure::SniMismatch,
+            count
+        );
+        true
+    }
+}
+
+fn peer_name(peer: Option<std::net::SocketAddr>) -> String {
+    peer.map_or_else(|| String::from("unknown peer"), |p| p.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::configure_tls;
+    use crate::TlsConfig;
+
+    fn sample(name: &str) -> String {
+        let cargo_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR to be set");
+        let path = std::path::PathBuf::from(cargo_dir)
+            .join("tests")
+            .join("sample_tls")
+            .join(name);
+        String::from(path.to_str().unwrap())
+    }
+
+    fn server_config() -> TlsConfig {
+        TlsConfig {
+            certificate_file: sample("httprust-test-ec-cert.pem"),
+            private_key_file: Some(sample("httprust-test-ec-key.pem")),
+            ..Default::default()
+        }
+    }
+
+    fn client_config() -> rustls::ClientConfig {
+        let mut ca = std::io::BufReader::new(
+            std::fs::File::open(sample("httprust-test-ca-cert.pem")).unwrap(),
+        );
+        let mut cfg = rustls::ClientConfig::new();
+        cfg.root_store.add_pem_file(&mut ca).unwrap();
+        cfg
+    }
+
+    // runs a handshake in memory, returning how it failed on the server side
+    fn failure(server: TlsConfig, client: rustls::ClientConfig, server_name: &str) -> Failure {
+        use rustls::Session;
+
+        fn transfer(from: &mut dyn Session, to: &mut dyn Session) {
+            let mut buffer = vec![];
+            from.write_tls(&mut buffer).unwrap();
+            to.read_tls(&mut &buffer[..]).unwrap();
+        }
+
+        let server = configure_tls(&server).unwrap();
+        let requested = Requested::default();
+        let name = webpki::DNSNameRef::try_from_ascii_str(server_name).unwrap();
+        let mut client = rustls::ClientSession::new(&Arc::new(client), name);
+        let mut server = rustls::ServerSession::new(&server);
+        let failures = Failures::default();
+
+        for _ in 0..10 {
+            transfer(&mut client, &mut server);
+            if let Err(e) = requested.recording(|| server.process_new_packets()) {
+                let err = std::io::Error::new(std::io::ErrorKind::InvalidData, e);
+                let failure = failures.report(None, &requested, &err);
+                assert_eq!(Some(&1), failures.counts.lock().unwrap().get(&failure));
+                return failure;
+            }
+            transfer(&mut server, &mut client);
+            // the client sends an alert on failure, which the server reads next
+            let _ = client.process_new_packets();
+        }
+        panic!("handshake did not fail");
+    }
+
+    #[test]
+    fn no_shared_cipher() {
+        let server = TlsConfig {
+            cipher_suites: vec![String::from("TLS13_AES_256_GCM_SHA384")],
+            ..server_config()
+        };
+        let mut client = client_config();
+        client.ciphersuites = rustls::ALL_CIPHERSUITES
+            .iter()
+            .cloned()
+            .filter(|s| s.suite == rustls::CipherSuite::TLS13_CHACHA20_POLY1305_SHA256)
+            .collect();
+
+        assert_eq!(
+            Failure::NoSharedCipher,
+            failure(server, client, "localhost")
+        );
+    }
+
+    #[test]
+    fn sni_mismatch() {
+        assert_eq!(
+            Failure::SniMismatch,
+            failure(server_config(), client_config(), "example.com")
+        );
+    }
+
+    // resolves the certificate for name the way a handshake would
+    fn resolve(server: &rustls::ServerConfig, name: &str) -> Requested {
+        let requested = Requested::default();
+        let name = webpki::DNSNameRef::try_from_ascii_str(name).unwrap();
+        requested.recording(|| server.cert_resolver.resolve(Some(name), &[]));
+        requested
+    }
+
+    #[test]
+    fn sni_mismatch_after_handshake() {
+        let server = configure_tls(&server_config()).unwrap();
+        let failures = Failures::default();
+
+        assert!(!failures.check_server_name(None, &resolve(&server, "localhost")));
+        assert!(!failures.check_server_name(None, &resolve(&server, "LocalHost")));
+        assert!(failures.check_server_name(None, &resolve(&server, "example.com")));
+        assert_eq!(
+            Some(&1),
+            failures.counts.lock().unwrap().get(&Failure::SniMismatch)
+        );
+    }
+
+    #[test]
+    fn server_names_match() {
+        let server = configure_tls(&TlsConfig {
+            server_names: vec![ServerCertificate {
+                server_name: String::from("alpha.test"),
+                certificate_file: sample("httprust-test-alpha-cert.pem"),
+                private_key_file: Some(sample("httprust-test-alpha-key.pem")),
+                pkcs12_password: None,
+            }],
+            ..server_config()
+        })
+        .unwrap();
+
+        assert!(!resolve(&server, "alpha.test").mismatched());
+        assert!(!resolve(&server, "localhost").mismatched());
+        assert!(resolve(&server, "beta.test").mismatched());
+        // nothing is recorded outside of a handshake
+        let name = webpki::DNSNameRef::try_from_ascii_str("beta.test").unwrap();
+        assert!(server.cert_resolver.resolve(Some(name), &[]).is_some());
+    }
+
+    #[test]
+    fn wildcard_names() {
+        assert!(matches_name("*.example.com", "www.example.com"));
+        assert!(!matches_name("*.example.com", "example.com"));
+        assert!(!matches_name("*.example.com", "a.b.example.com"));
+        assert!(matches_name("Example.com", "example.com"));
+    }
+
+    #[test]
+    fn certificate_rejected() {
+        // trusts nothing, so the name is fine but the issuer isn't
+        assert_eq!(
+            Failure::CertificateRejected,
+            failure(server_config(), rustls::ClientConfig::new(), "localhost")
+        );
+    }
+
+    #[test]
+    fn closed() {
+        let err = std::io::Error::from(std::io::ErrorKind::UnexpectedEof);
+        assert_eq!(Failure::Closed, classify(&err, &Requested::default()));
+    }
+}
@@ -4,161 +4,1155 @@ extern crate log;
 extern crate tokio;
 extern crate tokio_signal;
 
+use connection::Connection;
 use futures::sync::oneshot::{channel, Sender};
 use hyper::rt::{self, Future, Stream};
+use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 
+mod access;
+mod acme;
 mod async_stream;
+mod auth;
+mod bearer;
 mod compressed_read;
+mod config;
+mod connection;
+mod drain;
+mod error;
 mod handler;
+mod handshake;
+mod keys;
+mod limits;
+mod listen;
 mod meta_info;
+mod selfsigned;
+mod signed;
+mod sni;
+mod systemd;
 mod tls;
+mod tus;
+mod x509;
 
-#[derive(Debug)]
+pub use error::Error;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct TlsConfig {
+    // a pem certificate chain, or a PKCS#12 bundle holding both the chain and its private key
+    pub certificate_file: String,
+    // pem PKCS#1, PKCS#8 or SEC1 private key, not needed for PKCS#12 bundles
+    pub private_key_file: Option<String>,
+    #[serde(serialize_with = "config::redacted")]
+    pub pkcs12_password: Option<String>,
+    pub client_auth: Option<ClientAuthConfig>,
+    // extra certificates selected by sni, the one above is the default
+    pub server_names: Vec<ServerCertificate>,
+    // how often to check the files for changes, to reload them. SIGHUP always reloads.
+    #[serde(with = "config::seconds")]
+    pub watch_interval: Option<std::time::Duration>,
+    // "1.2" or "1.3", by default both are accepted
+    pub min_version: Option<String>,
+    // iana names like TLS13_AES_256_GCM_SHA384 in order of preference, empty for all supported
+    pub cipher_suites: Vec<String>,
+    // "h2" and/or "http/1.1" in order of preference, by default both with h2 first
+    pub alpn_protocols: Option<Vec<String>>,
+    // stateless resumption, off by default
+    pub session_tickets: bool,
+    // number of sessions kept for resumption, 0 disables it. 256 by default.
+    pub session_cache_size: Option<usize>,
+    // append session secrets to the file named by SSLKEYLOGFILE, to decrypt captured traffic with
+    // wireshark. For development only.
+    pub key_log: bool,
+}
+
+// a development certificate authority and a certificate for the host names signed by it, generated
+// at startup. Without a cache_dir they are written to a fresh temporary directory each time; with
+// one they are kept, so the authority only needs to be trusted once.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SelfSignedConfig {
+    pub hostnames: Vec<String>,
+    pub cache_dir: Option<String>,
+}
+
+impl Default for SelfSignedConfig {
+    fn default() -> SelfSignedConfig {
+        SelfSignedConfig {
+            hostnames: vec![
+                String::from("localhost"),
+                String::from("127.0.0.1"),
+                String::from("::1"),
+            ],
+            cache_dir: None,
+        }
+    }
+}
+
+// the generated files, pem encoded
+#[derive(Debug, Clone)]
+pub struct SelfSigned {
+    pub ca_certificate_file: String,
     pub certificate_file: String,
     pub private_key_file: String,
 }
 
-#[derive(Debug)]
+// certificates from an ACME certificate authority like let's encrypt, requested at startup when
+// there is no usable one yet and renewed before they expire. Until the first one is issued a self
+// signed placeholder is served. The tls certificate and key files should be certificate_file()
+// and private_key_file().
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AcmeConfig {
+    pub directory_url: String,
+    pub domains: Vec<String>,
+    // mailto: urls the certificate authority can send expiry notices to
+    pub contact: Vec<String>,
+    // agreeing to the certificate authority's terms of service, which registering an account
+    // requires
+    pub agree_tos: bool,
+    // where the account key, certificate and its key are kept. Required, there is no default
+    // location for private keys.
+    pub cache_dir: String,
+    // "http-01", answered by a plain http listener on http_port, or "tls-alpn-01", answered on the
+    // tls port
+    pub challenge: String,
+    pub http_port: u16,
+    // trust the certificate authorities in this file when talking to the directory, instead of the
+    // usual roots. For testing against a local pebble.
+    pub directory_ca_file: Option<String>,
+}
+
+impl AcmeConfig {
+    pub fn certificate_file(&self) -> String {
+        acme::certificate_file(self)
+    }
+
+    pub fn private_key_file(&self) -> String {
+        acme::private_key_file(self)
+    }
+}
+
+impl Default for AcmeConfig {
+    fn default() -> AcmeConfig {
+        AcmeConfig {
+            directory_url: String::from("https://acme-v02.api.letsencrypt.org/directory"),
+            domains: vec![],
+            contact: vec![],
+            agree_tos: false,
+            cache_dir: String::new(),
+            challenge: String::from("http-01"),
+            http_port: 80,
+            directory_ca_file: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerCertificate {
+    pub server_name: String,
+    pub certificate_file: String,
+    pub private_key_file: Option<String>,
+    #[serde(serialize_with = "config::redacted")]
+    pub pkcs12_password: Option<String>,
+}
+
+// client certificates are verified against the ca certificates in ca_file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientAuthConfig {
+    pub ca_file: String,
+    // when set, clients without a certificate are let in anonymously instead of refused
+    #[serde(default)]
+    pub optional: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_body_size: Option<u64>,
+    pub disk_quota: Option<u64>,
+    pub min_free_space: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadConfig {
+    pub endpoint: String,
+    pub staging_dir: String,
+}
+
+impl Default for UploadConfig {
+    fn default() -> UploadConfig {
+        UploadConfig {
+            endpoint: String::from("/files"),
+            staging_dir: String::from(".tus-staging"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Realm {
+    pub prefix: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BasicAuthConfig {
+    pub htpasswd_file: String,
+    // the whole site by default, like on the command line
+    #[serde(default = "everything")]
+    pub realms: Vec<Realm>,
+    // basic auth sends credentials in the clear, so without tls it needs to be explicitly allowed
+    #[serde(default)]
+    pub allow_insecure: bool,
+}
+
+fn everything() -> Vec<Realm> {
+    vec![Realm {
+        prefix: String::from("/"),
+        name: String::from("httprust"),
+    }]
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BearerAuthConfig {
+    // jwks document, pem encoded public key (RS256, ES256) or shared secret (HS256)
+    pub jwt_key_file: Option<String>,
+    pub audience: Option<String>,
+    pub issuer: Option<String>,
+    // claim holding the permissions, as space separated string or array ("read:/a write:/b")
+    pub permissions_claim: String,
+    // lines of "<token> <permission>..."
+    pub tokens_file: Option<String>,
+}
+
+impl Default for BearerAuthConfig {
+    fn default() -> BearerAuthConfig {
+        BearerAuthConfig {
+            jwt_key_file: None,
+            audience: None,
+            issuer: None,
+            permissions_claim: String::from("scope"),
+            tokens_file: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigningConfig {
+    pub key_file: String,
+    // paths under these prefixes are only served through signed urls
+    #[serde(default)]
+    pub required_prefixes: Vec<String>,
+}
+
+// address ranges in cidr notation ("10.0.0.0/8", "fd00::/8", "192.168.1.5")
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessRules {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    // when not empty, only clients presenting a certificate with one of these subjects, either
+    // the full distinguished name or just the common name, are allowed
+    pub subjects: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PathAccess {
+    pub prefix: String,
+    pub rules: AccessRules,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessConfig {
+    // connections from denied addresses are dropped before anything is read
+    pub global: AccessRules,
+    // requests under denied paths get a 403
+    pub paths: Vec<PathAccess>,
+    // proxies whose X-Forwarded-For header is believed
+    pub trusted_proxies: Vec<String>,
+}
+
+impl AccessConfig {
+    fn restricts_addresses(&self) -> bool {
+        let restricts = |r: &AccessRules| !r.allow.is_empty() || !r.deny.is_empty();
+        restricts(&self.global) || self.paths.iter().any(|p| restricts(&p.rules))
+    }
+
+    fn requires_subjects(&self) -> bool {
+        !self.global.subjects.is_empty() || self.paths.iter().any(|p| !p.rules.subjects.is_empty())
+    }
+}
+
+// a unix domain socket to listen on instead of tcp, for a proxy on the same host. Plain http only.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UnixSocketConfig {
+    pub path: String,
+    // permissions of the socket file, like 0o660. By default what the umask leaves.
+    pub mode: Option<u32>,
+}
+
+// another address to serve the same content on, with or without tls
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub port: u16,
+    pub local_only: bool,
+    // as in Config
+    pub bind: Vec<String>,
+    pub unix: Option<UnixSocketConfig>,
+    pub tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub port: u16,
     pub local_only: bool,
+    // ip addresses to listen on, with or without a port, like 10.0.0.5 or [::]:8080. Without any,
+    // all interfaces on ipv4 and ipv6, or only loopback with local_only.
+    pub bind: Vec<String>,
+    // listen on this socket instead of on tcp
+    pub unix: Option<UnixSocketConfig>,
+    // running as a systemd service: use the sockets of socket activation instead of the ones above
+    // when given, and report readiness, stopping and watchdog keepalives to NOTIFY_SOCKET
+    pub systemd: bool,
     pub root: String,
+    // gzip html, css, javascript and other text for clients that accept it
+    pub compress: bool,
+    // added to every response that doesn't set them itself, like Cache-Control
+    pub headers: std::collections::BTreeMap<String, String>,
     pub tls: Option<TlsConfig>,
+    // served next to the listener above, all stopping together
+    pub listeners: Vec<ListenerConfig>,
+    // with tls, a plain http listener on this port redirects everything to https
+    pub redirect_port: Option<u16>,
+    // how long to wait for open connections when stopping before closing them, None waits for
+    // as long as they take
+    #[serde(with = "config::seconds")]
+    pub drain_timeout: Option<std::time::Duration>,
+    pub acme: Option<AcmeConfig>,
+    pub limits: LimitsConfig,
+    pub uploads: Option<UploadConfig>,
+    pub basic_auth: Option<BasicAuthConfig>,
+    pub bearer_auth: Option<BearerAuthConfig>,
+    pub signed_urls: Option<SigningConfig>,
+    pub access: AccessConfig,
 }
 
-pub fn run_notify<F>(cfg: Config, notify: F)
-where
-    F: FnOnce() + Send + 'static,
-{
-    log::info!("starting server with configuration {:#?}", cfg);
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            port: 8080,
+            local_only: false,
+            bind: Vec::new(),
+            unix: None,
+            systemd: false,
+            root: String::from("."),
+            compress: true,
+            headers: std::collections::BTreeMap::new(),
+            tls: None,
+            listeners: Vec::new(),
+            redirect_port: None,
+            drain_timeout: Some(std::time::Duration::from_secs(30)),
+            acme: None,
+            limits: LimitsConfig::default(),
+            uploads: None,
+            basic_auth: None,
+            bearer_auth: None,
+            signed_urls: None,
+            access: AccessConfig::default(),
+        }
+    }
+}
 
-    rt::run(rt::lazy(move || {
-        if cfg.tls.is_some() {
-            let (server, stopper) = make_tls_server(cfg);
-            let signal_handler = make_signal_handler(stopper);
+impl Config {
+    // reads a toml file with the same structure, see config.example.toml
+    pub fn from_file(path: &str) -> std::io::Result<Config> {
+        config::load(path)
+    }
 
-            rt::spawn(signal_handler);
-            rt::spawn(server);
-        } else {
-            let (server, stopper) = make_plain_server(cfg);
-            let signal_handler = make_signal_handler(stopper);
+    // the configuration as a toml file would give it, with passwords redacted
+    pub fn to_toml(&self) -> std::io::Result<String> {
+        config::to_string(self)
+    }
+}
 
-            rt::spawn(signal_handler);
-            rt::spawn(server);
+// validates a configuration without serving it: the files it refers to are read, and addresses
+// are parsed but not bound
+pub fn check(cfg: &Config) -> std::io::Result<()> {
+    make_handler(cfg)?;
+    if let Some(acme) = &cfg.acme {
+        acme::validate(acme)?;
+    }
+    for (index, listener) in listeners(cfg).iter().enumerate() {
+        listen::with_port(&listener.bind, listener.port)?;
+        if let Some(unix) = listener.unix.as_ref().and_then(|u| u.mode) {
+            if unix > 0o777 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("invalid unix socket mode {:o}", unix),
+                ));
+            }
         }
+        // acme provides the main listener's certificate once running
+        if index == 0 && cfg.acme.is_some() {
+            continue;
+        }
+        if let Some(tls) = &listener.tls {
+            tls::configure_tls(tls)?;
+        }
+    }
+    Ok(())
+}
 
-        log::info!("ready to serve");
-        notify();
+// creates a path plus query string that can be fetched without further authentication until
+// `valid_for` has passed, for a server using the same signing key
+pub fn sign_url(
+    key_file: &str,
+    path: &str,
+    valid_for: std::time::Duration,
+) -> std::io::Result<String> {
+    let key = signed::load_key(key_file)?;
+    Ok(signed::sign(
+        &key,
+        path,
+        signed::now() + valid_for.as_secs(),
+    ))
+}
 
-        Ok(())
-    }));
+pub fn self_signed(cfg: &SelfSignedConfig) -> std::io::Result<SelfSigned> {
+    match &cfg.cache_dir {
+        Some(dir) => selfsigned::generate(&cfg.hostnames, std::path::Path::new(dir), true),
+        None => {
+            let dir = std::env::temp_dir().join(format!("httprust-{}", std::process::id()));
+            selfsigned::generate(&cfg.hostnames, &dir, false)
+        }
+    }
+}
 
-    log::info!("done");
+// a running server, to find where it listens and to stop it. Clones control the same server.
+#[derive(Clone)]
+pub struct Handle {
+    addresses: Vec<std::net::SocketAddr>,
+    stopper: Arc<std::sync::Mutex<Option<Sender<()>>>>,
+    aborter: Arc<std::sync::Mutex<Option<Sender<()>>>>,
+    done: futures::future::Shared<futures::sync::oneshot::Receiver<()>>,
+    systemd: Option<systemd::Manager>,
+    live: Arc<std::sync::Mutex<Live>>,
 }
 
-pub fn run(cfg: Config) {
-    run_notify(cfg, || {});
+// what a reload can change while serving
+struct Live {
+    started: Config,
+    handler: Current,
+    // per listener, in the order of listeners()
+    tls: Vec<Option<tls::SharedConfig>>,
+    challenges: Option<acme::Challenges>,
 }
 
-fn make_plain_server(cfg: Config) -> (impl Future<Item = (), Error = ()>, Sender<()>) {
-    log::warn!("running insecure http (not s) server");
+// the handler for new requests, replaced by a reload
+type Current = Arc<std::sync::RwLock<Arc<handler::Handler>>>;
 
-    let address = {
-        if cfg.local_only {
-            [127, 0, 0, 1]
-        } else {
-            [0, 0, 0, 0]
+fn current(handler: &Current) -> Arc<handler::Handler> {
+    handler.read().unwrap().clone()
+}
+
+impl Handle {
+    // the tcp addresses listened on: the main listener's, the extra listeners' and then the
+    // redirect listener's, in that order. Useful with port 0, which listens on a free port.
+    pub fn addresses(&self) -> &[std::net::SocketAddr] {
+        &self.addresses
+    }
+
+    // stops accepting connections and lets the server future finish once the ones open are done,
+    // or the drain timeout passed. Does nothing when already stopping.
+    pub fn shutdown(&self) {
+        if let Some(stopper) = self.stopper.lock().unwrap().take() {
+            if let Some(manager) = &self.systemd {
+                manager.notify("STOPPING=1");
+            }
+            let _ = stopper.send(());
         }
+    }
+
+    // stops right away, closing any open connections
+    pub fn abort(&self) {
+        self.shutdown();
+        if let Some(aborter) = self.aborter.lock().unwrap().take() {
+            let _ = aborter.send(());
+        }
+    }
+
+    // blocks until the server future has finished, or was dropped. Not to be called from the
+    // runtime it runs on.
+    pub fn wait(&self) {
+        let _ = self.done.clone().wait();
+    }
+
+    // applies cfg to new requests and tls handshakes: the root, limits, uploads, authentication,
+    // access rules and certificates. Open connections carry on as they are. Nothing changes when
+    // cfg is invalid, or changes what is only applied when starting: the addresses listened on,
+    // whether they use tls, and the rest of what fixed() covers.
+    pub fn reload(&self, cfg: Config) -> std::io::Result<()> {
+        let live = self.live.lock().unwrap();
+        if fixed(&cfg) != fixed(&live.started) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "changed listeners, systemd, redirect_port, drain_timeout or acme need a restart",
+            ));
+        }
+
+        let mut handler = make_handler(&cfg)?;
+        if let Some(challenges) = &live.challenges {
+            handler = handler.with_challenges(challenges.clone());
+        }
+
+        // every certificate is loaded before any is replaced
+        let mut certificates = Vec::new();
+        for (index, (listener, shared)) in listeners(&cfg).into_iter().zip(&live.tls).enumerate() {
+            // acme manages the certificate of the main listener
+            if index == 0 && live.started.acme.is_some() {
+                continue;
+            }
+            if let (Some(tls), Some(shared)) = (listener.tls, shared) {
+                let server = tls::configure_tls(&tls)?;
+                certificates.push((shared, tls, server));
+            }
+        }
+
+        for (shared, tls, server) in certificates {
+            shared.update(tls, server);
+        }
+        *live.handler.write().unwrap() = Arc::new(handler);
+        log::info!("reloaded configuration");
+        Ok(())
+    }
+}
+
+// the parts of a configuration only applied when starting
+fn fixed(cfg: &Config) -> String {
+    let listeners: Vec<_> = listeners(cfg)
+        .into_iter()
+        .map(|l| (l.port, l.local_only, l.bind, l.unix, l.tls.is_some()))
+        .collect();
+    format!(
+        "{:?}",
+        (
+            listeners,
+            cfg.systemd,
+            cfg.redirect_port,
+            cfg.drain_timeout,
+            &cfg.acme
+        )
+    )
+}
+
+// binds every listener, returning the server to spawn on a tokio runtime and a handle to control
+// it. Unlike run it leaves signals alone, see stop_on_signals for that.
+pub fn serve(cfg: Config) -> Result<(impl Future<Item = (), Error = ()> + Send, Handle), Error> {
+    log::info!("starting server with configuration {:#?}", cfg);
+
+    let systemd = if cfg.systemd {
+        Some(systemd::Manager::from_env())
+    } else {
+        None
     };
-    let address = (address, cfg.port).into();
+    let (server, stopper, aborter, addresses, live) = make_server(cfg, systemd.as_ref())?;
+    let (finished, done) = channel::<()>();
+    let server = server.then(move |result| {
+        let _ = finished.send(());
+        log::info!("done");
+        result
+    });
 
-    let handle = handler::Handler::new(cfg.root.as_str())
-        .map_err(|e| {
-            log::error!("error creating handler {}", e);
-            panic!("create handler");
-        })
-        .unwrap();
+    // the sockets are listening, connections queue until the server runs
+    log::info!("ready to serve");
+    if let Some(manager) = &systemd {
+        manager.notify("READY=1");
+    }
 
-    let handle = Arc::new(handle);
+    let handle = Handle {
+        addresses,
+        stopper: Arc::new(std::sync::Mutex::new(Some(stopper))),
+        aborter: Arc::new(std::sync::Mutex::new(Some(aborter))),
+        done: done.shared(),
+        systemd,
+        live: Arc::new(std::sync::Mutex::new(live)),
+    };
+    Ok((server, handle))
+}
 
-    let handle = move || {
-        let this_handler = handle.clone();
-        hyper::service::service_fn(move |req| this_handler.handle(req))
+// like run, calling notify with the addresses listened on once the server is up
+pub fn run_notify<F>(cfg: Config, notify: F) -> Result<(), Error>
+where
+    F: FnOnce(Vec<std::net::SocketAddr>) + Send + 'static,
+{
+    run_with(cfg, move |handle| notify(handle.addresses().to_vec()))
+}
+
+// serves until SIGINT or SIGTERM. Returns once the server is done, or right away when it could
+// not start.
+pub fn run(cfg: Config) -> Result<(), Error> {
+    run_notify(cfg, |_| {})
+}
+
+// like run, reloading the configuration returned by load on SIGHUP
+pub fn run_reloading<L>(cfg: Config, load: L) -> Result<(), Error>
+where
+    L: Fn() -> std::io::Result<Config> + Send + 'static,
+{
+    run_with(cfg, move |handle| {
+        rt::spawn(reload_on_sighup(handle, load));
+    })
+}
+
+// serves on a runtime of its own, calling started on it once serving
+fn run_with<S>(cfg: Config, started: S) -> Result<(), Error>
+where
+    S: FnOnce(Handle) + Send + 'static,
+{
+    let mut runtime = tokio::runtime::Runtime::new().map_err(Error::Runtime)?;
+    runtime.block_on(rt::lazy(move || {
+        let (server, handle) = serve(cfg)?;
+
+        rt::spawn(stop_on_signals(handle.clone()));
+        rt::spawn(server);
+
+        started(handle);
+
+        Ok::<_, Error>(())
+    }))?;
+    let _ = runtime.shutdown_on_idle().wait();
+    Ok(())
+}
+
+type Background = Box<dyn Future<Item = (), Error = ()> + Send>;
+
+// the main listener followed by the extra ones
+fn listeners(cfg: &Config) -> Vec<ListenerConfig> {
+    let main = ListenerConfig {
+        port: cfg.port,
+        local_only: cfg.local_only,
+        bind: cfg.bind.clone(),
+        unix: cfg.unix.clone(),
+        tls: cfg.tls.clone(),
     };
+    std::iter::once(main)
+        .chain(cfg.listeners.iter().cloned())
+        .collect()
+}
+
+// the same interfaces as the main listener, on another port
+fn bind_next_to(cfg: &Config, port: u16) -> Result<Vec<std::net::TcpListener>, Error> {
+    let interfaces = listen::with_port(&cfg.bind, port).map_err(Error::Config)?;
+    listen::bind_all(&interfaces, port, cfg.local_only).map_err(Error::Listen)
+}
 
-    let server = hyper::Server::bind(&address).serve(handle);
+fn incoming(listener: std::net::TcpListener) -> Result<tokio::net::tcp::Incoming, Error> {
+    let listener = tokio::net::TcpListener::from_std(listener, &tokio::reactor::Handle::default())
+        .map_err(Error::Listen)?;
+    log::info!(
+        "listening on {:?}",
+        listener.local_addr().map_err(Error::Listen)?
+    );
+    Ok(listener.incoming())
+}
+
+// every listener shares one handler, and stops when the first returned sender fires, closing
+// open connections when the second does. Also returns the tcp addresses listened on.
+#[allow(clippy::type_complexity)]
+fn make_server(
+    cfg: Config,
+    systemd: Option<&systemd::Manager>,
+) -> Result<
+    (
+        impl Future<Item = (), Error = ()>,
+        Sender<()>,
+        Sender<()>,
+        Vec<std::net::SocketAddr>,
+        Live,
+    ),
+    Error,
+> {
+    let challenges = acme::Challenges::default();
+    let mut handle = make_handler(&cfg).map_err(Error::Config)?;
+    if cfg.acme.is_some() {
+        handle = handle.with_challenges(challenges.clone());
+    }
+    let handle: Current = Arc::new(std::sync::RwLock::new(Arc::new(handle)));
+    let mut live = Live {
+        started: cfg.clone(),
+        handler: handle.clone(),
+        tls: Vec::new(),
+        challenges: cfg.acme.as_ref().map(|_| challenges.clone()),
+    };
 
-    log::info!("listening on {:?}", address);
+    if let Some(acme) = &cfg.acme {
+        acme::prepare(acme).map_err(Error::Config)?;
+    }
 
+    let connections = Arc::new(drain::Connections::default());
+    let drain_timeout = cfg.drain_timeout;
+    let stopping = connections.clone();
     let (tx, rx) = channel::<()>();
-    let server = server
-        .with_graceful_shutdown(rx)
-        .map_err(|e| log::error!("server error {}", e));
+    // stops certificate renewal along with the server, or when it is dropped
+    let (stop_acme, acme_stopped) = std::sync::mpsc::channel::<()>();
+    // a dropped handle leaves the server running
+    let shutdown = rx
+        .or_else(|_| futures::future::empty::<(), ()>())
+        .map(move |_| {
+            drop(stop_acme);
+            stopping.stop(drain_timeout)
+        })
+        .shared();
+    let (abort_tx, abort_rx) = channel::<()>();
+    let drain = drain::Drain::new(
+        connections.clone(),
+        abort_rx.or_else(|_| futures::future::empty::<(), ()>()),
+        shutdown.clone().map(|_| ()).map_err(|_| ()),
+        drain_timeout,
+    );
 
-    (server, tx)
-}
+    let mut servers = Vec::new();
+    let mut addresses = Vec::new();
+    // running next to the servers for as long as they do. Never finishes by itself.
+    let mut background: Vec<Background> = vec![Box::new(futures::future::empty())];
+    let mut main_tls = None;
+    let mut main_port = None;
+
+    // with socket activation, systemd's sockets take the place of the main listener's own
+    let mut inherited = Vec::new();
+    if let Some(manager) = systemd {
+        inherited = systemd::listeners().map_err(Error::Listen)?;
+        if let Some(watchdog) = manager.watchdog() {
+            background.push(Box::new(watchdog));
+        }
+    }
 
-// todo: plenty of duplicated code with make_plain_server, but heavy use of generics make it very hard to
-//       create meaningful return types
-fn make_tls_server(cfg: Config) -> (impl Future<Item = (), Error = ()>, Sender<()>) {
-    log::info!("running https server");
+    for (index, listener) in listeners(&cfg).into_iter().enumerate() {
+        let sockets = if index == 0 && !inherited.is_empty() {
+            std::mem::take(&mut inherited)
+        } else {
+            listen::open(&listener).map_err(Error::Listen)?
+        };
 
-    let address = {
-        if cfg.local_only {
-            [127, 0, 0, 1]
+        let mut shared = None;
+        if let Some(tls) = listener.tls {
+            log::info!("running https server");
+            let mut config = tls::SharedConfig::new(tls).map_err(Error::Config)?;
+            // acme manages the certificate of the main listener
+            if index == 0 {
+                if let Some(acme) = &cfg.acme {
+                    if acme.challenge == "tls-alpn-01" {
+                        config = config.with_challenges(challenges.clone());
+                    }
+                }
+                main_tls = Some(config.clone());
+            }
+            background.push(Box::new(config.clone().watch()));
+            shared = Some(config);
         } else {
-            [0, 0, 0, 0]
+            log::warn!("running insecure http (not s) server");
         }
-    };
-    let address = (address, cfg.port).into();
+        live.tls.push(shared.clone());
 
-    let handle = handler::Handler::new(cfg.root.as_str())
-        .map_err(|e| {
-            log::error!("error creating handler {}", e);
-            panic!("create handler");
-        })
-        .unwrap();
+        for socket in sockets {
+            if let listen::Socket::Tcp(tcp) = &socket {
+                let address = tcp.local_addr().map_err(Error::Listen)?;
+                if index == 0 && main_port.is_none() {
+                    main_port = Some(address.port());
+                }
+                addresses.push(address);
+            }
+            let shutdown = shutdown.clone().map(|_| ()).map_err(|_| ());
+            let server = match (socket, &shared) {
+                (listen::Socket::Tcp(tcp), Some(shared)) => make_tls_server(
+                    tcp,
+                    shared.clone(),
+                    handle.clone(),
+                    connections.clone(),
+                    drain.clone(),
+                    shutdown,
+                ),
+                (listen::Socket::Tcp(tcp), None) => make_plain_server(
+                    tcp,
+                    handle.clone(),
+                    connections.clone(),
+                    drain.clone(),
+                    shutdown,
+                ),
+                (listen::Socket::Unix(unix, path), None) => make_unix_server(
+                    unix,
+                    path,
+                    handle.clone(),
+                    connections.clone(),
+                    drain.clone(),
+                    shutdown,
+                ),
+                (socket, Some(_)) => {
+                    return Err(Error::Config(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("tls over unix sockets is not supported: {}", socket),
+                    )))
+                }
+            };
+            servers.push(server?);
+        }
+    }
+
+    // plain http next to the tls server, for redirects and http-01 challenges
+    let mut redirects = Vec::new();
+    if let Some(port) = cfg.redirect_port {
+        // the port actually bound, when asked for any free one
+        let https_port = main_port.unwrap_or(cfg.port);
+        for tcp in bind_next_to(&cfg, port)? {
+            addresses.push(tcp.local_addr().map_err(Error::Listen)?);
+            redirects.push((tcp, Some(https_port)));
+        }
+    }
+    if let Some(acme) = &cfg.acme {
+        if acme.challenge != "tls-alpn-01" && cfg.redirect_port != Some(acme.http_port) {
+            for tcp in bind_next_to(&cfg, acme.http_port)? {
+                redirects.push((tcp, None));
+            }
+        }
+    }
+    for (tcp, https_port) in redirects {
+        servers.push(make_redirect_server(
+            tcp,
+            https_port,
+            handle.clone(),
+            connections.clone(),
+            drain.clone(),
+            shutdown.clone().map(|_| ()).map_err(|_| ()),
+        )?);
+    }
+    if let Some(acme) = cfg.acme {
+        acme::spawn(acme, challenges, main_tls.unwrap(), acme_stopped);
+    }
+
+    let server = futures::future::join_all(servers)
+        .map(move |_| connections.report())
+        .select(
+            futures::future::select_all(background)
+                .map(|_| ())
+                .map_err(|_| ()),
+        )
+        .map(|_| ())
+        .map_err(|_| ());
+
+    Ok((server, tx, abort_tx, addresses, live))
+}
+
+fn make_plain_server<S>(
+    listener: std::net::TcpListener,
+    handle: Current,
+    connections: Arc<drain::Connections>,
+    drain: drain::Drain,
+    shutdown: S,
+) -> Result<Background, Error>
+where
+    S: Future<Item = ()> + Send + 'static,
+{
+    let filter = handle.clone();
 
-    let handle = Arc::new(handle);
+    let incoming = incoming(listener)?.filter(move |conn| {
+        conn.peer_addr()
+            .map(|peer| current(&filter).accepts(&peer))
+            .unwrap_or(false)
+    });
 
-    let handle = move || {
+    let handle = hyper::service::make_service_fn(move |conn: &tokio::net::TcpStream| {
         let this_handler = handle.clone();
-        hyper::service::service_fn(move |req| this_handler.handle(req))
-    };
+        let info = conn.info();
+        let open = connections.open();
+        Ok::<_, hyper::Error>(hyper::service::service_fn(move |req| {
+            let _ = &open;
+            current(&this_handler).handle(req, &info)
+        }))
+    });
 
-    let cfg = tls::configure_tls(cfg.tls.unwrap()).unwrap();
+    let server = hyper::Server::builder(incoming)
+        .executor(drain)
+        .serve(handle);
 
-    let server = tls::make_server(address, cfg).unwrap().serve(handle);
+    Ok(Box::new(
+        server
+            .with_graceful_shutdown(shutdown)
+            .map_err(|e| log::error!("server error {}", e)),
+    ))
+}
 
-    log::info!("listening on {:?}", address);
+// removes the socket file at path when done
+fn make_unix_server<S>(
+    listener: std::os::unix::net::UnixListener,
+    path: Option<String>,
+    handle: Current,
+    connections: Arc<drain::Connections>,
+    drain: drain::Drain,
+    shutdown: S,
+) -> Result<Background, Error>
+where
+    S: Future<Item = ()> + Send + 'static,
+{
+    log::info!(
+        "listening on {}",
+        listen::Socket::Unix(listener.try_clone().map_err(Error::Listen)?, None)
+    );
+    let listener = tokio::net::UnixListener::from_std(listener, &tokio::reactor::Handle::default())
+        .map_err(Error::Listen)?;
 
-    let (tx, rx) = channel::<()>();
-    let server = server
-        .with_graceful_shutdown(rx)
-        .map_err(|e| log::error!("server error {}", e));
+    let handle = hyper::service::make_service_fn(move |conn: &tokio::net::UnixStream| {
+        let this_handler = handle.clone();
+        let info = conn.info();
+        let open = connections.open();
+        Ok::<_, hyper::Error>(hyper::service::service_fn(move |req| {
+            let _ = &open;
+            current(&this_handler).handle(req, &info)
+        }))
+    });
+
+    let server = hyper::Server::builder(listener.incoming())
+        .executor(drain)
+        .serve(handle);
+
+    Ok(Box::new(
+        server
+            .with_graceful_shutdown(shutdown)
+            .map_err(|e| log::error!("server error {}", e))
+            .then(move |r| {
+                if let Some(path) = path {
+                    let _ = std::fs::remove_file(path);
+                }
+                r
+            }),
+    ))
+}
+
+fn make_tls_server<S>(
+    listener: std::net::TcpListener,
+    shared: tls::SharedConfig,
+    handle: Current,
+    connections: Arc<drain::Connections>,
+    drain: drain::Drain,
+    shutdown: S,
+) -> Result<Background, Error>
+where
+    S: Future<Item = ()> + Send + 'static,
+{
+    let filter = handle.clone();
+
+    let handle = hyper::service::make_service_fn(move |conn: &tls::ServerStream| {
+        let this_handler = handle.clone();
+        let info = conn.info();
+        let open = connections.open();
+        Ok::<_, hyper::Error>(hyper::service::service_fn(move |req| {
+            let _ = &open;
+            current(&this_handler).handle(req, &info)
+        }))
+    });
+
+    let server = tls::make_server(incoming(listener)?, shared, move |peer| {
+        current(&filter).accepts(peer)
+    })
+    .executor(drain)
+    .serve(handle);
+
+    Ok(Box::new(
+        server
+            .with_graceful_shutdown(shutdown)
+            .map_err(|e| log::error!("server error {}", e)),
+    ))
+}
+
+// plain http next to the tls server, answering acme http-01 challenges and redirecting anything
+// else to https_port, if given
+fn make_redirect_server<S>(
+    listener: std::net::TcpListener,
+    https_port: Option<u16>,
+    handle: Current,
+    connections: Arc<drain::Connections>,
+    drain: drain::Drain,
+    shutdown: S,
+) -> Result<Background, Error>
+where
+    S: Future<Item = ()> + Send + 'static,
+{
+    match https_port {
+        Some(_) => log::info!("redirecting to https from {:?}", listener.local_addr()),
+        None => log::info!("answering acme challenges on {:?}", listener.local_addr()),
+    }
+    let filter = handle.clone();
+
+    let incoming = incoming(listener)?.filter(move |conn| {
+        conn.peer_addr()
+            .map(|peer| current(&filter).accepts(&peer))
+            .unwrap_or(false)
+    });
+
+    let service = hyper::service::make_service_fn(move |_: &tokio::net::TcpStream| {
+        let this_handler = handle.clone();
+        let open = connections.open();
+        Ok::<_, hyper::Error>(hyper::service::service_fn(move |req| {
+            let _ = &open;
+            current(&this_handler)
+                .challenge(&req)
+                .unwrap_or_else(|| match https_port {
+                    Some(port) => handler::redirect_to_https(&req, port),
+                    None => handler::direct_response(hyper::StatusCode::NOT_FOUND),
+                })
+        }))
+    });
 
-    (server, tx)
+    let server = hyper::Server::builder(incoming)
+        .executor(drain)
+        .serve(service);
+
+    Ok(Box::new(
+        server
+            .with_graceful_shutdown(shutdown)
+            .map_err(|e| log::error!("redirect server error {}", e)),
+    ))
 }
 
-fn make_signal_handler(stopper: Sender<()>) -> impl Future<Item = (), Error = ()> {
+fn make_handler(cfg: &Config) -> std::io::Result<handler::Handler> {
+    let mut handle = handler::Handler::new(cfg.root.as_str())?
+        .with_limits(cfg.limits.clone())
+        .with_compression(cfg.compress)
+        .with_headers(&cfg.headers)?;
+
+    if let Some(uploads) = &cfg.uploads {
+        handle = handle.with_uploads(uploads)?;
+    }
+
+    if let Some(basic_auth) = &cfg.basic_auth {
+        if listeners(cfg).iter().any(|l| l.tls.is_none()) && !basic_auth.allow_insecure {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "refusing to enable basic authentication over plain http",
+            ));
+        }
+        handle = handle.with_basic_auth(basic_auth)?;
+    }
+
+    if let Some(bearer_auth) = &cfg.bearer_auth {
+        if cfg.basic_auth.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "basic and bearer authentication can not be combined",
+            ));
+        }
+        handle = handle.with_bearer_auth(bearer_auth)?;
+    }
+
+    if let Some(signed_urls) = &cfg.signed_urls {
+        handle = handle.with_signed_urls(signed_urls)?;
+    }
+
+    if cfg.acme.is_some() && cfg.tls.is_none() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "acme certificates need tls",
+        ));
+    }
+
+    for listener in listeners(cfg).iter().filter(|l| l.unix.is_some()) {
+        if listener.tls.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "tls over unix sockets is not supported",
+            ));
+        }
+        if cfg.access.restricts_addresses() {
+            log::warn!("ip access rules don't apply to connections over unix sockets");
+        }
+    }
+
+    if cfg.redirect_port.is_some() && cfg.tls.is_none() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "redirecting to https needs tls",
+        ));
+    }
+
+    let client_auth = listeners(cfg)
+        .iter()
+        .any(|l| l.tls.as_ref().is_some_and(|tls| tls.client_auth.is_some()));
+    if !client_auth && cfg.access.requires_subjects() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "client certificate subject rules need tls client authentication",
+        ));
+    }
+    handle = handle.with_access(&cfg.access)?;
+
+    Ok(handle)
+}
+
+// shuts the server down on SIGINT or SIGTERM, aborting it on a second one. Finishes with the
+// server.
+pub fn stop_on_signals(handle: Handle) -> impl Future<Item = (), Error = ()> + Send {
     use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
 
     let sigint = Signal::new(SIGINT).flatten_stream();
     let sigterm = Signal::new(SIGTERM).flatten_stream();
     let stream = sigint.select(sigterm);
+    let done = handle.done.clone().map(|_| ()).map_err(|_| ());
 
+    let mut stopping = false;
     stream
-        .into_future()
-        .and_then(|sig| {
-            let (sig, _) = sig;
-            log::info!("got signal {:?}, stopping", sig);
-            stopper.send(()).expect("send stop event");
+        .map_err(|e| log::error!("error catching signal: {}", e))
+        .for_each(move |sig| {
+            if stopping {
+                log::warn!("got signal {:?} again, stopping now", sig);
+                handle.abort();
+            } else {
+                log::info!("got signal {:?}, stopping", sig);
+                handle.shutdown();
+                stopping = true;
+            }
             Ok(())
         })
-        .map_err(|e| {
-            let (e, _) = e;
-            log::error!("error catching signal: {}", e);
+        .select(done)
+        .map(|_| ())
+        .map_err(|_| ())
+}
+
+// reloads the configuration returned by load on SIGHUP, see Handle::reload. An invalid one is
+// logged and the current one kept. Certificates are then only reloaded with the rest, while
+// changed files are still picked up at the tls watch_interval. Finishes with the server.
+pub fn reload_on_sighup<L>(handle: Handle, load: L) -> impl Future<Item = (), Error = ()> + Send
+where
+    L: Fn() -> std::io::Result<Config> + Send + 'static,
+{
+    use tokio_signal::unix::{Signal, SIGHUP};
+
+    for tls in handle.live.lock().unwrap().tls.iter().flatten() {
+        tls.ignore_sighup();
+    }
+    let done = handle.done.clone().map(|_| ()).map_err(|_| ());
+
+    Signal::new(SIGHUP)
+        .flatten_stream()
+        .map_err(|e| log::error!("error catching SIGHUP: {}", e))
+        .for_each(move |_| {
+            log::info!("got SIGHUP, reloading configuration");
+            if let Err(e) = load().and_then(|cfg| handle.reload(cfg)) {
+                log::error!("keeping the current configuration: {}", e);
+            }
+            Ok(())
         })
+        .select(done)
+        .map(|_| ())
+        .map_err(|_| ())
 }
@@ -0,0 +1,298 @@
+extern crate libc;
+
+use super::LimitsConfig;
+use futures::{Async, Poll, Stream};
+use hyper::header::HeaderValue;
+use hyper::{header, HeaderMap, StatusCode};
+use std::path::Path;
+
+// checks that can be done on the request headers alone, before any of the body is read. Hyper only
+// sends a "100 Continue" once the body is polled, so answering with an error here means a client
+// sending "Expect: 100-continue" never transmits its body.
+pub fn check_request(
+    limits: &LimitsConfig,
+    headers: &HeaderMap<HeaderValue>,
+) -> Result<(), StatusCode> {
+    check_expect(headers)?;
+    check_content_length(limits, headers)?;
+    Ok(())
+}
+
+fn check_expect(headers: &HeaderMap<HeaderValue>) -> Result<(), StatusCode> {
+    match headers.get(header::EXPECT) {
+        Some(expect) => match expect.to_str() {
+            Ok(e) if e.trim().eq_ignore_ascii_case("100-continue") => Ok(()),
+            _ => {
+                log::info!("unsupported expectation {:?}", expect);
+                Err(StatusCode::EXPECTATION_FAILED)
+            }
+        },
+        None => Ok(()),
+    }
+}
+
+pub fn content_length(headers: &HeaderMap<HeaderValue>) -> Result<Option<u64>, StatusCode> {
+    match headers.get(header::CONTENT_LENGTH) {
+        Some(cl) => cl
+            .to_str()
+            .ok()
+            .and_then(|cl| cl.parse::<u64>().ok())
+            .map(Some)
+            .ok_or(StatusCode::BAD_REQUEST),
+        None => Ok(None),
+    }
+}
+
+fn check_content_length(
+    limits: &LimitsConfig,
+    headers: &HeaderMap<HeaderValue>,
+) -> Result<(), StatusCode> {
+    let length = content_length(headers)?;
+
+    match (length, limits.max_body_size) {
+        (Some(length), Some(max)) if length > max => {
+            log::info!("request body of {} bytes exceeds limit of {}", length, max);
+            Err(StatusCode::PAYLOAD_TOO_LARGE)
+        }
+        _ => Ok(()),
+    }
+}
+
+// checks whether `incoming` more bytes can be stored in `dir`, both against the configured quota
+// for that directory and the minimum amount of free space to leave on the file system
+pub fn check_storage(limits: &LimitsConfig, dir: &Path, incoming: u64) -> Result<(), StatusCode> {
+    if let Some(quota) = limits.disk_quota {
+        let used = disk_usage(dir).map_err(|e| {
+            log::error!("can not determine disk usage of {:?}: {}", dir, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+        if used.saturating_add(incoming) > quota {
+            log::warn!(
+                "storing {} bytes in {:?} would exceed quota ({} of {} used)",
+                incoming,
+                dir,
+                used,
+                quota
+            );
+            return Err(StatusCode::INSUFFICIENT_STORAGE);
+        }
+    }
+
+    if let Some(min_free) = limits.min_free_space {
+        let free = free_space(dir).map_err(|e| {
+            log::error!("can not determine free space for {:?}: {}", dir, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+        if free < incoming.saturating_add(min_free) {
+            log::warn!(
+                "storing {} bytes in {:?} would leave less than {} bytes free ({} available)",
+                incoming,
+                dir,
+                min_free,
+                free
+            );
+            return Err(StatusCode::INSUFFICIENT_STORAGE);
+        }
+    }
+
+    Ok(())
+}
+
+fn disk_usage(dir: &Path) -> std::io::Result<u64> {
+    let mut total = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        if meta.is_dir() {
+            total += disk_usage(&entry.path())?;
+        } else {
+            total += meta.len();
+        }
+    }
+    Ok(total)
+}
+
+fn free_space(dir: &Path) -> std::io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[derive(Debug)]
+pub enum BodyError<E> {
+    TooLarge,
+    Inner(E),
+}
+
+// enforces the maximum body size while streaming, for requests that did not announce their size
+// through Content-Length (chunked) or that lied about it
+pub struct LimitedBody<S> {
+    inner: S,
+    remaining: Option<u64>,
+}
+
+impl<S> LimitedBody<S> {
+    pub fn new(inner: S, limits: &LimitsConfig) -> LimitedBody<S> {
+        LimitedBody {
+            inner,
+            remaining: limits.max_body_size,
+        }
+    }
+}
+
+impl<S> Stream for LimitedBody<S>
+where
+    S: Stream,
+    S::Item: AsRef<[u8]>,
+{
+    type Item = S::Item;
+    type Error = BodyError<S::Error>;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let chunk = match self.inner.poll().map_err(BodyError::Inner)? {
+            Async::Ready(Some(chunk)) => chunk,
+            other => return Ok(other),
+        };
+
+        if let Some(remaining) = self.remaining {
+            let len = chunk.as_ref().len() as u64;
+            if len > remaining {
+                log::info!("request body exceeds limit while streaming");
+                return Err(BodyError::TooLarge);
+            }
+            self.remaining = Some(remaining - len);
+        }
+
+        Ok(Async::Ready(Some(chunk)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(max_body_size: Option<u64>) -> LimitsConfig {
+        LimitsConfig {
+            max_body_size,
+            ..Default::default()
+        }
+    }
+
+    fn headers(pairs: &[(header::HeaderName, &'static str)]) -> HeaderMap<HeaderValue> {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.insert(name.clone(), HeaderValue::from_static(value));
+        }
+        map
+    }
+
+    #[test]
+    fn content_length_within_limit() {
+        let h = headers(&[(header::CONTENT_LENGTH, "10")]);
+        assert_eq!(Ok(()), check_request(&limits(Some(10)), &h));
+        assert_eq!(Ok(()), check_request(&limits(None), &h));
+    }
+
+    #[test]
+    fn content_length_exceeds_limit() {
+        let h = headers(&[(header::CONTENT_LENGTH, "11")]);
+        assert_eq!(
+            Err(StatusCode::PAYLOAD_TOO_LARGE),
+            check_request(&limits(Some(10)), &h)
+        );
+    }
+
+    #[test]
+    fn invalid_content_length() {
+        let h = headers(&[(header::CONTENT_LENGTH, "eleven")]);
+        assert_eq!(
+            Err(StatusCode::BAD_REQUEST),
+            check_request(&limits(None), &h)
+        );
+    }
+
+    #[test]
+    fn expect_continue() {
+        let h = headers(&[(header::EXPECT, "100-Continue")]);
+        assert_eq!(Ok(()), check_request(&limits(None), &h));
+
+        let h = headers(&[
+            (header::EXPECT, "100-continue"),
+            (header::CONTENT_LENGTH, "11"),
+        ]);
+        assert_eq!(
+            Err(StatusCode::PAYLOAD_TOO_LARGE),
+            check_request(&limits(Some(10)), &h)
+        );
+    }
+
+    #[test]
+    fn unknown_expectation() {
+        let h = headers(&[(header::EXPECT, "something-else")]);
+        assert_eq!(
+            Err(StatusCode::EXPECTATION_FAILED),
+            check_request(&limits(None), &h)
+        );
+    }
+
+    #[test]
+    fn limited_body() {
+        let chunks = vec![vec![0u8; 4], vec![0u8; 4]];
+
+        let body = LimitedBody::new(
+            futures::stream::iter_ok::<_, ()>(chunks.clone()),
+            &limits(Some(8)),
+        );
+        assert_eq!(2, body.wait().filter(|c| c.is_ok()).count());
+
+        let mut body =
+            LimitedBody::new(futures::stream::iter_ok::<_, ()>(chunks), &limits(Some(7))).wait();
+        body.next().unwrap().expect("first chunk fits");
+        match body.next().unwrap() {
+            Err(BodyError::TooLarge) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn storage_quota() {
+        let cargo_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR to be set");
+        let root = std::path::PathBuf::from(cargo_dir)
+            .join("tests")
+            .join("sample_root");
+
+        let used = disk_usage(&root).expect("disk usage");
+        assert!(used > 0);
+
+        let quota = LimitsConfig {
+            disk_quota: Some(used + 10),
+            ..Default::default()
+        };
+        assert_eq!(Ok(()), check_storage(&quota, &root, 10));
+        assert_eq!(
+            Err(StatusCode::INSUFFICIENT_STORAGE),
+            check_storage(&quota, &root, 11)
+        );
+
+        let free = LimitsConfig {
+            min_free_space: Some(u64::MAX / 2),
+            ..Default::default()
+        };
+        assert_eq!(
+            Err(StatusCode::INSUFFICIENT_STORAGE),
+            check_storage(&free, &root, 1)
+        );
+    }
+}
@@ -0,0 +1,320 @@
+extern crate net2;
+
+use crate::{ListenerConfig, UnixSocketConfig};
+use std::io::Result;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+const BACKLOG: i32 = 1024;
+
+// a listening socket, ready to be served
+pub enum Socket {
+    Tcp(TcpListener),
+    // with the path to remove once done, unless someone else owns the socket file
+    Unix(UnixListener, Option<String>),
+}
+
+impl std::fmt::Display for Socket {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Socket::Tcp(listener) => match listener.local_addr() {
+                Ok(address) => write!(f, "{}", address),
+                Err(_) => f.write_str("tcp socket"),
+            },
+            Socket::Unix(listener, _) => {
+                match listener
+                    .local_addr()
+                    .ok()
+                    .and_then(|a| a.as_pathname().map(Path::to_path_buf))
+                {
+                    Some(path) => write!(f, "{}", path.display()),
+                    None => f.write_str("unix socket"),
+                }
+            }
+        }
+    }
+}
+
+// the sockets a listener is configured for
+pub fn open(listener: &ListenerConfig) -> Result<Vec<Socket>> {
+    match &listener.unix {
+        Some(unix) => Ok(vec![Socket::Unix(
+            bind_unix(unix)?,
+            Some(unix.path.clone()),
+        )]),
+        None => Ok(
+            bind_all(&listener.bind, listener.port, listener.local_only)?
+                .into_iter()
+                .map(Socket::Tcp)
+                .collect(),
+        ),
+    }
+}
+
+// binds every configured address, an ip with or without a port, like 10.0.0.5, [::]:8080 or
+// ::1. Without any, all interfaces on both ipv4 and ipv6, or only loopback with local_only.
+// Port 0 picks a free port, the same one for every address.
+pub fn bind_all(bind: &[String], port: u16, local_only: bool) -> Result<Vec<TcpListener>> {
+    if !bind.is_empty() {
+        let mut free = 0;
+        let mut listeners = Vec::new();
+        for b in bind {
+            let mut address = parse(b, port)?;
+            if address.port() == 0 {
+                address.set_port(free);
+            }
+            let listener = self::bind(&address)?;
+            if free == 0 && address.port() == 0 {
+                free = listener.local_addr()?.port();
+            }
+            listeners.push(listener);
+        }
+        return Ok(listeners);
+    }
+
+    if local_only {
+        let listener = self::bind(&(Ipv4Addr::LOCALHOST, port).into())?;
+        let port = listener.local_addr()?.port();
+        let mut listeners = vec![listener];
+        match self::bind(&(Ipv6Addr::LOCALHOST, port).into()) {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => log::debug!("not listening on ipv6 loopback: {}", e),
+        }
+        Ok(listeners)
+    } else {
+        match self::bind(&(Ipv6Addr::UNSPECIFIED, port).into()) {
+            Ok(listener) => Ok(vec![listener]),
+            Err(e) => {
+                log::debug!("no dual stack socket, listening on ipv4 only: {}", e);
+                Ok(vec![self::bind(&(Ipv4Addr::UNSPECIFIED, port).into())?])
+            }
+        }
+    }
+}
+
+// the same interfaces, on another port
+pub fn with_port(bind: &[String], port: u16) -> Result<Vec<String>> {
+    bind.iter()
+        .map(|b| parse(b, port).map(|address| SocketAddr::new(address.ip(), port).to_string()))
+        .collect()
+}
+
+fn parse(bind: &str, port: u16) -> Result<SocketAddr> {
+    if let Ok(address) = bind.parse::<SocketAddr>() {
+        return Ok(address);
+    }
+    bind.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, port))
+        .map_err(|_| {
+            error(format!(
+                "invalid bind address {}, expected an ip address with an optional port",
+                bind
+            ))
+        })
+}
+
+fn bind(address: &SocketAddr) -> Result<TcpListener> {
+    log::debug!("binding {}", address);
+    try_bind(address).map_err(|e| error(format!("error binding to {}: {}", address, e)))
+}
+
+// an unspecified ipv6 address accepts ipv4 connections too, whatever the system default is
+fn try_bind(address: &SocketAddr) -> Result<TcpListener> {
+    let builder = match address {
+        SocketAddr::V4(_) => net2::TcpBuilder::new_v4()?,
+        SocketAddr::V6(v6) => {
+            let builder = net2::TcpBuilder::new_v6()?;
+            builder.only_v6(!v6.ip().is_unspecified())?;
+            builder
+        }
+    };
+    builder.reuse_address(true)?.bind(address)?.listen(BACKLOG)
+}
+
+// a socket file left behind by a server that is gone is replaced, one that is still answering is
+// not, and neither is anything that isn't a socket
+pub fn bind_unix(cfg: &UnixSocketConfig) -> Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let path = Path::new(&cfg.path);
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(error(format!(
+                "{} exists and is not a socket, refusing to replace it",
+                cfg.path
+            )));
+        }
+        match UnixStream::connect(path) {
+            Ok(_) => return Err(error(format!("{} is in use by another server", cfg.path))),
+            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                log::info!("removing stale socket {}", cfg.path);
+                std::fs::remove_file(path)?;
+            }
+            Err(e) => return Err(error(format!("error checking {}: {}", cfg.path, e))),
+        }
+    }
+
+    let listener = match cfg.mode {
+        Some(mode) => bind_private(path, mode),
+        None => UnixListener::bind(path),
+    };
+    listener.map_err(|e| error(format!("error binding to {}: {}", cfg.path, e)))
+}
+
+// binds in a directory only we can enter and moves the socket into place once it has its mode,
+// so it is never reachable with the wider permissions of the umask
+fn bind_private(path: &Path, mode: u32) -> Result<UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let name = path
+        .file_name()
+        .ok_or_else(|| error(format!("{:?} is not a file name", path)))?;
+    let dir = path.with_file_name(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let staged = dir.join("socket");
+    let listener = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&staged);
+    let _ = std::fs::remove_dir(&dir);
+    listener
+}
+
+fn error(err: String) -> std::io::Error {
+    std::io::Error::other(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|v| v.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_addresses() {
+        assert_eq!(
+            "10.0.0.5:8080".parse::<SocketAddr>().unwrap(),
+            parse("10.0.0.5", 8080).unwrap()
+        );
+        assert_eq!(
+            "[::1]:9000".parse::<SocketAddr>().unwrap(),
+            parse("[::1]:9000", 8080).unwrap()
+        );
+        assert_eq!(
+            "[::1]:8080".parse::<SocketAddr>().unwrap(),
+            parse("[::1]", 8080).unwrap()
+        );
+        assert_eq!(
+            "[::]:8080".parse::<SocketAddr>().unwrap(),
+            parse("::", 8080).unwrap()
+        );
+        assert!(parse("localhost:8080", 8080).is_err());
+    }
+
+    #[test]
+    fn moves_to_another_port() {
+        assert_eq!(
+            strings(&["10.0.0.5:80", "[::1]:80"]),
+            with_port(&strings(&["10.0.0.5:8080", "::1"]), 80).unwrap()
+        );
+    }
+
+    fn socket_config(name: &str) -> UnixSocketConfig {
+        let path = std::env::temp_dir().join(format!("httprust-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        UnixSocketConfig {
+            path: path.to_str().unwrap().to_string(),
+            mode: None,
+        }
+    }
+
+    #[test]
+    fn replaces_stale_sockets() {
+        let cfg = socket_config("stale.sock");
+
+        let listener = bind_unix(&cfg).unwrap();
+        assert!(bind_unix(&cfg).is_err());
+
+        drop(listener);
+        assert!(Path::new(&cfg.path).exists());
+        let _listener = bind_unix(&cfg).unwrap();
+        UnixStream::connect(&cfg.path).expect("connect");
+
+        let _ = std::fs::remove_file(&cfg.path);
+    }
+
+    #[test]
+    fn keeps_other_files() {
+        let cfg = socket_config("not-a.sock");
+        std::fs::write(&cfg.path, "something").unwrap();
+
+        assert!(bind_unix(&cfg).is_err());
+        assert_eq!("something", std::fs::read_to_string(&cfg.path).unwrap());
+
+        let _ = std::fs::remove_file(&cfg.path);
+    }
+
+    #[test]
+    fn socket_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let cfg = UnixSocketConfig {
+            mode: Some(0o600),
+            ..socket_config("mode.sock")
+        };
+        let _listener = bind_unix(&cfg).unwrap();
+        let mode = std::fs::metadata(&cfg.path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+
+        // moved into place without leaving the private directory behind
+        assert!(UnixStream::connect(&cfg.path).is_ok());
+        let path = Path::new(&cfg.path);
+        let name = path.file_name().unwrap().to_str().unwrap();
+        let staging = path.with_file_name(format!(".{}.{}", name, std::process::id()));
+        assert!(!staging.exists());
+
+        let _ = std::fs::remove_file(&cfg.path);
+    }
+
+    #[test]
+    fn one_free_port() {
+        let listeners = bind_all(&[], 0, true).unwrap();
+        let ports: Vec<u16> = listeners
+            .iter()
+            .map(|l| l.local_addr().unwrap().port())
+            .collect();
+        assert_ne!(0, ports[0]);
+        assert!(ports.iter().all(|p| *p == ports[0]));
+
+        let listeners = bind_all(&strings(&["127.0.0.1", "127.0.0.2"]), 0, false).unwrap();
+        assert_eq!(
+            listeners[0].local_addr().unwrap().port(),
+            listeners[1].local_addr().unwrap().port()
+        );
+    }
+
+    #[test]
+    fn dual_stack() {
+        let listener = match bind(&"[::]:0".parse().unwrap()) {
+            Ok(listener) => listener,
+            // no ipv6 here
+            Err(_) => return,
+        };
+        let port = listener.local_addr().unwrap().port();
+
+        std::net::TcpStream::connect(("127.0.0.1", port)).expect("ipv4 connection");
+        std::net::TcpStream::connect(("::1", port)).expect("ipv6 connection");
+    }
+}
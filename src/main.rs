@@ -2,17 +2,30 @@ extern crate clap;
 extern crate path_abs;
 extern crate pretty_env_logger;
 
-use httprust;
-
 fn main() {
     pretty_env_logger::init_timed();
 
     let cwd = std::env::current_dir().expect("get cwd");
-    let cwd = cwd.to_str().unwrap();
+    // kept for the arguments, which are applied again on every reload
+    let cwd: &'static str = Box::leak(cwd.to_str().unwrap().into());
 
     let args = clap::App::new("httprust")
         .author("Klaas de Vries")
         .about("Simple http server")
+        .arg(
+            clap::Arg::with_name("config")
+                .short("C")
+                .long("config")
+                .takes_value(true)
+                .validator(validate_file)
+                .help("read the configuration from this toml file, see config.example.toml. Options given on the command line take precedence. Read again on SIGHUP")
+        )
+        .arg(
+            clap::Arg::with_name("check_config")
+                .long("check-config")
+                .takes_value(false)
+                .help("check the configuration, print it as toml and exit")
+        )
         .arg(
             clap::Arg::with_name("root_directory")
                 .short("r")
@@ -28,7 +41,8 @@ fn main() {
                 .long("port")
                 .takes_value(true)
                 .default_value("8080")
-                .help("set port to listen on"),
+                .validator(validate_port)
+                .help("set port to listen on, 0 for any free one"),
         )
         .arg(
             clap::Arg::with_name("local_only")
@@ -37,15 +51,167 @@ fn main() {
                 .takes_value(false)
                 .help("only open for local connections")
         )
+        .arg(
+            clap::Arg::with_name("bind")
+                .short("b")
+                .long("bind")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .conflicts_with("local_only")
+                .validator(validate_bind)
+                .help("ip address to listen on, like 10.0.0.5 or [::]:8080, can be repeated. Without a port --port is used. By default all interfaces, on both ipv4 and ipv6")
+        )
+        .arg(
+            clap::Arg::with_name("unix_socket")
+                .long("unix-socket")
+                .takes_value(true)
+                .conflicts_with_all(&["bind", "local_only"])
+                .help("listen on this unix domain socket instead of a tcp port, replacing a stale one left behind")
+        )
+        .arg(
+            clap::Arg::with_name("unix_socket_mode")
+                .long("unix-socket-mode")
+                .takes_value(true)
+                .requires("unix_socket")
+                .validator(validate_mode)
+                .help("permissions of the unix socket, in octal like 660")
+        )
+        .arg(
+            clap::Arg::with_name("systemd")
+                .long("systemd")
+                .help("run as a systemd service: serve the sockets passed by socket activation, if any, and notify systemd of readiness (Type=notify) and watchdog keepalives")
+        )
         .arg(
             clap::Arg::with_name("certificate_file")
                 .short("c")
                 .long("cert")
                 .takes_value(true)
-                .requires("private_key_file")
                 .validator(validate_file)
-                .help("when provided, this will be a https server. The proviced certifice will be used")
+                .help("when provided, this will be a https server. The proviced certifice will be used, either a pem chain that needs --key, or a PKCS#12 bundle")
+        )
+        .arg(
+            clap::Arg::with_name("tls_self_signed")
+                .long("tls-self-signed")
+                .help("https with a generated certificate, signed by a generated certificate authority that is printed at startup. For development.")
+        )
+        .arg(
+            clap::Arg::with_name("tls_hostname")
+                .long("tls-hostname")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("tls_self_signed")
+                .help("host name or ip address the generated certificate is for, can be repeated. By default localhost, 127.0.0.1 and ::1")
+        )
+        .arg(
+            clap::Arg::with_name("tls_self_signed_dir")
+                .long("tls-self-signed-dir")
+                .takes_value(true)
+                .requires("tls_self_signed")
+                .help("keep the generated certificate authority and certificate here, and reuse them on the next start")
         )
+        .arg(
+            clap::Arg::with_name("acme_domain")
+                .long("acme-domain")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("acme_cache")
+                .help("get a certificate for this domain from an acme certificate authority, and renew it before it expires. Can be repeated")
+        )
+        .arg(
+            clap::Arg::with_name("acme_directory")
+                .long("acme-directory")
+                .takes_value(true)
+                .requires("acme_domain")
+                .help("directory url of the acme certificate authority, let's encrypt by default")
+        )
+        .arg(
+            clap::Arg::with_name("acme_contact")
+                .long("acme-contact")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("acme_domain")
+                .help("contact url for the acme account, like mailto:admin@example.com")
+        )
+        .arg(
+            clap::Arg::with_name("acme_agree_tos")
+                .long("acme-agree-tos")
+                .requires("acme_domain")
+                .help("agree to the terms of service of the acme certificate authority, needed to register an account with it")
+        )
+        .arg(
+            clap::Arg::with_name("acme_cache")
+                .long("acme-cache")
+                .takes_value(true)
+                .requires("acme_domain")
+                .help("directory to keep the acme account key and certificates in, required with --acme-domain")
+        )
+        .arg(
+            clap::Arg::with_name("acme_challenge")
+                .long("acme-challenge")
+                .takes_value(true)
+                .possible_values(&["http-01", "tls-alpn-01"])
+                .requires("acme_domain")
+                .help("how to prove control of the domains, http-01 by default")
+        )
+        .arg(
+            clap::Arg::with_name("acme_http_port")
+                .long("acme-http-port")
+                .takes_value(true)
+                .requires("acme_domain")
+                .validator(validate_port)
+                .help("plain http port to answer http-01 challenges on, 80 by default")
+        )
+        .arg(
+            clap::Arg::with_name("http_port")
+                .long("http-port")
+                .takes_value(true)
+                .requires("tls")
+                .validator(validate_port)
+                .help("also serve plain http on this port, next to https"),
+        )
+        .arg(
+            clap::Arg::with_name("redirect_port")
+                .long("redirect-port")
+                .takes_value(true)
+                .requires("tls")
+                .validator(validate_port)
+                .help("plain http port redirecting every request to https"),
+        )
+        .arg(
+            clap::Arg::with_name("drain_timeout")
+                .long("drain-timeout")
+                .takes_value(true)
+                .default_value("30")
+                .validator(validate_size)
+                .help("when stopping, wait this many seconds for open connections before closing them. A second SIGINT or SIGTERM closes them right away"),
+        )
+        .arg(
+            clap::Arg::with_name("no_compress")
+                .long("no-compress")
+                .help("never gzip responses, even for clients that accept it"),
+        )
+        .arg(
+            clap::Arg::with_name("header")
+                .long("header")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(validate_header)
+                .help("add this header to every response that doesn't set it itself, like \"Cache-Control: no-cache\". Can be given more than once"),
+        )
+        .arg(
+            clap::Arg::with_name("acme_directory_ca")
+                .long("acme-directory-ca")
+                .takes_value(true)
+                .requires("acme_domain")
+                .validator(validate_file)
+                .help("trust the certificate authorities in this file when talking to the acme directory, for testing")
+        )
+        .group(clap::ArgGroup::with_name("tls").args(&["certificate_file", "tls_self_signed", "acme_domain"]))
         .arg(
             clap::Arg::with_name("private_key_file")
                 .short("k")
@@ -53,26 +219,615 @@ fn main() {
                 .takes_value(true)
                 .requires("certificate_file")
                 .validator(validate_file)
-                .help("needed in combination with --cert, this specifies the file containing the private key")
+                .help("needed in combination with a pem --cert, this specifies the file containing the PKCS#1, PKCS#8 or SEC1 private key")
+        )
+        .arg(
+            clap::Arg::with_name("pkcs12_password")
+                .long("pkcs12-password")
+                .env("HTTPRUST_PKCS12_PASSWORD")
+                .takes_value(true)
+                .requires("certificate_file")
+                .help("password for a PKCS#12 --cert bundle")
+        )
+        .arg(
+            clap::Arg::with_name("sni")
+                .long("sni")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("tls")
+                .validator(validate_sni)
+                .help("NAME=CERT[,KEY], serve this certificate to clients asking for server name NAME, --cert remains the default")
+        )
+        .arg(
+            clap::Arg::with_name("tls_watch")
+                .long("tls-watch")
+                .takes_value(true)
+                .requires("tls")
+                .validator(validate_size)
+                .help("check the certificate and key files every this many seconds, and reload them when changed. SIGHUP always reloads them")
+        )
+        .arg(
+            clap::Arg::with_name("tls_min_version")
+                .long("tls-min-version")
+                .takes_value(true)
+                .possible_values(&["1.2", "1.3"])
+                .requires("tls")
+                .help("oldest tls version to accept")
+        )
+        .arg(
+            clap::Arg::with_name("tls_cipher")
+                .long("tls-cipher")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("tls")
+                .help("only use this cipher suite, for example TLS13_AES_256_GCM_SHA384. Can be repeated, in order of preference")
+        )
+        .arg(
+            clap::Arg::with_name("alpn")
+                .long("alpn")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .possible_values(&["h2", "http/1.1"])
+                .requires("tls")
+                .help("advertise this protocol, in order of preference. By default h2 and http/1.1")
+        )
+        .arg(
+            clap::Arg::with_name("tls_tickets")
+                .long("tls-tickets")
+                .requires("tls")
+                .help("allow resuming sessions with session tickets")
+        )
+        .arg(
+            clap::Arg::with_name("tls_session_cache")
+                .long("tls-session-cache")
+                .takes_value(true)
+                .requires("tls")
+                .validator(validate_size)
+                .help("number of sessions to remember for resumption, 0 disables resumption by session id")
+        )
+        .arg(
+            clap::Arg::with_name("tls_key_log")
+                .long("tls-key-log")
+                .requires("tls")
+                .help("append tls secrets to the file named by SSLKEYLOGFILE, to decrypt captures with wireshark. For development only")
+        )
+        .arg(
+            clap::Arg::with_name("client_ca")
+                .long("client-ca")
+                .takes_value(true)
+                .requires("tls")
+                .validator(validate_file)
+                .help("require client certificates signed by one of the certificate authorities in this file")
+        )
+        .arg(
+            clap::Arg::with_name("client_cert_optional")
+                .long("client-cert-optional")
+                .requires("client_ca")
+                .help("also accept clients without a certificate, only access rules can then refuse them")
+        )
+        .arg(
+            clap::Arg::with_name("max_body_size")
+                .long("max-body-size")
+                .takes_value(true)
+                .validator(validate_size)
+                .help("maximum size of a request body in bytes, larger requests are refused with 413")
+        )
+        .arg(
+            clap::Arg::with_name("disk_quota")
+                .long("disk-quota")
+                .takes_value(true)
+                .validator(validate_size)
+                .help("maximum number of bytes that may be stored in a directory receiving uploads")
+        )
+        .arg(
+            clap::Arg::with_name("min_free_space")
+                .long("min-free-space")
+                .takes_value(true)
+                .validator(validate_size)
+                .help("refuse uploads that would leave less than this many bytes free on disk")
+        )
+        .arg(
+            clap::Arg::with_name("tus_endpoint")
+                .long("tus-endpoint")
+                .takes_value(true)
+                .help("accept resumable (tus) uploads at this path, for example /files"),
+        )
+        .arg(
+            clap::Arg::with_name("tus_staging")
+                .long("tus-staging")
+                .takes_value(true)
+                .requires("tus_endpoint")
+                .help("directory, relative to the root, holding uploads in progress"),
+        )
+        .arg(
+            clap::Arg::with_name("htpasswd")
+                .long("htpasswd")
+                .takes_value(true)
+                .validator(validate_file)
+                .help("require http basic authentication for users in this htpasswd file"),
+        )
+        .arg(
+            clap::Arg::with_name("realm")
+                .long("realm")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("htpasswd")
+                .validator(validate_realm)
+                .help("PREFIX=NAME, protect paths under PREFIX with a realm called NAME (default /=httprust)"),
+        )
+        .arg(
+            clap::Arg::with_name("allow_insecure_auth")
+                .long("allow-insecure-auth")
+                .takes_value(false)
+                .requires("htpasswd")
+                .help("allow basic authentication without tls, sending passwords in the clear"),
+        )
+        .arg(
+            clap::Arg::with_name("jwt_key")
+                .long("jwt-key")
+                .takes_value(true)
+                .validator(validate_file)
+                .conflicts_with("htpasswd")
+                .help("require bearer jwts verified with this jwks, public key (RS256, ES256) or secret (HS256) file"),
+        )
+        .arg(
+            clap::Arg::with_name("jwt_audience")
+                .long("jwt-audience")
+                .takes_value(true)
+                .requires("jwt_key")
+                .help("only accept jwts issued for this audience"),
+        )
+        .arg(
+            clap::Arg::with_name("jwt_issuer")
+                .long("jwt-issuer")
+                .takes_value(true)
+                .requires("jwt_key")
+                .help("only accept jwts from this issuer"),
+        )
+        .arg(
+            clap::Arg::with_name("jwt_claim")
+                .long("jwt-claim")
+                .takes_value(true)
+                .default_value("scope")
+                .help("claim holding permissions such as \"read:/ write:/files\""),
+        )
+        .arg(
+            clap::Arg::with_name("api_tokens")
+                .long("api-tokens")
+                .takes_value(true)
+                .validator(validate_file)
+                .conflicts_with("htpasswd")
+                .help("require bearer tokens listed in this file, one \"<token> <permission>...\" per line"),
+        )
+        .arg(
+            clap::Arg::with_name("signing_key")
+                .long("signing-key")
+                .takes_value(true)
+                .validator(validate_file)
+                .help("accept urls signed with the key in this file, see the sign subcommand"),
+        )
+        .arg(
+            clap::Arg::with_name("signed_only")
+                .long("signed-only")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("signing_key")
+                .help("only serve paths under this prefix through signed urls"),
+        )
+        .arg(
+            clap::Arg::with_name("allow")
+                .long("allow")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(validate_cidr)
+                .help("only accept connections from this address range, for example 10.0.0.0/8"),
+        )
+        .arg(
+            clap::Arg::with_name("deny")
+                .long("deny")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(validate_cidr)
+                .help("drop connections from this address range"),
+        )
+        .arg(
+            clap::Arg::with_name("path_allow")
+                .long("path-allow")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(validate_path_cidr)
+                .help("PREFIX=RANGE, only allow this address range to access paths under PREFIX"),
+        )
+        .arg(
+            clap::Arg::with_name("path_deny")
+                .long("path-deny")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(validate_path_cidr)
+                .help("PREFIX=RANGE, refuse this address range access to paths under PREFIX"),
+        )
+        .arg(
+            clap::Arg::with_name("trusted_proxy")
+                .long("trusted-proxy")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(validate_cidr)
+                .help("believe X-Forwarded-For headers from proxies in this address range"),
+        )
+        .arg(
+            clap::Arg::with_name("client_subject")
+                .long("client-subject")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("client_ca")
+                .help("only serve clients with a certificate for this subject, or common name"),
+        )
+        .arg(
+            clap::Arg::with_name("path_subject")
+                .long("path-subject")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("client_ca")
+                .validator(validate_path_subject)
+                .help("PREFIX=SUBJECT, only allow clients with a certificate for this subject, or common name, to access paths under PREFIX"),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("sign")
+                .about("create a temporary download link")
+                .arg(
+                    clap::Arg::with_name("PATH")
+                        .required(true)
+                        .index(1)
+                        .help("path to sign, for example /downloads/report.pdf"),
+                )
+                .arg(
+                    clap::Arg::with_name("signing_key")
+                        .long("signing-key")
+                        .takes_value(true)
+                        .required(true)
+                        .validator(validate_file)
+                        .help("file containing the key the server uses"),
+                )
+                .arg(
+                    clap::Arg::with_name("expires_in")
+                        .long("expires-in")
+                        .takes_value(true)
+                        .default_value("3600")
+                        .validator(validate_size)
+                        .help("number of seconds the link stays valid"),
+                )
+                .arg(
+                    clap::Arg::with_name("base_url")
+                        .long("base-url")
+                        .takes_value(true)
+                        .default_value("")
+                        .help("origin to prefix the link with, for example https://files.example.com"),
+                ),
         )
         .get_matches();
 
-    let cfg = httprust::Config {
-        port: args
-            .value_of("port")
-            .unwrap()
-            .parse::<u16>()
-            .expect("invalid port number"),
-        local_only: args.is_present("local_only"),
-        root: args.value_of("root_directory").unwrap().to_string(),
-        tls: args
-            .value_of("certificate_file")
-            .map(|cf| httprust::TlsConfig {
-                certificate_file: cf.to_string(),
-                private_key_file: args.value_of("private_key_file").unwrap().to_string(),
+    if let Some(sign) = args.subcommand_matches("sign") {
+        let path = httprust::sign_url(
+            sign.value_of("signing_key").unwrap(),
+            sign.value_of("PATH").unwrap(),
+            std::time::Duration::from_secs(
+                parse_size(sign.value_of("expires_in").unwrap())
+                    .unwrap_or_else(|e| exit(httprust::Error::Config(e))),
+            ),
+        )
+        .unwrap_or_else(|e| exit(httprust::Error::Config(e)));
+        println!(
+            "{}{}",
+            sign.value_of("base_url").unwrap().trim_end_matches('/'),
+            path
+        );
+        return;
+    }
+
+    // generated once, a reload keeps serving the same certificate
+    let self_signed = if args.is_present("tls_self_signed") {
+        let default = httprust::SelfSignedConfig::default();
+        let generated = httprust::self_signed(&httprust::SelfSignedConfig {
+            hostnames: args
+                .values_of("tls_hostname")
+                .map(|v| v.map(String::from).collect())
+                .unwrap_or(default.hostnames),
+            cache_dir: args.value_of("tls_self_signed_dir").map(String::from),
+        })
+        .unwrap_or_else(|e| exit(httprust::Error::Config(e)));
+        eprintln!(
+            "serving a self signed certificate, trust {} to accept it",
+            generated.ca_certificate_file
+        );
+        Some(generated)
+    } else {
+        None
+    };
+
+    let mut cfg = match args.value_of("config") {
+        Some(file) => {
+            httprust::Config::from_file(file).unwrap_or_else(|e| exit(httprust::Error::Config(e)))
+        }
+        None => httprust::Config {
+            root: cwd.to_string(),
+            ..Default::default()
+        },
+    };
+    apply_arguments(&mut cfg, &args, self_signed.as_ref())
+        .unwrap_or_else(|e| exit(httprust::Error::Config(e)));
+
+    if args.is_present("check_config") {
+        match httprust::check(&cfg).and_then(|_| cfg.to_toml()) {
+            Ok(toml) => print!("{}", toml),
+            Err(e) => exit(httprust::Error::Config(e)),
+        }
+        return;
+    }
+
+    let result = match args.value_of("config") {
+        Some(file) => {
+            let file = file.to_string();
+            httprust::run_reloading(cfg, move || {
+                let mut cfg = httprust::Config::from_file(&file)?;
+                apply_arguments(&mut cfg, &args, self_signed.as_ref())?;
+                Ok(cfg)
+            })
+        }
+        None => httprust::run(cfg),
+    };
+    if let Err(e) = result {
+        exit(e);
+    }
+}
+
+// overrides the configuration with whatever was given on the command line. The addresses of the
+// main listener, and sections like tls or basic_auth, are replaced as a whole. self_signed is the
+// certificate generated for --tls-self-signed.
+fn apply_arguments(
+    cfg: &mut httprust::Config,
+    args: &clap::ArgMatches,
+    self_signed: Option<&httprust::SelfSigned>,
+) -> std::io::Result<()> {
+    if let Some(port) = given(args, "port") {
+        cfg.port = parse_port(port)?;
+    }
+    if args.is_present("local_only") {
+        cfg.local_only = true;
+        cfg.bind.clear();
+        cfg.unix = None;
+    }
+    if args.is_present("bind") {
+        cfg.bind = values(args, "bind");
+        cfg.local_only = false;
+        cfg.unix = None;
+    }
+    if let Some(path) = args.value_of("unix_socket") {
+        cfg.unix = Some(httprust::UnixSocketConfig {
+            path: path.to_string(),
+            mode: args
+                .value_of("unix_socket_mode")
+                .map(parse_mode)
+                .transpose()?,
+        });
+        cfg.bind.clear();
+        cfg.local_only = false;
+    }
+    if args.is_present("systemd") {
+        cfg.systemd = true;
+    }
+    if let Some(root) = given(args, "root_directory") {
+        cfg.root = root.to_string();
+    }
+    if let Some(port) = args.value_of("http_port") {
+        cfg.listeners.push(httprust::ListenerConfig {
+            port: parse_port(port)?,
+            local_only: cfg.local_only,
+            bind: interfaces(&cfg.bind),
+            unix: None,
+            tls: None,
+        });
+    }
+    if let Some(port) = args.value_of("redirect_port") {
+        cfg.redirect_port = Some(parse_port(port)?);
+    }
+    if let Some(timeout) = given(args, "drain_timeout") {
+        cfg.drain_timeout = Some(std::time::Duration::from_secs(parse_size(timeout)?));
+    }
+    if args.is_present("no_compress") {
+        cfg.compress = false;
+    }
+    for header in values(args, "header") {
+        let (name, value) = parse_header(&header)?;
+        cfg.headers.insert(name, value);
+    }
+
+    if let Some(domains) = args.values_of("acme_domain") {
+        let default = httprust::AcmeConfig::default();
+        cfg.acme = Some(httprust::AcmeConfig {
+            directory_url: args
+                .value_of("acme_directory")
+                .map(String::from)
+                .unwrap_or(default.directory_url),
+            domains: domains.map(String::from).collect(),
+            contact: values(args, "acme_contact"),
+            agree_tos: args.is_present("acme_agree_tos"),
+            // clap requires it along with acme_domain
+            cache_dir: args.value_of("acme_cache").unwrap().to_string(),
+            challenge: args
+                .value_of("acme_challenge")
+                .map(String::from)
+                .unwrap_or(default.challenge),
+            http_port: args
+                .value_of("acme_http_port")
+                .map(parse_port)
+                .transpose()?
+                .unwrap_or(default.http_port),
+            directory_ca_file: args.value_of("acme_directory_ca").map(String::from),
+        });
+    }
+    let certificate = match self_signed {
+        Some(generated) => Some((
+            generated.certificate_file.clone(),
+            Some(generated.private_key_file.clone()),
+        )),
+        None => match &cfg.acme {
+            Some(acme) if args.is_present("acme_domain") => {
+                Some((acme.certificate_file(), Some(acme.private_key_file())))
+            }
+            _ => args.value_of("certificate_file").map(|cf| {
+                (
+                    cf.to_string(),
+                    args.value_of("private_key_file").map(String::from),
+                )
             }),
+        },
     };
-    httprust::run(cfg);
+    if let Some((certificate_file, private_key_file)) = certificate {
+        cfg.tls = Some(httprust::TlsConfig {
+            certificate_file,
+            private_key_file,
+            pkcs12_password: args.value_of("pkcs12_password").map(String::from),
+            server_names: values(args, "sni")
+                .iter()
+                .map(|sni| parse_sni(sni, args.value_of("pkcs12_password")))
+                .collect::<std::io::Result<_>>()?,
+            watch_interval: args
+                .value_of("tls_watch")
+                .map(|s| parse_size(s).map(std::time::Duration::from_secs))
+                .transpose()?,
+            min_version: args.value_of("tls_min_version").map(String::from),
+            cipher_suites: values(args, "tls_cipher"),
+            alpn_protocols: args
+                .values_of("alpn")
+                .map(|v| v.map(String::from).collect()),
+            session_tickets: args.is_present("tls_tickets"),
+            session_cache_size: args
+                .value_of("tls_session_cache")
+                .map(|s| parse_size(s).map(|size| size as usize))
+                .transpose()?,
+            key_log: args.is_present("tls_key_log"),
+            client_auth: args
+                .value_of("client_ca")
+                .map(|ca| httprust::ClientAuthConfig {
+                    ca_file: ca.to_string(),
+                    optional: args.is_present("client_cert_optional"),
+                }),
+        });
+    }
+    // a configuration file can leave the tls files to acme
+    if let (Some(acme), None) = (&cfg.acme, &cfg.tls) {
+        cfg.tls = Some(httprust::TlsConfig {
+            certificate_file: acme.certificate_file(),
+            private_key_file: Some(acme.private_key_file()),
+            ..Default::default()
+        });
+    }
+
+    if let Some(size) = args.value_of("max_body_size") {
+        cfg.limits.max_body_size = Some(parse_size(size)?);
+    }
+    if let Some(size) = args.value_of("disk_quota") {
+        cfg.limits.disk_quota = Some(parse_size(size)?);
+    }
+    if let Some(size) = args.value_of("min_free_space") {
+        cfg.limits.min_free_space = Some(parse_size(size)?);
+    }
+    if let Some(endpoint) = args.value_of("tus_endpoint") {
+        let default = httprust::UploadConfig::default();
+        cfg.uploads = Some(httprust::UploadConfig {
+            endpoint: endpoint.to_string(),
+            staging_dir: args
+                .value_of("tus_staging")
+                .map(String::from)
+                .unwrap_or(default.staging_dir),
+        });
+    }
+    if let Some(htpasswd) = args.value_of("htpasswd") {
+        cfg.basic_auth = Some(httprust::BasicAuthConfig {
+            htpasswd_file: htpasswd.to_string(),
+            realms: args
+                .values_of("realm")
+                .map(|realms| realms.map(parse_realm).collect::<std::io::Result<_>>())
+                .transpose()?
+                .unwrap_or_else(|| {
+                    vec![httprust::Realm {
+                        prefix: String::from("/"),
+                        name: String::from("httprust"),
+                    }]
+                }),
+            allow_insecure: args.is_present("allow_insecure_auth"),
+        });
+    }
+    if args.is_present("jwt_key") || args.is_present("api_tokens") {
+        cfg.bearer_auth = Some(httprust::BearerAuthConfig {
+            jwt_key_file: args.value_of("jwt_key").map(String::from),
+            audience: args.value_of("jwt_audience").map(String::from),
+            issuer: args.value_of("jwt_issuer").map(String::from),
+            permissions_claim: args.value_of("jwt_claim").unwrap().to_string(),
+            tokens_file: args.value_of("api_tokens").map(String::from),
+        });
+    }
+    if let Some(key) = args.value_of("signing_key") {
+        cfg.signed_urls = Some(httprust::SigningConfig {
+            key_file: key.to_string(),
+            required_prefixes: values(args, "signed_only"),
+        });
+    }
+
+    if args.is_present("allow") {
+        cfg.access.global.allow = values(args, "allow");
+    }
+    if args.is_present("deny") {
+        cfg.access.global.deny = values(args, "deny");
+    }
+    if args.is_present("client_subject") {
+        cfg.access.global.subjects = values(args, "client_subject");
+    }
+    if ["path_allow", "path_deny", "path_subject"]
+        .iter()
+        .any(|name| args.is_present(name))
+    {
+        cfg.access.paths = path_access(args)?;
+    }
+    if args.is_present("trusted_proxy") {
+        cfg.access.trusted_proxies = values(args, "trusted_proxy");
+    }
+    Ok(())
+}
+
+// the value of an option given on the command line, ignoring its default
+fn given<'a>(args: &'a clap::ArgMatches, name: &str) -> Option<&'a str> {
+    if args.occurrences_of(name) > 0 {
+        args.value_of(name)
+    } else {
+        None
+    }
+}
+
+// with an exit code from sysexits.h for what kept the server from starting
+fn exit(e: httprust::Error) -> ! {
+    eprintln!("error: {}", e);
+    std::process::exit(match e {
+        httprust::Error::Config(_) => 78,  // EX_CONFIG
+        httprust::Error::Listen(_) => 69,  // EX_UNAVAILABLE
+        httprust::Error::Runtime(_) => 71, // EX_OSERR
+    })
+}
+
+fn error(err: String) -> std::io::Error {
+    std::io::Error::other(err)
 }
 
 fn validate_directory(d: String) -> Result<(), String> {
@@ -88,3 +843,191 @@ fn validate_file(f: String) -> Result<(), String> {
         Err(e) => Err(format!("{}", e)),
     }
 }
+
+fn validate_size(s: String) -> Result<(), String> {
+    match s.parse::<u64>() {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
+fn validate_port(s: String) -> Result<(), String> {
+    match s.parse::<u16>() {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
+fn parse_port(s: &str) -> std::io::Result<u16> {
+    s.parse::<u16>()
+        .map_err(|e| error(format!("invalid port number {}: {}", s, e)))
+}
+
+fn parse_size(s: &str) -> std::io::Result<u64> {
+    s.parse::<u64>()
+        .map_err(|e| error(format!("invalid size {}: {}", s, e)))
+}
+
+fn validate_bind(s: String) -> Result<(), String> {
+    let ip = s.trim_start_matches('[').trim_end_matches(']');
+    if s.parse::<std::net::SocketAddr>().is_ok() || ip.parse::<std::net::IpAddr>().is_ok() {
+        Ok(())
+    } else {
+        Err(String::from(
+            "expected an ip address with an optional port, like 10.0.0.5 or [::]:8080",
+        ))
+    }
+}
+
+fn validate_mode(s: String) -> Result<(), String> {
+    match u32::from_str_radix(&s, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(()),
+        _ => Err(String::from("expected octal permissions, like 660")),
+    }
+}
+
+fn parse_mode(s: &str) -> std::io::Result<u32> {
+    u32::from_str_radix(s, 8).map_err(|e| error(format!("invalid mode {}: {}", s, e)))
+}
+
+fn validate_header(s: String) -> Result<(), String> {
+    match s.split_once(':') {
+        Some((name, _)) if !name.trim().is_empty() => Ok(()),
+        _ => Err(String::from(
+            "expected NAME: VALUE, for example \"Cache-Control: no-cache\"",
+        )),
+    }
+}
+
+fn parse_header(s: &str) -> std::io::Result<(String, String)> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| error(format!("header {} needs a value", s)))?;
+    Ok((name.trim().to_string(), value.trim().to_string()))
+}
+
+fn validate_realm(s: String) -> Result<(), String> {
+    match s.find('=') {
+        Some(i) if s.starts_with('/') && i + 1 < s.len() => Ok(()),
+        _ => Err(String::from(
+            "expected PREFIX=NAME, for example /private=staff",
+        )),
+    }
+}
+
+fn parse_realm(s: &str) -> std::io::Result<httprust::Realm> {
+    let (prefix, name) = s
+        .split_once('=')
+        .ok_or_else(|| error(format!("realm {} needs a name", s)))?;
+    Ok(httprust::Realm {
+        prefix: prefix.to_string(),
+        name: name.to_string(),
+    })
+}
+
+fn validate_sni(s: String) -> Result<(), String> {
+    match s.split_once('=') {
+        Some((name, files)) if !name.is_empty() => {
+            for file in files.split(',') {
+                validate_file(file.to_string())?;
+            }
+            Ok(())
+        }
+        _ => Err(String::from(
+            "expected NAME=CERT[,KEY], for example example.com=example.pem,example-key.pem",
+        )),
+    }
+}
+
+fn parse_sni(
+    s: &str,
+    pkcs12_password: Option<&str>,
+) -> std::io::Result<httprust::ServerCertificate> {
+    let (name, files) = s
+        .split_once('=')
+        .ok_or_else(|| error(format!("server name {} needs a certificate file", s)))?;
+    let (certificate_file, private_key_file) = match files.split_once(',') {
+        Some((certificate, key)) => (certificate, Some(key.to_string())),
+        None => (files, None),
+    };
+    Ok(httprust::ServerCertificate {
+        server_name: name.to_string(),
+        certificate_file: certificate_file.to_string(),
+        private_key_file,
+        pkcs12_password: pkcs12_password.map(String::from),
+    })
+}
+
+fn values(args: &clap::ArgMatches, name: &str) -> Vec<String> {
+    args.values_of(name)
+        .map(|v| v.map(String::from).collect())
+        .unwrap_or_default()
+}
+
+// the bind addresses without their ports
+fn interfaces(bind: &[String]) -> Vec<String> {
+    bind.iter()
+        .map(|b| match b.parse::<std::net::SocketAddr>() {
+            Ok(address) => address.ip().to_string(),
+            Err(_) => b.clone(),
+        })
+        .collect()
+}
+
+// collects --path-allow, --path-deny and --path-subject into one set of rules per prefix
+fn path_access(args: &clap::ArgMatches) -> std::io::Result<Vec<httprust::PathAccess>> {
+    let mut paths: Vec<httprust::PathAccess> = vec![];
+
+    for name in &["path_allow", "path_deny", "path_subject"] {
+        for value in values(args, name) {
+            let (prefix, rule) = value
+                .split_once('=')
+                .ok_or_else(|| error(format!("expected PREFIX=RULE, got {}", value)))?;
+            let index = match paths.iter().position(|p| p.prefix == prefix) {
+                Some(i) => i,
+                None => {
+                    paths.push(httprust::PathAccess {
+                        prefix: prefix.to_string(),
+                        rules: httprust::AccessRules::default(),
+                    });
+                    paths.len() - 1
+                }
+            };
+
+            let rules = &mut paths[index].rules;
+            match *name {
+                "path_allow" => rules.allow.push(rule.to_string()),
+                "path_deny" => rules.deny.push(rule.to_string()),
+                _ => rules.subjects.push(rule.to_string()),
+            }
+        }
+    }
+
+    Ok(paths)
+}
+
+fn validate_cidr(s: String) -> Result<(), String> {
+    let ip = s.split('/').next().unwrap();
+    match ip.parse::<std::net::IpAddr>() {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("{}", e)),
+    }
+}
+
+fn validate_path_cidr(s: String) -> Result<(), String> {
+    match s.split_once('=') {
+        Some((prefix, range)) if prefix.starts_with('/') => validate_cidr(range.to_string()),
+        _ => Err(String::from(
+            "expected PREFIX=RANGE, for example /internal=10.0.0.0/8",
+        )),
+    }
+}
+
+fn validate_path_subject(s: String) -> Result<(), String> {
+    match s.split_once('=') {
+        Some((prefix, subject)) if prefix.starts_with('/') && !subject.is_empty() => Ok(()),
+        _ => Err(String::from(
+            "expected PREFIX=SUBJECT, for example /admin=operator",
+        )),
+    }
+}
@@ -13,13 +13,10 @@ pub fn is_compressable(m: &Mime) -> bool {
         return true;
     }
 
-    match m.subtype() {
-        mime::JAVASCRIPT => true,
-        mime::JSON => true,
-        mime::TEXT => true,
-        mime::XML => true,
-        _ => false,
-    }
+    matches!(
+        m.subtype(),
+        mime::JAVASCRIPT | mime::JSON | mime::TEXT | mime::XML
+    )
 }
 
 #[cfg(test)]
@@ -39,7 +36,7 @@ mod tests {
 
         for (filename, expect) in &cases {
             let actual = super::sniff_mime(OsStr::new(filename));
-            assert_eq!(*expect, actual.map(|v| v.clone()));
+            assert_eq!(*expect, actual);
         }
     }
 
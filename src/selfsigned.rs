@@ -0,0 +1,297 @@
+extern crate x509_parser;
+
+use crate::keys::{
+    create_private_dir, der_write, load_identity, pem_blocks, pem_encode, write_file,
+};
+use crate::x509;
+use crate::SelfSigned;
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair};
+use std::io::Result;
+use std::path::Path;
+
+// development certificates: a local certificate authority and a server certificate for the
+// configured host names, signed by it. Trusting the authority once makes browsers and curl accept
+// the server.
+
+const CA_VALID_DAYS: u64 = 3650;
+const LEAF_VALID_DAYS: u64 = 365;
+// a cached certificate closer than this to expiry is replaced
+const RENEW_DAYS: u64 = 30;
+
+const CA_NAME: &str = "httprust development CA";
+
+const CA_CERTIFICATE: &str = "ca.pem";
+const CA_KEY: &str = "ca-key.pem";
+const CERTIFICATE: &str = "cert.pem";
+const KEY: &str = "key.pem";
+
+struct Authority {
+    key: EcdsaKeyPair,
+    name: Vec<u8>,
+}
+
+// writes the authority and server certificate to dir. With reuse, what is already there is kept
+// as long as it is valid, and the server certificate still covers the host names.
+pub fn generate(hostnames: &[String], dir: &Path, reuse: bool) -> Result<SelfSigned> {
+    if hostnames.is_empty() {
+        return Err(error(String::from(
+            "a self signed certificate needs at least one host name",
+        )));
+    }
+    create_private_dir(dir)?;
+
+    let rng = SystemRandom::new();
+    let now = x509::now();
+
+    let cached = if reuse {
+        load_authority(dir, now)
+    } else {
+        None
+    };
+    let fresh = cached.is_none();
+    let ca = match cached {
+        Some(ca) => {
+            log::info!("reusing certificate authority in {}", dir.display());
+            ca
+        }
+        None => {
+            log::info!("generating certificate authority in {}", dir.display());
+            new_authority(dir, &rng, now)?
+        }
+    };
+
+    if fresh || !leaf_is_current(dir, hostnames, &ca, now) {
+        log::info!("issuing certificate for {}", hostnames.join(", "));
+        new_leaf(dir, hostnames, &ca, &rng, now)?;
+    }
+
+    Ok(SelfSigned {
+        ca_certificate_file: path(dir, CA_CERTIFICATE),
+        certificate_file: path(dir, CERTIFICATE),
+        private_key_file: path(dir, KEY),
+    })
+}
+
+// whether cert was issued by one of our development authorities
+pub fn is_generated(cert: &x509_parser::certificate::X509Certificate) -> bool {
+    cert.issuer().as_raw() == x509::name(CA_NAME).as_slice()
+}
+
+fn new_authority(dir: &Path, rng: &SystemRandom, now: u64) -> Result<Authority> {
+    let pkcs8 = x509::generate_key(rng)?;
+    let key = x509::key_pair(&pkcs8)?;
+    let name = x509::name(CA_NAME);
+
+    let extensions = vec![
+        x509::extension(
+            x509::BASIC_CONSTRAINTS_OID,
+            true,
+            &der_write(0x30, &[0x01, 0x01, 0xff]),
+        ),
+        // keyCertSign and cRLSign
+        x509::extension(x509::KEY_USAGE_OID, true, &[0x03, 0x02, 0x01, 0x06]),
+        x509::extension(
+            x509::SUBJECT_KEY_ID_OID,
+            false,
+            &der_write(0x04, &x509::key_id(&key)),
+        ),
+    ];
+    let cert = x509::certificate(
+        &key,
+        rng,
+        &name,
+        &name,
+        key.public_key().as_ref(),
+        now,
+        CA_VALID_DAYS,
+        &extensions,
+    )?;
+
+    write_file(&dir.join(CA_KEY), &pem_encode("PRIVATE KEY", &pkcs8), true)?;
+    write_file(
+        &dir.join(CA_CERTIFICATE),
+        &pem_encode("CERTIFICATE", &cert),
+        false,
+    )?;
+    Ok(Authority { key, name })
+}
+
+fn new_leaf(
+    dir: &Path,
+    hostnames: &[String],
+    ca: &Authority,
+    rng: &SystemRandom,
+    now: u64,
+) -> Result<()> {
+    let pkcs8 = x509::generate_key(rng)?;
+    let key = x509::key_pair(&pkcs8)?;
+
+    let mut extensions = x509::server_extensions(hostnames);
+    extensions.push(x509::extension(
+        x509::SUBJECT_KEY_ID_OID,
+        false,
+        &der_write(0x04, &x509::key_id(&key)),
+    ));
+    extensions.push(x509::extension(
+        x509::AUTHORITY_KEY_ID_OID,
+        false,
+        &der_write(0x30, &der_write(0x80, &x509::key_id(&ca.key))),
+    ));
+    let cert = x509::certificate(
+        &ca.key,
+        rng,
+        &ca.name,
+        &x509::name(&hostnames[0]),
+        key.public_key().as_ref(),
+        now,
+        LEAF_VALID_DAYS,
+        &extensions,
+    )?;
+
+    write_file(&dir.join(KEY), &pem_encode("PRIVATE KEY", &pkcs8), true)?;
+    write_file(
+        &dir.join(CERTIFICATE),
+        &pem_encode("CERTIFICATE", &cert),
+        false,
+    )
+}
+
+// the cached authority, if both files are there, belong together and have years left
+fn load_authority(dir: &Path, now: u64) -> Option<Authority> {
+    let key_file = path(dir, CA_KEY);
+    let content = std::fs::read(&key_file).ok()?;
+    let blocks = pem_blocks(&key_file, &content).ok()?;
+    let key = x509::key_pair(&blocks.first()?.der).ok()?;
+
+    let cert_file = path(dir, CA_CERTIFICATE);
+    let content = std::fs::read(&cert_file).ok()?;
+    let blocks = pem_blocks(&cert_file, &content).ok()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(&blocks.first()?.der).ok()?;
+
+    if cert.public_key().subject_public_key.data.as_ref() != key.public_key().as_ref() {
+        log::warn!("{} does not belong to {}", cert_file, key_file);
+        return None;
+    }
+    if x509::expires_within(&cert, now, RENEW_DAYS) {
+        log::info!("{} is about to expire", cert_file);
+        return None;
+    }
+
+    Some(Authority {
+        name: cert.subject().as_raw().to_vec(),
+        key,
+    })
+}
+
+// whether the cached server certificate was issued by ca, for exactly these host names, and
+// isn't about to expire
+fn leaf_is_current(dir: &Path, hostnames: &[String], ca: &Authority, now: u64) -> bool {
+    let cert_file = path(dir, CERTIFICATE);
+    let identity = match load_identity(&cert_file, Some(&path(dir, KEY)), None) {
+        Ok(identity) => identity,
+        Err(e) => {
+            log::debug!("not reusing {}: {}", cert_file, e);
+            return false;
+        }
+    };
+    let cert = match x509_parser::parse_x509_certificate(&identity.0[0].0) {
+        Ok((_, cert)) => cert,
+        Err(_) => return false,
+    };
+
+    cert.issuer().as_raw() == ca.name.as_slice()
+        && x509::hostnames(&cert) == Some(x509::normalize(hostnames))
+        && !x509::expires_within(&cert, now, RENEW_DAYS)
+}
+
+fn path(dir: &Path, name: &str) -> String {
+    dir.join(name).to_string_lossy().into_owned()
+}
+
+fn error(err: String) -> std::io::Error {
+    std::io::Error::other(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("httprust-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn hosts(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    fn der(file: &str) -> Vec<u8> {
+        let content = std::fs::read(file).unwrap();
+        pem_blocks(file, &content).unwrap().remove(0).der
+    }
+
+    #[test]
+    fn certificate_is_trusted_through_the_authority() {
+        let dir = temp_dir("self-signed");
+        let generated = generate(&hosts(&["localhost", "127.0.0.1"]), &dir, false).unwrap();
+
+        let ca = der(&generated.ca_certificate_file);
+        let leaf = der(&generated.certificate_file);
+        load_identity(
+            &generated.certificate_file,
+            Some(&generated.private_key_file),
+            None,
+        )
+        .unwrap();
+
+        let anchors =
+            [
+                webpki::trust_anchor_util::cert_der_as_trust_anchor(untrusted::Input::from(&ca))
+                    .unwrap(),
+            ];
+        let cert = webpki::EndEntityCert::from(untrusted::Input::from(&leaf)).unwrap();
+        cert.verify_is_valid_tls_server_cert(
+            &[&webpki::ECDSA_P256_SHA256],
+            &webpki::TLSServerTrustAnchors(&anchors),
+            &[],
+            webpki::Time::try_from(std::time::SystemTime::now()).unwrap(),
+        )
+        .unwrap();
+
+        let localhost = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        assert!(cert.verify_is_valid_for_dns_name(localhost).is_ok());
+        let other = webpki::DNSNameRef::try_from_ascii_str("example.com").unwrap();
+        assert!(cert.verify_is_valid_for_dns_name(other).is_err());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn cached_files_are_reused() {
+        let dir = temp_dir("self-signed-cache");
+        let first = generate(&hosts(&["localhost"]), &dir, true).unwrap();
+        let ca = der(&first.ca_certificate_file);
+        let leaf = der(&first.certificate_file);
+
+        generate(&hosts(&["localhost"]), &dir, true).unwrap();
+        assert_eq!(ca, der(&first.ca_certificate_file));
+        assert_eq!(leaf, der(&first.certificate_file));
+
+        // other host names need a new certificate, from the same authority
+        generate(&hosts(&["localhost", "::1"]), &dir, true).unwrap();
+        assert_eq!(ca, der(&first.ca_certificate_file));
+        assert_ne!(leaf, der(&first.certificate_file));
+
+        generate(&hosts(&["localhost", "::1"]), &dir, false).unwrap();
+        assert_ne!(ca, der(&first.ca_certificate_file));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn needs_a_host_name() {
+        assert!(generate(&[], &temp_dir("self-signed-empty"), false).is_err());
+    }
+}
@@ -0,0 +1,254 @@
+extern crate ring;
+
+use crate::auth::in_prefix;
+use crate::SigningConfig;
+use hyper::{Method, StatusCode};
+use ring::{digest, hmac};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// temporary download links: "<path>?expires=<unix time>&sig=<hex hmac-sha256 of path and expiry>".
+// A valid signature grants read access to exactly that path until it expires, regardless of any
+// other authentication.
+pub struct SignedUrls {
+    key: hmac::SigningKey,
+    required: Vec<String>,
+}
+
+impl SignedUrls {
+    pub fn new(cfg: &SigningConfig) -> std::io::Result<SignedUrls> {
+        let key = load_key(&cfg.key_file)?;
+        log::info!(
+            "accepting signed urls, required for {:?}",
+            cfg.required_prefixes
+        );
+
+        Ok(SignedUrls {
+            key: hmac::SigningKey::new(&digest::SHA256, &key),
+            required: cfg.required_prefixes.clone(),
+        })
+    }
+
+    // Ok(true) if the request carries a valid signature, Ok(false) if it carries none and does not
+    // need one
+    pub fn check(
+        &self,
+        path: &str,
+        method: &Method,
+        query: Option<&str>,
+    ) -> Result<bool, StatusCode> {
+        let (expires, sig) = parse_query(query.unwrap_or(""));
+
+        match (expires, sig) {
+            (None, None) => {
+                if self.required.iter().any(|p| in_prefix(path, p)) {
+                    log::info!("unsigned request for {}, which requires a signature", path);
+                    Err(StatusCode::FORBIDDEN)
+                } else {
+                    Ok(false)
+                }
+            }
+            (Some(expires), Some(sig)) => {
+                if *method != Method::GET && *method != Method::HEAD {
+                    log::info!("signed urls only allow reading, not {}", method);
+                    return Err(StatusCode::FORBIDDEN);
+                }
+                self.verify(path, expires, sig, now()).map(|_| true)
+            }
+            _ => {
+                log::info!("incomplete signature for {}", path);
+                Err(StatusCode::FORBIDDEN)
+            }
+        }
+    }
+
+    fn verify(&self, path: &str, expires: &str, sig: &str, now: u64) -> Result<(), StatusCode> {
+        let sig = from_hex(sig).ok_or(StatusCode::FORBIDDEN)?;
+        hmac::verify_with_own_key(&self.key, message(path, expires).as_bytes(), &sig).map_err(
+            |_| {
+                log::warn!("invalid signature for {}", path);
+                StatusCode::FORBIDDEN
+            },
+        )?;
+
+        // only trust the expiry after the signature proved it was not tampered with
+        let expires = expires.parse::<u64>().map_err(|_| StatusCode::FORBIDDEN)?;
+        if expires < now {
+            log::info!("signed url for {} expired at {}", path, expires);
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        log::debug!("valid signature for {} until {}", path, expires);
+        Ok(())
+    }
+}
+
+// returns the path with the query string that makes it valid until `expires`
+pub fn sign(key: &[u8], path: &str, expires: u64) -> String {
+    let path = crate::handler::normalize_path(path);
+    let key = hmac::SigningKey::new(&digest::SHA256, key);
+    let sig = hmac::sign(&key, message(&path, &expires.to_string()).as_bytes());
+    let sig: String = sig.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+
+    format!("{}?expires={}&sig={}", path, expires, sig)
+}
+
+pub fn load_key(filename: &str) -> std::io::Result<Vec<u8>> {
+    let key = std::fs::read(filename).map_err(|e| {
+        std::io::Error::new(e.kind(), format!("failed to read {}: {}", filename, e))
+    })?;
+
+    if key.len() < 16 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("signing key in {} is too short", filename),
+        ));
+    }
+    Ok(key)
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn message(path: &str, expires: &str) -> String {
+    format!("{}\n{}", path, expires)
+}
+
+fn parse_query(query: &str) -> (Option<&str>, Option<&str>) {
+    let mut expires = None;
+    let mut sig = None;
+
+    for pair in query.split('&') {
+        match pair.split_once('=') {
+            Some(("expires", v)) => expires = Some(v),
+            Some(("sig", v)) => sig = Some(v),
+            _ => {}
+        }
+    }
+
+    (expires, sig)
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn make_signed(required: Vec<&str>) -> SignedUrls {
+        SignedUrls {
+            key: hmac::SigningKey::new(&digest::SHA256, KEY),
+            required: required.into_iter().map(String::from).collect(),
+        }
+    }
+
+    fn split(url: &str) -> (&str, &str) {
+        url.split_once('?').unwrap()
+    }
+
+    #[test]
+    fn valid_signature() {
+        let signed = make_signed(vec![]);
+        let url = sign(KEY, "/docs/a.pdf", now() + 60);
+        let (path, query) = split(&url);
+
+        assert_eq!("/docs/a.pdf", path);
+        assert_eq!(Ok(true), signed.check(path, &Method::GET, Some(query)));
+        assert_eq!(Ok(true), signed.check(path, &Method::HEAD, Some(query)));
+        assert_eq!(
+            Err(StatusCode::FORBIDDEN),
+            signed.check(path, &Method::DELETE, Some(query))
+        );
+    }
+
+    #[test]
+    fn signature_is_bound_to_path() {
+        let signed = make_signed(vec![]);
+        let url = sign(KEY, "/docs/a.pdf", now() + 60);
+        let (_, query) = split(&url);
+
+        assert_eq!(
+            Err(StatusCode::FORBIDDEN),
+            signed.check("/docs/b.pdf", &Method::GET, Some(query))
+        );
+    }
+
+    #[test]
+    fn tampered_expiry() {
+        let signed = make_signed(vec![]);
+        let expires = now() + 60;
+        let url = sign(KEY, "/a", expires);
+        let (path, query) = split(&url);
+        let query = query.replace(&expires.to_string(), &(expires + 3600).to_string());
+
+        assert_eq!(
+            Err(StatusCode::FORBIDDEN),
+            signed.check(path, &Method::GET, Some(&query))
+        );
+    }
+
+    #[test]
+    fn expired() {
+        let signed = make_signed(vec![]);
+        let url = sign(KEY, "/a", 1000);
+        let (path, query) = split(&url);
+        let sig = query.split_once("sig=").unwrap().1;
+
+        assert_eq!(Ok(()), signed.verify(path, "1000", sig, 999));
+        assert_eq!(
+            Err(StatusCode::FORBIDDEN),
+            signed.verify(path, "1000", sig, 1001)
+        );
+    }
+
+    #[test]
+    fn wrong_key() {
+        let signed = make_signed(vec![]);
+        let url = sign(b"some other key of sufficient len", "/a", now() + 60);
+        let (path, query) = split(&url);
+
+        assert_eq!(
+            Err(StatusCode::FORBIDDEN),
+            signed.check(path, &Method::GET, Some(query))
+        );
+    }
+
+    #[test]
+    fn required_prefixes() {
+        let signed = make_signed(vec!["/private"]);
+
+        assert_eq!(Ok(false), signed.check("/public/a", &Method::GET, None));
+        assert_eq!(
+            Err(StatusCode::FORBIDDEN),
+            signed.check("/private/a", &Method::GET, None)
+        );
+        assert_eq!(
+            Err(StatusCode::FORBIDDEN),
+            signed.check("/private/a", &Method::GET, Some("expires=12"))
+        );
+
+        let url = sign(KEY, "/private/a", now() + 60);
+        let (path, query) = split(&url);
+        assert_eq!(Ok(true), signed.check(path, &Method::GET, Some(query)));
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(Some(vec![0x01, 0xab, 0xff]), from_hex("01abff"));
+        assert_eq!(None, from_hex("01a"));
+        assert_eq!(None, from_hex("zz"));
+    }
+}
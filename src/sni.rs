@@ -0,0 +1,157 @@
+extern crate rustls;
+extern crate webpki;
+
+use crate::keys;
+use crate::ServerCertificate;
+use rustls::sign::CertifiedKey;
+use std::collections::HashMap;
+use std::io::Result;
+use std::sync::Arc;
+
+// picks the certificate by the server name the client asked for. Clients that don't send a name,
+// or one we have no certificate for, get the default.
+pub struct SniResolver {
+    default: CertifiedKey,
+    by_name: HashMap<String, CertifiedKey>,
+}
+
+impl SniResolver {
+    pub fn new(default: keys::Identity, names: &[ServerCertificate]) -> Result<SniResolver> {
+        let mut by_name = HashMap::new();
+
+        for name in names {
+            let identity = keys::load_identity(
+                name.certificate_file.as_str(),
+                name.private_key_file.as_deref(),
+                name.pkcs12_password.as_deref(),
+            )?;
+            let key = certified_key(identity)?;
+
+            let dns_name = webpki::DNSNameRef::try_from_ascii_str(&name.server_name)
+                .map_err(|_| error(format!("invalid server name {}", name.server_name)))?;
+            key.cross_check_end_entity_cert(Some(dns_name))
+                .map_err(|e| {
+                    error(format!(
+                        "certificate {} is not valid for {}: {:?}",
+                        name.certificate_file, name.server_name, e
+                    ))
+                })?;
+
+            log::info!(
+                "serving {} for server name {}",
+                name.certificate_file,
+                name.server_name
+            );
+            by_name.insert(name.server_name.to_ascii_lowercase(), key);
+        }
+
+        Ok(SniResolver {
+            default: certified_key(default)?,
+            by_name,
+        })
+    }
+
+    fn lookup(&self, server_name: Option<&str>) -> &CertifiedKey {
+        server_name
+            .and_then(|name| self.by_name.get(&name.to_ascii_lowercase()))
+            .unwrap_or(&self.default)
+    }
+}
+
+impl rustls::ResolvesServerCert for SniResolver {
+    fn resolve(
+        &self,
+        server_name: Option<webpki::DNSNameRef>,
+        _sigschemes: &[rustls::SignatureScheme],
+    ) -> Option<CertifiedKey> {
+        let server_name: Option<&str> = server_name.map(|n| n.into());
+        log::debug!("resolving certificate for {:?}", server_name);
+        Some(self.lookup(server_name).clone())
+    }
+}
+
+fn certified_key((certs, key): keys::Identity) -> Result<CertifiedKey> {
+    let key = rustls::sign::any_supported_type(&key)
+        .map_err(|_| error(String::from("unsupported private key")))?;
+    Ok(CertifiedKey::new(certs, Arc::new(key)))
+}
+
+fn error(err: String) -> std::io::Error {
+    std::io::Error::other(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(name: &str) -> String {
+        let cargo_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR to be set");
+        let path = std::path::PathBuf::from(cargo_dir)
+            .join("tests")
+            .join("sample_tls")
+            .join(name);
+        String::from(path.to_str().unwrap())
+    }
+
+    fn server_certificate(server_name: &str, cert: &str, key: &str) -> ServerCertificate {
+        ServerCertificate {
+            server_name: String::from(server_name),
+            certificate_file: sample(cert),
+            private_key_file: Some(sample(key)),
+            pkcs12_password: None,
+        }
+    }
+
+    fn default_identity() -> keys::Identity {
+        keys::load_identity(
+            &sample("httprust-test-cert.pem"),
+            Some(&sample("httprust-test-key.pem")),
+            None,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn lookup_by_name() {
+        let resolver = SniResolver::new(
+            default_identity(),
+            &[
+                server_certificate(
+                    "alpha.test",
+                    "httprust-test-alpha-cert.pem",
+                    "httprust-test-alpha-key.pem",
+                ),
+                server_certificate(
+                    "beta.test",
+                    "httprust-test-beta-cert.pem",
+                    "httprust-test-beta-key.pem",
+                ),
+            ],
+        )
+        .unwrap();
+
+        let default = resolver.lookup(None).cert.clone();
+        let alpha = resolver.lookup(Some("alpha.test")).cert.clone();
+        let beta = resolver.lookup(Some("BETA.test")).cert.clone();
+
+        assert_eq!(default_identity().0, default);
+        assert_ne!(default, alpha);
+        assert_ne!(alpha, beta);
+        assert_eq!(default, resolver.lookup(Some("gamma.test")).cert);
+    }
+
+    #[test]
+    fn certificate_must_match_name() {
+        let result = SniResolver::new(
+            default_identity(),
+            &[server_certificate(
+                "beta.test",
+                "httprust-test-alpha-cert.pem",
+                "httprust-test-alpha-key.pem",
+            )],
+        );
+
+        let e = result.err().expect("mismatch to be refused");
+        assert!(e.to_string().contains("not valid for beta.test"), "{}", e);
+    }
+}
@@ -0,0 +1,252 @@
+extern crate libc;
+
+use crate::listen::Socket;
+use futures::{Future, Stream};
+use std::io::Result;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// the protocol described in sd_listen_fds(3) and sd_notify(3), without linking to libsystemd
+
+// inherited sockets start right after stdin, stdout and stderr
+const LISTEN_FDS_START: RawFd = 3;
+
+// set once the inherited sockets are taken, as they can only have one owner
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+// sockets handed over by socket activation, when they are meant for this process. Only the first
+// call gets them.
+pub fn listeners() -> Result<Vec<Socket>> {
+    if TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(vec![]);
+    }
+    let fds = listen_fds(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    );
+
+    fds.into_iter().map(socket).collect()
+}
+
+fn listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> Vec<RawFd> {
+    if listen_pid.and_then(|p| p.parse::<u32>().ok()) != Some(pid) {
+        return vec![];
+    }
+    let count = listen_fds
+        .and_then(|n| n.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    (LISTEN_FDS_START..LISTEN_FDS_START + count).collect()
+}
+
+// takes ownership of fd, which must be a listening stream socket
+fn socket(fd: RawFd) -> Result<Socket> {
+    let mut kind: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let found = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_TYPE,
+            &mut kind as *mut libc::c_int as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if found != 0 || kind != libc::SOCK_STREAM {
+        return Err(error(format!("inherited fd {} is not a stream socket", fd)));
+    }
+
+    let mut address: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    if unsafe {
+        libc::getsockname(
+            fd,
+            &mut address as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut len,
+        )
+    } != 0
+    {
+        return Err(std::io::Error::last_os_error());
+    }
+    unsafe {
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+    }
+
+    let socket = match libc::c_int::from(address.ss_family) {
+        libc::AF_UNIX => {
+            let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            // systemd owns the socket file
+            Socket::Unix(listener, None)
+        }
+        _ => {
+            let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+            listener.set_nonblocking(true)?;
+            Socket::Tcp(listener)
+        }
+    };
+    log::info!("inherited {} from systemd", socket);
+    Ok(socket)
+}
+
+// what the service manager asked of us, read from the environment once at startup
+#[derive(Clone, Debug)]
+pub struct Manager {
+    socket: Option<String>,
+    watchdog: Option<Duration>,
+}
+
+impl Manager {
+    pub fn from_env() -> Manager {
+        Manager {
+            socket: std::env::var_os("NOTIFY_SOCKET").map(|s| s.to_string_lossy().into_owned()),
+            watchdog: watchdog_interval(
+                std::env::var("WATCHDOG_USEC").ok().as_deref(),
+                std::env::var("WATCHDOG_PID").ok().as_deref(),
+                std::process::id(),
+            ),
+        }
+    }
+
+    // tells the service manager about our state, like READY=1. Does nothing when not started by
+    // one.
+    pub fn notify(&self, state: &str) {
+        if let Some(socket) = &self.socket {
+            if let Err(e) = notify_to(socket, state) {
+                log::warn!("error notifying systemd of {}: {}", state, e);
+            }
+        }
+    }
+
+    // keepalives at half the interval the service manager expects them, when it asked for them.
+    // Never finishes.
+    pub fn watchdog(&self) -> Option<impl Future<Item = (), Error = ()>> {
+        let interval = self.watchdog?;
+        log::info!("sending systemd watchdog keepalives every {:?}", interval);
+
+        let manager = self.clone();
+        Some(
+            tokio::timer::Interval::new_interval(interval)
+                .map_err(|e| log::error!("watchdog timer error: {}", e))
+                .for_each(move |_| {
+                    manager.notify("WATCHDOG=1");
+                    Ok(())
+                }),
+        )
+    }
+}
+
+fn notify_to(socket: &str, state: &str) -> Result<()> {
+    log::debug!("notifying {} of {}", socket, state);
+    let sender = UnixDatagram::unbound()?;
+    match socket.strip_prefix('@') {
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            sender.send_to_addr(state.as_bytes(), &address)?;
+        }
+        None => {
+            sender.send_to(state.as_bytes(), socket)?;
+        }
+    }
+    Ok(())
+}
+
+fn watchdog_interval(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok() != Some(own_pid) {
+            return None;
+        }
+    }
+    match usec?.parse::<u64>() {
+        Ok(usec) if usec > 0 => Some(Duration::from_micros(usec / 2)),
+        _ => None,
+    }
+}
+
+fn error(err: String) -> std::io::Error {
+    std::io::Error::other(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::IntoRawFd;
+
+    #[test]
+    fn only_for_this_process() {
+        assert_eq!(vec![3, 4], listen_fds(Some("42"), Some("2"), 42));
+        assert!(listen_fds(Some("41"), Some("2"), 42).is_empty());
+        assert!(listen_fds(None, Some("2"), 42).is_empty());
+        assert!(listen_fds(Some("42"), None, 42).is_empty());
+    }
+
+    #[test]
+    fn inherited_sockets() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = tcp.local_addr().unwrap();
+        match socket(tcp.into_raw_fd()).unwrap() {
+            Socket::Tcp(listener) => assert_eq!(address, listener.local_addr().unwrap()),
+            other => panic!("expected a tcp socket, got {}", other),
+        }
+
+        let path =
+            std::env::temp_dir().join(format!("httprust-inherited-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        match socket(unix.into_raw_fd()).unwrap() {
+            Socket::Unix(_, None) => {}
+            other => panic!("expected an inherited unix socket, got {}", other),
+        }
+        let _ = std::fs::remove_file(&path);
+
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(socket(udp.into_raw_fd()).is_err());
+    }
+
+    #[test]
+    fn notifies() {
+        let path =
+            std::env::temp_dir().join(format!("httprust-notify-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let receiver = UnixDatagram::bind(&path).unwrap();
+
+        notify_to(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buffer = [0; 64];
+        let n = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(b"READY=1", &buffer[..n]);
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn notifies_abstract_sockets() {
+        use std::os::linux::net::SocketAddrExt;
+
+        let name = format!("httprust-notify-{}", std::process::id());
+        let address = std::os::unix::net::SocketAddr::from_abstract_name(&name).unwrap();
+        let receiver = UnixDatagram::bind_addr(&address).unwrap();
+
+        notify_to(&format!("@{}", name), "STOPPING=1").unwrap();
+        let mut buffer = [0; 64];
+        let n = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(b"STOPPING=1", &buffer[..n]);
+    }
+
+    #[test]
+    fn watchdog_at_half_the_interval() {
+        assert_eq!(
+            Some(Duration::from_secs(5)),
+            watchdog_interval(Some("10000000"), None, 42)
+        );
+        assert_eq!(
+            Some(Duration::from_secs(5)),
+            watchdog_interval(Some("10000000"), Some("42"), 42)
+        );
+        assert_eq!(None, watchdog_interval(Some("10000000"), Some("41"), 42));
+        assert_eq!(None, watchdog_interval(Some("0"), None, 42));
+        assert_eq!(None, watchdog_interval(None, None, 42));
+    }
+}
@@ -2,57 +2,327 @@ extern crate hyper_rustls;
 extern crate rustls;
 extern crate tokio_rustls;
 use super::TlsConfig;
-use futures::Stream;
-use hyper;
-use log;
+use crate::acme;
+use crate::handshake;
+use crate::keys;
+use crate::sni;
+use futures::{Future, Stream};
 use std::io::Result;
-use std::sync::Arc;
-use tokio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
 
 // most code below kindly taken from hyper-rustls example code
 
-pub fn configure_tls(cfg: TlsConfig) -> Result<Arc<rustls::ServerConfig>> {
+pub type ServerStream = tokio_rustls::TlsStream<tokio::net::TcpStream, rustls::ServerSession>;
+
+// modification time and size of a file, None if it could not be read
+type Stamp = Option<(SystemTime, u64)>;
+
+// the tls configuration new connections are accepted with. Reloading swaps it atomically,
+// established connections keep the configuration they started with.
+#[derive(Clone)]
+pub struct SharedConfig {
+    tls: Arc<RwLock<TlsConfig>>,
+    current: Arc<RwLock<Arc<rustls::ServerConfig>>>,
+    stamps: Arc<Mutex<Vec<Stamp>>>,
+    // false once SIGHUP reloads the whole configuration, this one included
+    on_sighup: Arc<AtomicBool>,
+    // answered on the tls port when set, for acme tls-alpn-01
+    challenges: Option<acme::Challenges>,
+}
+
+impl SharedConfig {
+    pub fn new(cfg: TlsConfig) -> Result<SharedConfig> {
+        let current = configure_tls(&cfg)?;
+        let stamps = stamps(&cfg);
+
+        Ok(SharedConfig {
+            tls: Arc::new(RwLock::new(cfg)),
+            current: Arc::new(RwLock::new(current)),
+            stamps: Arc::new(Mutex::new(stamps)),
+            on_sighup: Arc::new(AtomicBool::new(true)),
+            challenges: None,
+        })
+    }
+
+    pub fn with_challenges(mut self, challenges: acme::Challenges) -> SharedConfig {
+        self.challenges = Some(challenges);
+        *self.current.write().unwrap() = self.answering_challenges(self.get());
+        self
+    }
+
+    fn answering_challenges(&self, cfg: Arc<rustls::ServerConfig>) -> Arc<rustls::ServerConfig> {
+        match &self.challenges {
+            Some(challenges) => {
+                let mut cfg = (*cfg).clone();
+                cfg.cert_resolver = Arc::new(acme::ChallengeResolver::new(
+                    cfg.cert_resolver.clone(),
+                    challenges.clone(),
+                ));
+                cfg.alpn_protocols.push(b"acme-tls/1".to_vec());
+                Arc::new(cfg)
+            }
+            None => cfg,
+        }
+    }
+
+    pub fn get(&self) -> Arc<rustls::ServerConfig> {
+        self.current.read().unwrap().clone()
+    }
+
+    // on failure the current configuration stays in place
+    pub fn reload(&self) -> Result<()> {
+        log::info!("reloading tls configuration");
+        let tls = self.tls.read().unwrap();
+        *self.stamps.lock().unwrap() = stamps(&tls);
+
+        match configure_tls(&tls) {
+            Ok(cfg) => {
+                *self.current.write().unwrap() = self.answering_challenges(cfg);
+                Ok(())
+            }
+            Err(e) => {
+                log::error!("keeping the current tls configuration: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    // replaces the settings along with the configuration made from them by configure_tls
+    pub fn update(&self, cfg: TlsConfig, server: Arc<rustls::ServerConfig>) {
+        *self.stamps.lock().unwrap() = stamps(&cfg);
+        *self.tls.write().unwrap() = cfg;
+        *self.current.write().unwrap() = self.answering_challenges(server);
+    }
+
+    // leaves SIGHUP to whoever reloads the configuration as a whole
+    pub fn ignore_sighup(&self) {
+        self.on_sighup.store(false, Ordering::SeqCst);
+    }
+
+    // true if any of the certificate or key files changed since the last (re)load
+    fn changed(&self) -> bool {
+        *self.stamps.lock().unwrap() != stamps(&self.tls.read().unwrap())
+    }
+
+    // reloads on SIGHUP, and when the files change if an interval to check them is configured.
+    // Never finishes, so it should be dropped with the server.
+    pub fn watch(self) -> impl Future<Item = (), Error = ()> {
+        use tokio_signal::unix::{Signal, SIGHUP};
+
+        let sighup = Signal::new(SIGHUP)
+            .flatten_stream()
+            .map(|_| true)
+            .map_err(|e| log::error!("error catching SIGHUP: {}", e));
+
+        let interval = self.tls.read().unwrap().watch_interval;
+        let poll: Box<dyn Stream<Item = bool, Error = ()> + Send> = match interval {
+            Some(interval) => Box::new(
+                tokio::timer::Interval::new_interval(interval)
+                    .map(|_| false)
+                    .map_err(|e| log::error!("error watching certificates: {}", e)),
+            ),
+            None => Box::new(futures::stream::empty()),
+        };
+
+        sighup.select(poll).for_each(move |forced| {
+            if forced && !self.on_sighup.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            if forced || self.changed() {
+                let _ = self.reload();
+            }
+            Ok(())
+        })
+    }
+}
+
+// stamps of every file the configuration is read from
+fn stamps(cfg: &TlsConfig) -> Vec<Stamp> {
+    let mut files = vec![Some(&cfg.certificate_file), cfg.private_key_file.as_ref()];
+    for name in &cfg.server_names {
+        files.push(Some(&name.certificate_file));
+        files.push(name.private_key_file.as_ref());
+    }
+    files.push(cfg.client_auth.as_ref().map(|c| &c.ca_file));
+
+    files
+        .into_iter()
+        .flatten()
+        .map(|f| {
+            std::fs::metadata(f)
+                .and_then(|m| Ok((m.modified()?, m.len())))
+                .ok()
+        })
+        .collect()
+}
+
+pub fn configure_tls(cfg: &TlsConfig) -> Result<Arc<rustls::ServerConfig>> {
     log::debug!("configuring tls");
 
     // Build TLS configuration.
 
-    // Load public certificate.
-    let certs = load_certs(cfg.certificate_file.as_str())?;
-    // Load private key.
-    let key = load_private_key(cfg.private_key_file.as_str())?;
-    // Do not use client certificate authentication.
-    let mut server_cfg = rustls::ServerConfig::new(rustls::NoClientAuth::new());
-    server_cfg.alpn_protocols = vec![b"h2".to_vec(), b"http1.1".to_vec()];
-
-    // Select a certificate to use.
-    server_cfg
-        .set_single_cert(certs, key)
-        .map_err(|e| error(format!("{}", e)))?;
+    // Load public certificate and private key.
+    let (certs, key) = keys::load_identity(
+        cfg.certificate_file.as_str(),
+        cfg.private_key_file.as_deref(),
+        cfg.pkcs12_password.as_deref(),
+    )?;
+    // Optionally verify client certificates.
+    let client_auth = match &cfg.client_auth {
+        Some(client_auth) => {
+            let roots = load_roots(client_auth.ca_file.as_str())?;
+            log::info!(
+                "verifying client certificates against {}",
+                client_auth.ca_file
+            );
+            if client_auth.optional {
+                rustls::AllowAnyAnonymousOrAuthenticatedClient::new(roots)
+            } else {
+                rustls::AllowAnyAuthenticatedClient::new(roots)
+            }
+        }
+        None => rustls::NoClientAuth::new(),
+    };
+    let mut server_cfg = rustls::ServerConfig::new(client_auth);
+    configure_protocol(cfg, &mut server_cfg)?;
+
+    if cfg.key_log {
+        match std::env::var_os("SSLKEYLOGFILE") {
+            Some(file) => log::warn!(
+                "writing tls secrets to {}, anyone reading it can decrypt the traffic",
+                file.to_string_lossy()
+            ),
+            None => log::warn!("tls key log requested, but SSLKEYLOGFILE is not set"),
+        }
+        server_cfg.key_log = Arc::new(rustls::KeyLogFile::new());
+    }
+
+    // Select a certificate to use, by server name if there is more than one.
+    if cfg.server_names.is_empty() {
+        server_cfg
+            .set_single_cert(certs, key)
+            .map_err(|e| error(format!("{}", e)))?;
+    } else {
+        server_cfg.cert_resolver =
+            Arc::new(sni::SniResolver::new((certs, key), &cfg.server_names)?);
+    }
+    server_cfg.cert_resolver =
+        handshake::recording_resolver(server_cfg.cert_resolver.clone(), &cfg.server_names);
     Ok(Arc::new(server_cfg))
 }
 
-pub fn make_server(
-    address: std::net::SocketAddr,
-    cfg: Arc<rustls::ServerConfig>,
-) -> Result<
-    hyper::server::Builder<
-        impl Stream<
-            Item = tokio_rustls::TlsStream<tokio::net::TcpStream, rustls::ServerSession>,
-            Error = std::io::Error,
-        >,
-    >,
-> {
-    log::debug!("serving tls at {}", address);
-
-    // Create a TCP listener via tokio.
-    let tcp = tokio::net::tcp::TcpListener::bind(&address)?;
-    let tls_acceptor = tokio_rustls::TlsAcceptor::from(cfg);
+// versions, cipher suites, alpn and resumption
+fn configure_protocol(cfg: &TlsConfig, server_cfg: &mut rustls::ServerConfig) -> Result<()> {
+    use rustls::ProtocolVersion;
+
+    server_cfg.versions = match cfg.min_version.as_deref() {
+        None | Some("1.2") => vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+        Some("1.3") => vec![ProtocolVersion::TLSv1_3],
+        Some(other) => {
+            return Err(error(format!(
+                "unsupported minimum tls version {}, expected 1.2 or 1.3",
+                other
+            )))
+        }
+    };
+
+    if !cfg.cipher_suites.is_empty() {
+        server_cfg.ciphersuites = cfg
+            .cipher_suites
+            .iter()
+            .map(|name| cipher_suite(name))
+            .collect::<Result<_>>()?;
+    }
+    if !server_cfg
+        .versions
+        .iter()
+        .any(|v| server_cfg.supports_version(*v))
+    {
+        return Err(error(String::from(
+            "none of the cipher suites can be used with the allowed tls versions",
+        )));
+    }
+
+    server_cfg.alpn_protocols = match &cfg.alpn_protocols {
+        Some(protocols) => protocols
+            .iter()
+            .map(|p| match p.as_str() {
+                "h2" | "http/1.1" => Ok(p.as_bytes().to_vec()),
+                other => Err(error(format!(
+                    "unsupported alpn protocol {}, expected h2 or http/1.1",
+                    other
+                ))),
+            })
+            .collect::<Result<_>>()?,
+        None => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+    };
+
+    if cfg.session_tickets {
+        server_cfg.ticketer = rustls::Ticketer::new();
+    }
+    match cfg.session_cache_size {
+        Some(0) => server_cfg.set_persistence(Arc::new(rustls::NoServerSessionStorage {})),
+        Some(size) => server_cfg.set_persistence(rustls::ServerSessionMemoryCache::new(size)),
+        None => {}
+    }
+
+    Ok(())
+}
+
+fn cipher_suite(name: &str) -> Result<&'static rustls::SupportedCipherSuite> {
+    let name_of = |suite: &rustls::SupportedCipherSuite| format!("{:?}", suite.suite);
+
+    rustls::ALL_CIPHERSUITES
+        .iter()
+        .find(|suite| name_of(suite).eq_ignore_ascii_case(name))
+        .cloned()
+        .ok_or_else(|| {
+            let supported: Vec<String> = rustls::ALL_CIPHERSUITES
+                .iter()
+                .map(|s| name_of(s))
+                .collect();
+            error(format!(
+                "unsupported cipher suite {}, expected one of {}",
+                name,
+                supported.join(", ")
+            ))
+        })
+}
+
+// accept decides, based on the peer address, whether a connection is allowed to start a handshake
+pub fn make_server<F>(
+    incoming: tokio::net::tcp::Incoming,
+    cfg: SharedConfig,
+    accept: F,
+) -> hyper::server::Builder<impl Stream<Item = ServerStream, Error = std::io::Error>>
+where
+    F: Fn(&std::net::SocketAddr) -> bool + Send + 'static,
+{
+    let failures = Arc::new(handshake::Failures::default());
+
     // Prepare a long-running future stream to accept and serve cients.
-    let tls = tcp
-        .incoming()
-        .and_then(move |s| tls_acceptor.accept(s))
+    let tls = incoming
+        .filter(move |s| s.peer_addr().map(|p| accept(&p)).unwrap_or(false))
+        .and_then(move |s| {
+            let peer = s.peer_addr().ok();
+            let (handshake, requested) =
+                handshake::recording(tokio_rustls::TlsAcceptor::from(cfg.get()).accept(s));
+            let failures = failures.clone();
+            handshake.then(move |r| match r {
+                Ok(x) => {
+                    failures.check_server_name(peer, &requested);
+                    Ok(Some(x))
+                }
+                Err(e) => {
+                    failures.report(peer, &requested, &e);
+                    Ok(None)
+                }
+            })
+        })
         .then(|r| match r {
-            Ok(x) => Ok::<_, std::io::Error>(Some(x)),
+            Ok(x) => Ok::<_, std::io::Error>(x),
             Err(_e) => {
                 log::error!("{}", _e);
                 Ok(None)
@@ -60,38 +330,273 @@ pub fn make_server(
         })
         .filter_map(|x| x);
 
-    let builder = hyper::Server::builder(tls);
-    Ok(builder)
+    hyper::Server::builder(tls)
 }
 
 fn error(err: String) -> std::io::Error {
-    std::io::Error::new(std::io::ErrorKind::Other, err)
+    std::io::Error::other(err)
 }
 
-// Load public certificate from file.
-fn load_certs(filename: &str) -> Result<Vec<rustls::Certificate>> {
-    // Open certificate file.
-    let certfile = std::fs::File::open(filename)
+// Load the certificate authorities client certificates are checked against.
+fn load_roots(filename: &str) -> Result<rustls::RootCertStore> {
+    let cafile = std::fs::File::open(filename)
         .map_err(|e| error(format!("failed to open {}: {}", filename, e)))?;
-    let mut reader = std::io::BufReader::new(certfile);
+    let mut reader = std::io::BufReader::new(cafile);
 
-    // Load and return certificate.
-    rustls::internal::pemfile::certs(&mut reader)
-        .map_err(|_| error("failed to load certificate".into()))
+    let mut roots = rustls::RootCertStore::empty();
+    match roots.add_pem_file(&mut reader) {
+        Ok((valid, _)) if valid > 0 => Ok(roots),
+        _ => Err(error(format!("no usable ca certificates in {}", filename))),
+    }
 }
 
-// Load private key from file.
-fn load_private_key(filename: &str) -> Result<rustls::PrivateKey> {
-    // Open keyfile.
-    let keyfile = std::fs::File::open(filename)
-        .map_err(|e| error(format!("failed to open {}: {}", filename, e)))?;
-    let mut reader = std::io::BufReader::new(keyfile);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(name: &str) -> std::path::PathBuf {
+        let cargo_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR to be set");
+        std::path::PathBuf::from(cargo_dir)
+            .join("tests")
+            .join("sample_tls")
+            .join(name)
+    }
+
+    fn serving(cfg: &SharedConfig) -> rustls::Certificate {
+        let key = cfg.get().cert_resolver.resolve(None, &[]).unwrap();
+        key.cert[0].clone()
+    }
+
+    fn install(dir: &std::path::Path, cert: &str, key: &str) {
+        std::fs::copy(sample(cert), dir.join("cert.pem")).unwrap();
+        std::fs::copy(sample(key), dir.join("key.pem")).unwrap();
+    }
+
+    fn ec_config() -> TlsConfig {
+        TlsConfig {
+            certificate_file: String::from(sample("httprust-test-ec-cert.pem").to_str().unwrap()),
+            private_key_file: Some(String::from(
+                sample("httprust-test-ec-key.pem").to_str().unwrap(),
+            )),
+            ..Default::default()
+        }
+    }
+
+    fn client_config() -> rustls::ClientConfig {
+        let mut ca = std::io::BufReader::new(
+            std::fs::File::open(sample("httprust-test-ca-cert.pem")).unwrap(),
+        );
+        let mut cfg = rustls::ClientConfig::new();
+        cfg.root_store.add_pem_file(&mut ca).unwrap();
+        cfg
+    }
+
+    // runs a handshake in memory, returning the client side of it
+    fn handshake(
+        server: TlsConfig,
+        client: rustls::ClientConfig,
+    ) -> std::result::Result<rustls::ClientSession, rustls::TLSError> {
+        use rustls::Session;
+
+        fn transfer(from: &mut dyn Session, to: &mut dyn Session) {
+            let mut buffer = vec![];
+            from.write_tls(&mut buffer).unwrap();
+            to.read_tls(&mut &buffer[..]).unwrap();
+        }
+
+        let server = configure_tls(&server).unwrap();
+        let name = webpki::DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut client = rustls::ClientSession::new(&Arc::new(client), name);
+        let mut server = rustls::ServerSession::new(&server);
+
+        for _ in 0..10 {
+            if !client.is_handshaking() && !server.is_handshaking() {
+                return Ok(client);
+            }
+            transfer(&mut client, &mut server);
+            server.process_new_packets()?;
+            transfer(&mut server, &mut client);
+            client.process_new_packets()?;
+        }
+        panic!("handshake did not finish");
+    }
+
+    #[test]
+    fn alpn() {
+        use rustls::Session;
+
+        let mut client = client_config();
+        client.alpn_protocols = vec![b"http/1.1".to_vec()];
+        let session = handshake(ec_config(), client).unwrap();
+        assert_eq!(Some(&b"http/1.1"[..]), session.get_alpn_protocol());
+
+        let mut client = client_config();
+        client.alpn_protocols = vec![b"http/1.1".to_vec(), b"h2".to_vec()];
+        let session = handshake(ec_config(), client).unwrap();
+        assert_eq!(Some(&b"h2"[..]), session.get_alpn_protocol());
+
+        let mut client = client_config();
+        client.alpn_protocols = vec![b"http/1.1".to_vec(), b"h2".to_vec()];
+        let server = TlsConfig {
+            alpn_protocols: Some(vec![String::from("http/1.1")]),
+            ..ec_config()
+        };
+        let session = handshake(server, client).unwrap();
+        assert_eq!(Some(&b"http/1.1"[..]), session.get_alpn_protocol());
+    }
+
+    #[test]
+    fn min_version() {
+        let server = TlsConfig {
+            min_version: Some(String::from("1.3")),
+            ..ec_config()
+        };
+        let mut client = client_config();
+        client.versions = vec![rustls::ProtocolVersion::TLSv1_2];
+        assert!(handshake(server.clone(), client).is_err());
+
+        let mut client = client_config();
+        client.versions = vec![rustls::ProtocolVersion::TLSv1_3];
+        assert!(handshake(server, client).is_ok());
+    }
+
+    #[test]
+    fn cipher_suites() {
+        use rustls::Session;
+
+        let server = TlsConfig {
+            cipher_suites: vec![
+                String::from("TLS13_AES_256_GCM_SHA384"),
+                String::from("tls_ecdhe_ecdsa_with_aes_128_gcm_sha256"),
+            ],
+            ..ec_config()
+        };
+
+        let session = handshake(server.clone(), client_config()).unwrap();
+        assert_eq!(
+            rustls::CipherSuite::TLS13_AES_256_GCM_SHA384,
+            session.get_negotiated_ciphersuite().unwrap().suite
+        );
+
+        let mut client = client_config();
+        client.versions = vec![rustls::ProtocolVersion::TLSv1_2];
+        let session = handshake(server, client).unwrap();
+        assert_eq!(
+            rustls::CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+            session.get_negotiated_ciphersuite().unwrap().suite
+        );
+    }
+
+    #[test]
+    fn invalid_protocol_settings() {
+        let invalid = vec![
+            TlsConfig {
+                min_version: Some(String::from("1.1")),
+                ..ec_config()
+            },
+            TlsConfig {
+                cipher_suites: vec![String::from("TLS_RSA_WITH_RC4_128_MD5")],
+                ..ec_config()
+            },
+            TlsConfig {
+                min_version: Some(String::from("1.3")),
+                cipher_suites: vec![String::from("TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256")],
+                ..ec_config()
+            },
+            TlsConfig {
+                alpn_protocols: Some(vec![String::from("http1.1")]),
+                ..ec_config()
+            },
+        ];
+
+        for cfg in invalid {
+            assert!(configure_tls(&cfg).is_err(), "{:?}", cfg);
+        }
+    }
+
+    #[test]
+    fn resumption() {
+        let cfg = configure_tls(&ec_config()).unwrap();
+        assert!(!cfg.ticketer.enabled());
+
+        let cfg = configure_tls(&TlsConfig {
+            session_tickets: true,
+            ..ec_config()
+        })
+        .unwrap();
+        assert!(cfg.ticketer.enabled());
+    }
+
+    #[test]
+    fn reload() {
+        let dir = std::env::temp_dir().join(format!("httprust-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        install(
+            &dir,
+            "httprust-test-alpha-cert.pem",
+            "httprust-test-alpha-key.pem",
+        );
+
+        let cfg = SharedConfig::new(TlsConfig {
+            certificate_file: String::from(dir.join("cert.pem").to_str().unwrap()),
+            private_key_file: Some(String::from(dir.join("key.pem").to_str().unwrap())),
+            ..Default::default()
+        })
+        .unwrap();
+        let alpha = serving(&cfg);
+        assert!(!cfg.changed());
+
+        install(
+            &dir,
+            "httprust-test-beta-cert.pem",
+            "httprust-test-beta-key.pem",
+        );
+        assert!(cfg.changed());
+        cfg.reload().unwrap();
+        assert!(!cfg.changed());
+        let beta = serving(&cfg);
+        assert_ne!(alpha, beta);
+
+        // halfway through a rotation, the old configuration stays
+        std::fs::copy(sample("httprust-test-alpha-cert.pem"), dir.join("cert.pem")).unwrap();
+        assert!(cfg.reload().is_err());
+        assert_eq!(beta, serving(&cfg));
+
+        std::fs::write(dir.join("cert.pem"), "garbage").unwrap();
+        assert!(cfg.reload().is_err());
+        assert_eq!(beta, serving(&cfg));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn update() {
+        let cfg = SharedConfig::new(TlsConfig {
+            certificate_file: String::from(
+                sample("httprust-test-alpha-cert.pem").to_str().unwrap(),
+            ),
+            private_key_file: Some(String::from(
+                sample("httprust-test-alpha-key.pem").to_str().unwrap(),
+            )),
+            ..Default::default()
+        })
+        .unwrap();
+        let alpha = serving(&cfg);
+
+        let beta = TlsConfig {
+            certificate_file: String::from(sample("httprust-test-beta-cert.pem").to_str().unwrap()),
+            private_key_file: Some(String::from(
+                sample("httprust-test-beta-key.pem").to_str().unwrap(),
+            )),
+            ..Default::default()
+        };
+        let server = configure_tls(&beta).unwrap();
+        cfg.update(beta, server);
+        assert_ne!(alpha, serving(&cfg));
 
-    // Load and return a single private key.
-    let keys = rustls::internal::pemfile::rsa_private_keys(&mut reader)
-        .map_err(|_| error("failed to load private key".into()))?;
-    if keys.len() != 1 {
-        return Err(error("expected a single private key".into()));
+        // reloads read the new files
+        assert!(!cfg.changed());
+        cfg.reload().unwrap();
+        assert_ne!(alpha, serving(&cfg));
     }
-    Ok(keys[0].clone())
 }
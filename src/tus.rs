@@ -0,0 +1,660 @@
+extern crate base64;
+extern crate ring;
+
+// server side of the tus 1.0 resumable upload protocol (https://tus.io/protocols/resumable-upload),
+// core protocol plus the creation and termination extensions.
+//
+// every upload gets a random id. While in progress, the data received so far lives in
+// <staging>/<id> and the bookkeeping (total length and destination) in <staging>/<id>.info. Once
+// all bytes have arrived, the data is moved to its destination under the root. Only one request at
+// a time writes to an upload.
+
+use crate::handler::{
+    direct_response, normalize_path, raw_direct_response, resolve_path, ResponseFuture,
+};
+use crate::limits::{self, BodyError, LimitedBody};
+use crate::{LimitsConfig, UploadConfig};
+use futures::{future, Future, Stream};
+use hyper::header::HeaderValue;
+use hyper::{header, Body, HeaderMap, Method, Request, Response, StatusCode};
+use path_abs::PathDir;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,termination";
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+const TUS_RESUMABLE: &str = "tus-resumable";
+const TUS_VERSION_HEADER: &str = "tus-version";
+const TUS_EXTENSION: &str = "tus-extension";
+const TUS_MAX_SIZE: &str = "tus-max-size";
+const UPLOAD_LENGTH: &str = "upload-length";
+const UPLOAD_OFFSET: &str = "upload-offset";
+const UPLOAD_METADATA: &str = "upload-metadata";
+
+#[derive(Clone)]
+pub struct Uploads {
+    root: PathDir,
+    staging: PathBuf,
+    endpoint: String,
+}
+
+#[derive(Debug, PartialEq)]
+struct UploadInfo {
+    length: u64,
+    target: String,
+}
+
+// data files of uploads being appended to or terminated. Shared by every Uploads, so a reload
+// doesn't let a second writer in.
+static WRITING: Mutex<Option<HashSet<PathBuf>>> = Mutex::new(None);
+
+// claims an upload for as long as it lives
+struct Writing(PathBuf);
+
+// access rules and authentication for a path, refusing with the response to send
+type Authorize<'a> = &'a dyn Fn(&str, &Request<Body>) -> Result<(), Box<Response<Body>>>;
+
+enum PatchError {
+    TooLarge,
+    Body(hyper::Error),
+    Io(std::io::Error),
+}
+
+impl Uploads {
+    pub fn new(root: &PathDir, cfg: &UploadConfig) -> std::io::Result<Uploads> {
+        let staging = root.join(&cfg.staging_dir);
+        std::fs::create_dir_all(&staging)?;
+        let staging = staging.canonicalize()?.as_path().to_path_buf();
+
+        if !staging.starts_with(root) || staging == root.as_path() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("staging directory {:?} must be inside the root", staging),
+            ));
+        }
+
+        let endpoint = format!("/{}", cfg.endpoint.trim_matches('/'));
+        log::info!(
+            "accepting uploads at {}, staging in {:?}",
+            endpoint,
+            staging
+        );
+
+        Ok(Uploads {
+            root: root.clone(),
+            staging,
+            endpoint,
+        })
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        self.split(path).is_some()
+    }
+
+    pub fn is_staging(&self, path: &Path) -> bool {
+        path.starts_with(&self.staging)
+    }
+
+    // splits the request path into the upload id, if any. None if this is not an upload path at all
+    fn split<'a>(&self, path: &'a str) -> Option<Option<&'a str>> {
+        let rest = path
+            .trim_end_matches('/')
+            .strip_prefix(self.endpoint.as_str())?;
+        if rest.is_empty() {
+            Some(None)
+        } else {
+            rest.strip_prefix('/').map(Some)
+        }
+    }
+
+    pub fn handle(
+        &self,
+        request: Request<Body>,
+        limits: &LimitsConfig,
+        authorize: Authorize,
+    ) -> ResponseFuture {
+        log::debug!("handling upload request for {}", request.uri());
+
+        if *request.method() == Method::OPTIONS {
+            return self.options(limits);
+        }
+
+        match request.headers().get(TUS_RESUMABLE) {
+            Some(v) if v == TUS_VERSION => {}
+            _ => {
+                log::info!("unsupported or missing tus version");
+                let mut response = raw_direct_response(StatusCode::PRECONDITION_FAILED);
+                response
+                    .headers_mut()
+                    .insert(TUS_VERSION_HEADER, HeaderValue::from_static(TUS_VERSION));
+                return Box::new(future::ok(response));
+            }
+        }
+
+        let id = match self.split(request.uri().path()) {
+            Some(id) => id.map(String::from),
+            None => return direct_response(StatusCode::NOT_FOUND),
+        };
+
+        let result = match (request.method().clone(), id) {
+            (Method::POST, None) => self.create(&request, limits, authorize),
+            (Method::HEAD, Some(id)) => self.offset(&id),
+            (Method::PATCH, Some(id)) => return self.append(&id, request, limits),
+            (Method::DELETE, Some(id)) => self.terminate(&id),
+            _ => Err(StatusCode::METHOD_NOT_ALLOWED),
+        };
+
+        let response = result.unwrap_or_else(tus_response);
+        Box::new(future::ok(response))
+    }
+
+    fn options(&self, limits: &LimitsConfig) -> ResponseFuture {
+        let mut response = tus_response(StatusCode::NO_CONTENT);
+        {
+            let headers = response.headers_mut();
+            headers.insert(TUS_VERSION_HEADER, HeaderValue::from_static(TUS_VERSION));
+            headers.insert(TUS_EXTENSION, HeaderValue::from_static(TUS_EXTENSIONS));
+            if let Some(max) = limits.max_body_size {
+                headers.insert(TUS_MAX_SIZE, HeaderValue::from(max));
+            }
+        }
+        Box::new(future::ok(response))
+    }
+
+    fn create(
+        &self,
+        request: &Request<Body>,
+        limits: &LimitsConfig,
+        authorize: Authorize,
+    ) -> Result<Response<Body>, StatusCode> {
+        let length = numeric_header(request.headers(), UPLOAD_LENGTH)?;
+        if let Some(max) = limits.max_body_size {
+            if length > max {
+                log::info!("upload of {} bytes exceeds limit of {}", length, max);
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+        }
+
+        let metadata = request
+            .headers()
+            .get(UPLOAD_METADATA)
+            .and_then(|m| m.to_str().ok())
+            .map(parse_metadata)
+            .unwrap_or_else(|| Ok(vec![]))?;
+        let target = metadata
+            .into_iter()
+            .find(|(k, _)| k == "filename")
+            .map(|(_, v)| v)
+            .ok_or_else(|| {
+                log::info!("upload without filename metadata");
+                StatusCode::BAD_REQUEST
+            })?;
+
+        let path = self.target_path(&target)?;
+        // the rules of the file written, not only those of the endpoint
+        if let Err(response) = authorize(&normalize_path(&format!("/{}", target)), request) {
+            return Ok(*response);
+        }
+        if path.exists() {
+            log::info!("refusing to overwrite {:?}", path);
+            return Err(StatusCode::CONFLICT);
+        }
+        let parent = path.parent().filter(|p| p.is_dir()).ok_or_else(|| {
+            log::info!("no directory to upload {:?} into", path);
+            StatusCode::NOT_FOUND
+        })?;
+        limits::check_storage(limits, parent, length)?;
+
+        let id = new_id()?;
+        let info = UploadInfo { length, target };
+        std::fs::write(self.data_path(&id), b"")
+            .and_then(|_| std::fs::write(self.info_path(&id), info.serialize()))
+            .map_err(internal_error)?;
+
+        log::info!("created upload {} for {:?}", id, info);
+
+        let location = format!("{}/{}", self.endpoint, id);
+        let mut response = tus_response(StatusCode::CREATED);
+        response.headers_mut().insert(
+            header::LOCATION,
+            HeaderValue::from_str(&location).map_err(internal_error)?,
+        );
+
+        if length == 0 {
+            self.finalize(&id, &info)?;
+        }
+
+        Ok(response)
+    }
+
+    fn offset(&self, id: &str) -> Result<Response<Body>, StatusCode> {
+        let info = self.load(id)?;
+        let offset = self.current_offset(id)?;
+
+        let mut response = tus_response(StatusCode::OK);
+        {
+            let headers = response.headers_mut();
+            headers.insert(UPLOAD_OFFSET, HeaderValue::from(offset));
+            headers.insert(UPLOAD_LENGTH, HeaderValue::from(info.length));
+            headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+        }
+        Ok(response)
+    }
+
+    fn append(&self, id: &str, request: Request<Body>, limits: &LimitsConfig) -> ResponseFuture {
+        let prepared = self
+            .claim(id)
+            .and_then(|writing| Ok((writing, self.prepare_append(id, &request, limits)?)));
+        let (writing, (info, offset, remaining)) = match prepared {
+            Ok(p) => p,
+            Err(code) => return Box::new(future::ok(tus_response(code))),
+        };
+
+        let data_path = self.data_path(id);
+        let remaining = LimitsConfig {
+            max_body_size: Some(remaining),
+            ..Default::default()
+        };
+        let body = LimitedBody::new(request.into_body(), &remaining).map_err(|e| match e {
+            BodyError::TooLarge => PatchError::TooLarge,
+            BodyError::Inner(e) => PatchError::Body(e),
+        });
+
+        let uploads = self.clone();
+        let id = String::from(id);
+
+        let fut = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(data_path)
+            .map_err(PatchError::Io)
+            .and_then(move |file| {
+                body.fold((file, offset), |(file, offset), chunk| {
+                    let len = chunk.len() as u64;
+                    tokio::io::write_all(file, chunk)
+                        .map(move |(file, _)| (file, offset + len))
+                        .map_err(PatchError::Io)
+                })
+            })
+            .then(move |result| {
+                let response = match result {
+                    Ok((_, offset)) => {
+                        log::debug!("upload {} at offset {} of {}", id, offset, info.length);
+
+                        let finalized = if offset == info.length {
+                            uploads.finalize(&id, &info)
+                        } else {
+                            Ok(())
+                        };
+
+                        match finalized {
+                            Ok(()) => {
+                                let mut response = tus_response(StatusCode::NO_CONTENT);
+                                response
+                                    .headers_mut()
+                                    .insert(UPLOAD_OFFSET, HeaderValue::from(offset));
+                                response
+                            }
+                            Err(code) => tus_response(code),
+                        }
+                    }
+                    Err(PatchError::TooLarge) => {
+                        log::info!("upload {} received more data than announced", id);
+                        tus_response(StatusCode::PAYLOAD_TOO_LARGE)
+                    }
+                    Err(PatchError::Body(e)) => {
+                        log::warn!("error receiving upload {}: {}", id, e);
+                        tus_response(StatusCode::BAD_REQUEST)
+                    }
+                    Err(PatchError::Io(e)) => {
+                        log::error!("error writing upload {}: {}", id, e);
+                        tus_response(StatusCode::INTERNAL_SERVER_ERROR)
+                    }
+                };
+                drop(writing);
+                Ok(response)
+            });
+
+        Box::new(fut)
+    }
+
+    fn prepare_append(
+        &self,
+        id: &str,
+        request: &Request<Body>,
+        limits: &LimitsConfig,
+    ) -> Result<(UploadInfo, u64, u64), StatusCode> {
+        match request.headers().get(header::CONTENT_TYPE) {
+            Some(ct) if ct == OFFSET_CONTENT_TYPE => {}
+            _ => return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+        }
+
+        let info = self.load(id)?;
+        let offset = self.current_offset(id)?;
+
+        if numeric_header(request.headers(), UPLOAD_OFFSET)? != offset {
+            log::info!("offset mismatch for upload {}, at {}", id, offset);
+            return Err(StatusCode::CONFLICT);
+        }
+
+        let remaining = info.length.checked_sub(offset).ok_or_else(|| {
+            log::error!("upload {} is past its length of {}", id, info.length);
+            StatusCode::CONFLICT
+        })?;
+        if let Some(length) = limits::content_length(request.headers())? {
+            if length > remaining {
+                log::info!("upload {} would exceed its length of {}", id, info.length);
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+        }
+
+        let parent = self.target_path(&info.target)?;
+        let parent = parent.parent().ok_or(StatusCode::NOT_FOUND)?;
+        limits::check_storage(limits, parent, remaining)?;
+
+        Ok((info, offset, remaining))
+    }
+
+    fn terminate(&self, id: &str) -> Result<Response<Body>, StatusCode> {
+        let _writing = self.claim(id)?;
+        self.load(id)?;
+
+        std::fs::remove_file(self.data_path(id))
+            .and_then(|_| std::fs::remove_file(self.info_path(id)))
+            .map_err(internal_error)?;
+
+        log::info!("terminated upload {}", id);
+        Ok(tus_response(StatusCode::NO_CONTENT))
+    }
+
+    fn finalize(&self, id: &str, info: &UploadInfo) -> Result<(), StatusCode> {
+        let target = self.target_path(&info.target)?;
+        if target.exists() {
+            log::warn!(
+                "{:?} appeared while uploading, keeping upload {}",
+                target,
+                id
+            );
+            return Err(StatusCode::CONFLICT);
+        }
+
+        std::fs::rename(self.data_path(id), &target)
+            .and_then(|_| std::fs::remove_file(self.info_path(id)))
+            .map_err(internal_error)?;
+
+        log::info!("upload {} complete, stored as {:?}", id, target);
+        Ok(())
+    }
+
+    // refuses with 423 Locked while another request is writing to the upload
+    fn claim(&self, id: &str) -> Result<Writing, StatusCode> {
+        let path = self.data_path(id);
+        let mut writing = WRITING.lock().unwrap();
+        if !writing
+            .get_or_insert_with(HashSet::new)
+            .insert(path.clone())
+        {
+            log::info!("upload {} is already being written", id);
+            return Err(StatusCode::LOCKED);
+        }
+        Ok(Writing(path))
+    }
+
+    fn target_path(&self, target: &str) -> Result<PathBuf, StatusCode> {
+        let path = resolve_path(&self.root, target)?;
+        if self.is_staging(&path) {
+            log::warn!("attempted upload into staging area: {:?}", path);
+            return Err(StatusCode::FORBIDDEN);
+        }
+        Ok(path.as_path().to_path_buf())
+    }
+
+    fn load(&self, id: &str) -> Result<UploadInfo, StatusCode> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(StatusCode::NOT_FOUND);
+        }
+
+        let content = std::fs::read_to_string(self.info_path(id)).map_err(|e| {
+            log::info!("no upload {}: {}", id, e);
+            StatusCode::NOT_FOUND
+        })?;
+        UploadInfo::parse(&content).ok_or_else(|| {
+            log::error!("corrupt info for upload {}", id);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })
+    }
+
+    fn current_offset(&self, id: &str) -> Result<u64, StatusCode> {
+        std::fs::metadata(self.data_path(id))
+            .map(|m| m.len())
+            .map_err(internal_error)
+    }
+
+    fn data_path(&self, id: &str) -> PathBuf {
+        self.staging.join(id)
+    }
+
+    fn info_path(&self, id: &str) -> PathBuf {
+        self.staging.join(format!("{}.info", id))
+    }
+}
+
+impl Drop for Writing {
+    fn drop(&mut self) {
+        if let Some(writing) = WRITING.lock().unwrap().as_mut() {
+            writing.remove(&self.0);
+        }
+    }
+}
+
+impl UploadInfo {
+    fn serialize(&self) -> String {
+        format!("length {}\ntarget {}\n", self.length, self.target)
+    }
+
+    fn parse(s: &str) -> Option<UploadInfo> {
+        let mut length = None;
+        let mut target = None;
+
+        for line in s.lines() {
+            let mut parts = line.splitn(2, ' ');
+            match (parts.next(), parts.next()) {
+                (Some("length"), Some(l)) => length = l.parse::<u64>().ok(),
+                (Some("target"), Some(t)) => target = Some(String::from(t)),
+                _ => {}
+            }
+        }
+
+        Some(UploadInfo {
+            length: length?,
+            target: target?,
+        })
+    }
+}
+
+// Upload-Metadata is a comma separated list of "key base64(value)" pairs, the value being optional
+fn parse_metadata(header: &str) -> Result<Vec<(String, String)>, StatusCode> {
+    header
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut parts = pair.splitn(2, ' ');
+            let key = parts.next().unwrap_or("");
+            let value = match parts.next() {
+                Some(v) => base64::decode(v.trim())
+                    .ok()
+                    .and_then(|v| String::from_utf8(v).ok())
+                    .ok_or(StatusCode::BAD_REQUEST)?,
+                None => String::new(),
+            };
+            Ok((String::from(key), value))
+        })
+        .collect()
+}
+
+fn numeric_header(headers: &HeaderMap<HeaderValue>, name: &str) -> Result<u64, StatusCode> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+        .ok_or_else(|| {
+            log::info!("missing or invalid {} header", name);
+            StatusCode::BAD_REQUEST
+        })
+}
+
+fn new_id() -> Result<String, StatusCode> {
+    use ring::rand::SecureRandom;
+
+    let mut bytes = [0u8; 16];
+    ring::rand::SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn tus_response(code: StatusCode) -> Response<Body> {
+    let body = if code.is_success() {
+        Body::empty()
+    } else {
+        Body::from(code.canonical_reason().unwrap_or(""))
+    };
+
+    let mut response = Response::builder().status(code).body(body).unwrap();
+    response
+        .headers_mut()
+        .insert(TUS_RESUMABLE, HeaderValue::from_static(TUS_VERSION));
+    response
+}
+
+fn internal_error<E: std::fmt::Display>(e: E) -> StatusCode {
+    log::error!("upload error: {}", e);
+    StatusCode::INTERNAL_SERVER_ERROR
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata() {
+        let parsed =
+            parse_metadata("filename d29ybGRfZG9taW5hdGlvbl9wbGFuLnBkZg==,is_confidential")
+                .expect("valid metadata");
+        assert_eq!(
+            vec![
+                (
+                    String::from("filename"),
+                    String::from("world_domination_plan.pdf")
+                ),
+                (String::from("is_confidential"), String::new()),
+            ],
+            parsed
+        );
+
+        assert_eq!(
+            Err(StatusCode::BAD_REQUEST),
+            parse_metadata("filename !!notbase64")
+        );
+    }
+
+    #[test]
+    fn info_roundtrip() {
+        let info = UploadInfo {
+            length: 1234,
+            target: String::from("some dir/file.txt"),
+        };
+        let serialized = info.serialize();
+        assert_eq!(Some(info), UploadInfo::parse(&serialized));
+        assert_eq!(None, UploadInfo::parse("length 12\n"));
+    }
+
+    // an upload of length bytes in a root of its own, with data received so far
+    fn staged(name: &str, length: u64, data: &str) -> (PathBuf, Uploads) {
+        let dir = std::env::temp_dir().join(format!("httprust-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let root = PathDir::new(&dir).unwrap();
+        let uploads = Uploads::new(&root, &UploadConfig::default()).unwrap();
+        let info = UploadInfo {
+            length,
+            target: String::from("file.txt"),
+        };
+        std::fs::write(uploads.info_path("ab"), info.serialize()).unwrap();
+        std::fs::write(uploads.data_path("ab"), data).unwrap();
+        (dir, uploads)
+    }
+
+    fn patch(offset: u64, length: u64) -> Request<Body> {
+        Request::builder()
+            .method(Method::PATCH)
+            .header(header::CONTENT_TYPE, OFFSET_CONTENT_TYPE)
+            .header(UPLOAD_OFFSET, offset)
+            .header(header::CONTENT_LENGTH, length)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn content_length_beyond_upload_length() {
+        let (dir, uploads) = staged("tus-length", 3, "1");
+        let request = |length: u64| patch(1, length);
+        let limits = LimitsConfig::default();
+        assert!(uploads.prepare_append("ab", &request(2), &limits).is_ok());
+        assert_eq!(
+            Err(StatusCode::PAYLOAD_TOO_LARGE),
+            uploads
+                .prepare_append("ab", &request(3), &limits)
+                .map(|_| ())
+        );
+        // would overflow when added to the offset
+        assert_eq!(
+            Err(StatusCode::PAYLOAD_TOO_LARGE),
+            uploads
+                .prepare_append("ab", &request(u64::MAX), &limits)
+                .map(|_| ())
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn one_writer_at_a_time() {
+        let (dir, uploads) = staged("tus-writers", 3, "");
+        let limits = LimitsConfig::default();
+
+        // holds on to the upload until it is done or dropped
+        let first = uploads.append("ab", patch(0, 3), &limits);
+        let second = uploads.append("ab", patch(0, 3), &limits).wait().unwrap();
+        assert_eq!(StatusCode::LOCKED, second.status());
+        assert_eq!(StatusCode::LOCKED, uploads.terminate("ab").unwrap_err());
+
+        drop(first);
+        assert!(uploads.claim("ab").is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn offset_past_upload_length() {
+        let (dir, uploads) = staged("tus-past", 3, "1234");
+        assert_eq!(
+            Err(StatusCode::CONFLICT),
+            uploads
+                .prepare_append("ab", &patch(4, 0), &LimitsConfig::default())
+                .map(|_| ())
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ids_are_unique() {
+        let a = new_id().unwrap();
+        let b = new_id().unwrap();
+        assert_eq!(32, a.len());
+        assert_ne!(a, b);
+    }
+}
@@ -0,0 +1,392 @@
+extern crate ring;
+extern crate untrusted;
+extern crate x509_parser;
+
+use crate::keys::der_write;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+use std::io::Result;
+
+// just enough X.509 to issue certificates and certificate requests for P-256 keys: the generated
+// development certificates, ACME certificate requests and tls-alpn-01 challenge certificates.
+// Signed with ring, encoded by hand.
+
+pub const DAY: u64 = 24 * 60 * 60;
+
+// ecdsa-with-SHA256, 1.2.840.10045.4.3.2
+const ECDSA_SHA256_OID: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+// id-ecPublicKey with namedCurve prime256v1
+const P256_ALGORITHM: &[u8] = &[
+    0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d,
+    0x03, 0x01, 0x07,
+];
+const COMMON_NAME_OID: &[u8] = &[0x06, 0x03, 0x55, 0x04, 0x03];
+const ORGANIZATION_OID: &[u8] = &[0x06, 0x03, 0x55, 0x04, 0x0a];
+// pkcs#9 extensionRequest, 1.2.840.113549.1.9.14
+const EXTENSION_REQUEST_OID: &[u8] = &[
+    0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x09, 0x0e,
+];
+pub const SUBJECT_KEY_ID_OID: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x0e];
+pub const KEY_USAGE_OID: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x0f];
+pub const BASIC_CONSTRAINTS_OID: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x13];
+pub const AUTHORITY_KEY_ID_OID: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x23];
+const SUBJECT_ALT_NAME_OID: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x11];
+const EXTENDED_KEY_USAGE_OID: &[u8] = &[0x06, 0x03, 0x55, 0x1d, 0x25];
+// id-kp-serverAuth, 1.3.6.1.5.5.7.3.1
+const SERVER_AUTH_OID: &[u8] = &[0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x01];
+// id-pe-acmeIdentifier, 1.3.6.1.5.5.7.1.31
+const ACME_IDENTIFIER_OID: &[u8] = &[0x06, 0x08, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x1f];
+
+#[allow(clippy::too_many_arguments)]
+pub fn certificate(
+    signer: &EcdsaKeyPair,
+    rng: &SystemRandom,
+    issuer: &[u8],
+    subject: &[u8],
+    public_key: &[u8],
+    now: u64,
+    days: u64,
+    extensions: &[Vec<u8>],
+) -> Result<Vec<u8>> {
+    // positive and without leading zeros, so it always takes the full 16 bytes
+    let mut serial = [0u8; 16];
+    rng.fill(&mut serial)
+        .map_err(|_| error(String::from("failed to generate a serial number")))?;
+    serial[0] = (serial[0] & 0x7f) | 0x40;
+
+    let mut validity = time(now - 60 * 60);
+    validity.extend(time(now + days * DAY));
+
+    let mut tbs = vec![0xa0, 0x03, 0x02, 0x01, 0x02];
+    tbs.extend(der_write(0x02, &serial));
+    tbs.extend(der_write(0x30, ECDSA_SHA256_OID));
+    tbs.extend_from_slice(issuer);
+    tbs.extend(der_write(0x30, &validity));
+    tbs.extend_from_slice(subject);
+    tbs.extend(public_key_info(public_key));
+    tbs.extend(der_write(0xa3, &der_write(0x30, &extensions.concat())));
+
+    signed(signer, rng, der_write(0x30, &tbs))
+}
+
+// a certificate signing request for the host names, as ACME wants it to finalize an order
+pub fn certificate_request(
+    key: &EcdsaKeyPair,
+    rng: &SystemRandom,
+    hostnames: &[String],
+) -> Result<Vec<u8>> {
+    let mut attribute = EXTENSION_REQUEST_OID.to_vec();
+    attribute.extend(der_write(
+        0x31,
+        &der_write(0x30, &subject_alt_names(hostnames)),
+    ));
+
+    let mut info = vec![0x02, 0x01, 0x00];
+    info.extend(name(&hostnames[0]));
+    info.extend(public_key_info(key.public_key().as_ref()));
+    info.extend(der_write(0xa0, &der_write(0x30, &attribute)));
+
+    signed(key, rng, der_write(0x30, &info))
+}
+
+// the self signed certificate answering a tls-alpn-01 challenge, carrying the sha-256 of the
+// key authorization. Returns the certificate and its pkcs#8 key.
+pub fn challenge_certificate(
+    hostname: &str,
+    key_authorization: &str,
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let rng = SystemRandom::new();
+    let pkcs8 = generate_key(&rng)?;
+    let key = key_pair(&pkcs8)?;
+
+    let digest = ring::digest::digest(&ring::digest::SHA256, key_authorization.as_bytes());
+    let extensions = vec![
+        subject_alt_names(&[String::from(hostname)]),
+        extension(ACME_IDENTIFIER_OID, true, &der_write(0x04, digest.as_ref())),
+    ];
+    let name = name(hostname);
+    let cert = certificate(
+        &key,
+        &rng,
+        &name,
+        &name,
+        key.public_key().as_ref(),
+        now(),
+        7,
+        &extensions,
+    )?;
+    Ok((cert, pkcs8))
+}
+
+pub fn name(common_name: &str) -> Vec<u8> {
+    let attribute = |oid: &[u8], value: &str| {
+        let mut pair = oid.to_vec();
+        pair.extend(der_write(0x0c, value.as_bytes()));
+        der_write(0x31, &der_write(0x30, &pair))
+    };
+
+    let mut name = attribute(ORGANIZATION_OID, "httprust");
+    name.extend(attribute(COMMON_NAME_OID, common_name));
+    der_write(0x30, &name)
+}
+
+pub fn extension(oid: &[u8], critical: bool, value: &[u8]) -> Vec<u8> {
+    let mut ext = oid.to_vec();
+    if critical {
+        ext.extend_from_slice(&[0x01, 0x01, 0xff]);
+    }
+    ext.extend(der_write(0x04, value));
+    der_write(0x30, &ext)
+}
+
+// the extensions of a tls server certificate for the host names, which can be ip addresses
+pub fn server_extensions(hostnames: &[String]) -> Vec<Vec<u8>> {
+    vec![
+        extension(BASIC_CONSTRAINTS_OID, true, &der_write(0x30, &[])),
+        // digitalSignature
+        extension(KEY_USAGE_OID, true, &[0x03, 0x02, 0x07, 0x80]),
+        extension(
+            EXTENDED_KEY_USAGE_OID,
+            false,
+            &der_write(0x30, SERVER_AUTH_OID),
+        ),
+        subject_alt_names(hostnames),
+    ]
+}
+
+fn subject_alt_names(hostnames: &[String]) -> Vec<u8> {
+    let names: Vec<u8> = hostnames
+        .iter()
+        .flat_map(|h| match h.parse::<std::net::IpAddr>() {
+            Ok(std::net::IpAddr::V4(ip)) => der_write(0x87, &ip.octets()),
+            Ok(std::net::IpAddr::V6(ip)) => der_write(0x87, &ip.octets()),
+            Err(_) => der_write(0x82, h.as_bytes()),
+        })
+        .collect();
+    extension(SUBJECT_ALT_NAME_OID, false, &der_write(0x30, &names))
+}
+
+// the dns names and ip addresses a certificate is for, sorted, None if it has none
+pub fn hostnames(cert: &x509_parser::certificate::X509Certificate) -> Option<Vec<String>> {
+    use x509_parser::extensions::GeneralName;
+
+    let san = cert.subject_alternative_name().ok()??;
+    let mut names: Vec<String> = san
+        .value
+        .general_names
+        .iter()
+        .filter_map(|n| match n {
+            GeneralName::DNSName(name) => Some(name.to_string()),
+            GeneralName::IPAddress(ip) => ip_to_string(ip),
+            _ => None,
+        })
+        .collect();
+    names.sort();
+    Some(names)
+}
+
+// sorted and with ip addresses written the same way as hostnames() does
+pub fn normalize(hostnames: &[String]) -> Vec<String> {
+    let mut names: Vec<String> = hostnames
+        .iter()
+        .map(|h| match h.parse::<std::net::IpAddr>() {
+            Ok(ip) => ip.to_string(),
+            Err(_) => h.clone(),
+        })
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+fn ip_to_string(ip: &[u8]) -> Option<String> {
+    match ip.len() {
+        4 => {
+            let mut octets = [0u8; 4];
+            octets.copy_from_slice(ip);
+            Some(std::net::IpAddr::from(octets).to_string())
+        }
+        16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(ip);
+            Some(std::net::IpAddr::from(octets).to_string())
+        }
+        _ => None,
+    }
+}
+
+pub fn expires_within(
+    cert: &x509_parser::certificate::X509Certificate,
+    now: u64,
+    days: u64,
+) -> bool {
+    cert.validity().not_after.timestamp() <= (now + days * DAY) as i64
+}
+
+// the usual sha1 of the public key, only used for chain building
+pub fn key_id(key: &EcdsaKeyPair) -> Vec<u8> {
+    ring::digest::digest(&ring::digest::SHA1, key.public_key().as_ref())
+        .as_ref()
+        .to_vec()
+}
+
+pub fn generate_key(rng: &SystemRandom) -> Result<Vec<u8>> {
+    EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, rng)
+        .map(|doc| doc.as_ref().to_vec())
+        .map_err(|_| error(String::from("failed to generate a private key")))
+}
+
+pub fn key_pair(pkcs8: &[u8]) -> Result<EcdsaKeyPair> {
+    EcdsaKeyPair::from_pkcs8(
+        &ECDSA_P256_SHA256_ASN1_SIGNING,
+        untrusted::Input::from(pkcs8),
+    )
+    .map_err(|e| error(format!("invalid private key: {}", e)))
+}
+
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn public_key_info(public_key: &[u8]) -> Vec<u8> {
+    let mut spki = der_write(0x30, P256_ALGORITHM);
+    spki.extend(bit_string(public_key));
+    der_write(0x30, &spki)
+}
+
+// appends the signature algorithm and signature to what was signed
+fn signed(signer: &EcdsaKeyPair, rng: &SystemRandom, content: Vec<u8>) -> Result<Vec<u8>> {
+    let signature = signer
+        .sign(rng, untrusted::Input::from(&content))
+        .map_err(|_| error(String::from("failed to sign")))?;
+
+    let mut out = content;
+    out.extend(der_write(0x30, ECDSA_SHA256_OID));
+    out.extend(bit_string(signature.as_ref()));
+    Ok(der_write(0x30, &out))
+}
+
+fn bit_string(bytes: &[u8]) -> Vec<u8> {
+    let mut value = vec![0x00];
+    value.extend_from_slice(bytes);
+    der_write(0x03, &value)
+}
+
+// UTCTime until 2050, GeneralizedTime after, as rfc 5280 wants
+fn time(secs: u64) -> Vec<u8> {
+    let (year, month, day) = civil_from_days(secs / DAY);
+    let clock = format!(
+        "{:02}{:02}{:02}{:02}{:02}Z",
+        month,
+        day,
+        secs % DAY / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    );
+
+    if year < 2050 {
+        der_write(0x17, format!("{:02}{}", year % 100, clock).as_bytes())
+    } else {
+        der_write(0x18, format!("{:04}{}", year, clock).as_bytes())
+    }
+}
+
+// days since 1970-01-01 to year, month and day
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn error(err: String) -> std::io::Error {
+    std::io::Error::other(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times() {
+        assert_eq!(b"700101000000Z", &time(0)[2..]);
+        assert_eq!(
+            b"000229123456Z",
+            &time(951_782_400 + 12 * 3600 + 34 * 60 + 56)[2..]
+        );
+        assert_eq!(0x17, time(2_524_607_999)[0]);
+        assert_eq!(b"20500101000000Z", &time(2_524_608_000)[2..]);
+    }
+
+    #[test]
+    fn certificate_request() {
+        let rng = SystemRandom::new();
+        let key = key_pair(&generate_key(&rng).unwrap()).unwrap();
+        let names = vec![
+            String::from("example.test"),
+            String::from("www.example.test"),
+        ];
+
+        let der = super::certificate_request(&key, &rng, &names).unwrap();
+        let (_, csr) =
+            <x509_parser::certification_request::X509CertificationRequest as x509_parser::prelude::FromDer<_>>::from_der(&der).unwrap();
+
+        // signed by the key it requests a certificate for
+        let (_, request, _) = crate::keys::der_read(&der).unwrap();
+        let (_, _, rest) = crate::keys::der_read(request).unwrap();
+        let info = &request[..request.len() - rest.len()];
+        let (_, _, rest) = crate::keys::der_read(rest).unwrap();
+        let (_, signature, _) = crate::keys::der_read(rest).unwrap();
+        ring::signature::verify(
+            &ring::signature::ECDSA_P256_SHA256_ASN1,
+            untrusted::Input::from(key.public_key().as_ref()),
+            untrusted::Input::from(info),
+            untrusted::Input::from(&signature[1..]),
+        )
+        .unwrap();
+
+        let requested: Vec<String> = csr
+            .requested_extensions()
+            .unwrap()
+            .filter_map(|ext| match ext {
+                x509_parser::extensions::ParsedExtension::SubjectAlternativeName(san) => Some(
+                    san.general_names
+                        .iter()
+                        .map(|n| n.to_string())
+                        .collect::<Vec<_>>(),
+                ),
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        assert_eq!(
+            vec!["DNSName(example.test)", "DNSName(www.example.test)"],
+            requested
+        );
+    }
+
+    #[test]
+    fn challenge_certificate_carries_the_key_authorization() {
+        let (der, _) = super::challenge_certificate("example.test", "token.thumbprint").unwrap();
+        let (_, cert) = x509_parser::parse_x509_certificate(&der).unwrap();
+
+        let ext = cert
+            .extensions()
+            .iter()
+            .find(|e| e.oid.to_id_string() == "1.3.6.1.5.5.7.1.31")
+            .unwrap();
+        let digest = ring::digest::digest(&ring::digest::SHA256, b"token.thumbprint");
+        assert!(ext.critical);
+        assert_eq!(der_write(0x04, digest.as_ref()), ext.value);
+        assert_eq!(Some(vec![String::from("example.test")]), hostnames(&cert));
+    }
+}
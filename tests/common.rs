@@ -1,78 +1,447 @@
-use reqwest;
-use std::sync::Once;
+#![allow(dead_code)]
+
+use std::sync::{Once, OnceLock};
 
 pub use reqwest::{Client, Error, Response, StatusCode};
 
-pub const PORT: u16 = 2950;
-pub const TLS_PORT: u16 = PORT + 1;
 pub const ADDRESS: &str = "localhost";
 
-pub fn server() {
-    static SERVER: Once = Once::new();
-    SERVER.call_once(|| {
-        let cargo_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR to be set");
-        let root = std::path::PathBuf::from(cargo_dir)
-            .join("tests")
-            .join("sample_root");
+pub fn server() -> u16 {
+    static PORT: OnceLock<u16> = OnceLock::new();
+    *PORT.get_or_init(|| {
+        serve(httprust::Config {
+            port: 0,
+            local_only: true,
+            root: sample_root(),
+            tls: None,
+            ..Default::default()
+        })[0]
+    })
+}
 
-        let (tx, rx) = std::sync::mpsc::channel();
+pub fn tls_server() -> u16 {
+    static PORT: OnceLock<u16> = OnceLock::new();
+    *PORT.get_or_init(|| {
+        serve(httprust::Config {
+            port: 0,
+            local_only: true,
+            root: sample_root(),
+            tls: Some(httprust::TlsConfig {
+                certificate_file: sample_tls("httprust-test-cert.pem"),
+                private_key_file: Some(sample_tls("httprust-test-key.pem")),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })[0]
+    })
+}
 
-        std::thread::spawn(move || {
-            let cfg = httprust::Config {
-                port: PORT,
-                local_only: true,
-                root: String::from(root.to_str().unwrap()),
-                tls: None,
-            };
-            httprust::run_notify(cfg, move || {
-                tx.send(()).expect("no notify readyness");
-            });
-        });
+// requires client certificates signed by the test ca, hello.txt is reserved for another subject
+pub fn mtls_server() -> u16 {
+    static PORT: OnceLock<u16> = OnceLock::new();
+    *PORT.get_or_init(|| {
+        serve(httprust::Config {
+            port: 0,
+            local_only: true,
+            root: sample_root(),
+            tls: Some(httprust::TlsConfig {
+                certificate_file: sample_tls("httprust-test-cert.pem"),
+                private_key_file: Some(sample_tls("httprust-test-key.pem")),
+                client_auth: Some(httprust::ClientAuthConfig {
+                    ca_file: sample_tls("httprust-test-ca-cert.pem"),
+                    optional: false,
+                }),
+                ..Default::default()
+            }),
+            access: httprust::AccessConfig {
+                paths: vec![httprust::PathAccess {
+                    prefix: String::from("/hello.txt"),
+                    rules: httprust::AccessRules {
+                        subjects: vec![String::from("someone-else")],
+                        ..Default::default()
+                    },
+                }],
+                ..Default::default()
+            },
+            ..Default::default()
+        })[0]
+    })
+}
 
-        rx.recv().expect("to be ready");
-    });
+// tls from the PKCS#12 fixture bundle
+pub fn pkcs12_server() -> u16 {
+    static PORT: OnceLock<u16> = OnceLock::new();
+    *PORT.get_or_init(|| {
+        serve(httprust::Config {
+            port: 0,
+            local_only: true,
+            root: sample_root(),
+            tls: Some(httprust::TlsConfig {
+                certificate_file: sample_tls("httprust-test.p12"),
+                pkcs12_password: Some(String::from("httprust")),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })[0]
+    })
 }
 
-pub fn tls_server() {
-    static SERVER: Once = Once::new();
-    SERVER.call_once(|| {
-        let cargo_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR to be set");
-        let root = std::path::PathBuf::from(cargo_dir.clone())
-            .join("tests")
-            .join("sample_root");
-        let tls_config = std::path::PathBuf::from(cargo_dir)
-            .join("tests")
-            .join("sample_tls");
-        let cert_file = tls_config.join("httprust-test-cert.pem");
-        let key_file = tls_config.join("httprust-test-key.pem");
-
-        let (tx, rx) = std::sync::mpsc::channel();
-
-        std::thread::spawn(move || {
-            let cfg = httprust::Config {
-                port: TLS_PORT,
+// tls with an ECDSA certificate and a SEC1 key
+pub fn ec_server() -> u16 {
+    static PORT: OnceLock<u16> = OnceLock::new();
+    *PORT.get_or_init(|| {
+        serve(httprust::Config {
+            port: 0,
+            local_only: true,
+            root: sample_root(),
+            tls: Some(httprust::TlsConfig {
+                certificate_file: sample_tls("httprust-test-ec-cert.pem"),
+                private_key_file: Some(sample_tls("httprust-test-ec-key.pem")),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })[0]
+    })
+}
+
+// plain http, with rsa and ec tls listeners next to it, returns their ports in that order
+pub fn multi_server() -> Vec<u16> {
+    static PORTS: OnceLock<Vec<u16>> = OnceLock::new();
+    PORTS
+        .get_or_init(|| {
+            let tls = |name: &str| httprust::ListenerConfig {
+                port: 0,
                 local_only: true,
-                root: String::from(root.to_str().unwrap()),
                 tls: Some(httprust::TlsConfig {
-                    certificate_file: String::from(cert_file.to_str().unwrap()),
-                    private_key_file: String::from(key_file.to_str().unwrap()),
+                    certificate_file: sample_tls(&format!("httprust-test-{}cert.pem", name)),
+                    private_key_file: Some(sample_tls(&format!("httprust-test-{}key.pem", name))),
+                    ..Default::default()
                 }),
+                ..Default::default()
             };
-            httprust::run_notify(cfg, move || {
-                tx.send(()).expect("no notify readyness");
-            });
+            serve(httprust::Config {
+                port: 0,
+                local_only: true,
+                root: sample_root(),
+                listeners: vec![tls(""), tls("ec-")],
+                ..Default::default()
+            })
+        })
+        .clone()
+}
+
+// listens on explicitly given ipv4 and ipv6 loopback addresses
+pub fn bind_server() -> u16 {
+    static PORT: OnceLock<u16> = OnceLock::new();
+    *PORT.get_or_init(|| {
+        serve(httprust::Config {
+            port: 0,
+            bind: vec![String::from("127.0.0.1"), String::from("[::1]:0")],
+            root: sample_root(),
+            ..Default::default()
+        })[0]
+    })
+}
+
+// listens on a unix socket only, returns its path
+pub fn unix_server() -> String {
+    static SERVER: Once = Once::new();
+    let path = std::env::temp_dir()
+        .join(format!("httprust-test-{}.sock", std::process::id()))
+        .to_str()
+        .unwrap()
+        .to_string();
+
+    let socket = path.clone();
+    SERVER.call_once(|| {
+        serve(httprust::Config {
+            unix: Some(httprust::UnixSocketConfig {
+                path: socket,
+                mode: Some(0o600),
+            }),
+            root: sample_root(),
+            ..Default::default()
         });
+    });
+
+    path
+}
+
+// sends a bare http/1.1 request over a unix socket, returning the whole response
+pub fn unix_get(path: &str, resource: &str) -> String {
+    use std::io::{Read, Write};
+
+    let mut socket = std::os::unix::net::UnixStream::connect(path).expect("connect");
+    write!(
+        socket,
+        "GET /{} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        resource
+    )
+    .expect("send request");
+
+    let mut response = String::new();
+    socket.read_to_string(&mut response).expect("read response");
+    response
+}
+
+// serves a generated certificate, returns the certificate authority to trust, the https port and
+// the port redirecting to it
+pub fn self_signed_server() -> (String, u16, u16) {
+    static PORTS: OnceLock<Vec<u16>> = OnceLock::new();
+    let dir =
+        std::env::temp_dir().join(format!("httprust-test-self-signed-{}", std::process::id()));
+    let cfg = httprust::SelfSignedConfig {
+        cache_dir: Some(dir.to_str().unwrap().to_string()),
+        ..Default::default()
+    };
+
+    let ports = PORTS.get_or_init(|| {
+        let generated = httprust::self_signed(&cfg).expect("generate certificate");
+        serve(httprust::Config {
+            port: 0,
+            redirect_port: Some(0),
+            local_only: true,
+            root: sample_root(),
+            tls: Some(httprust::TlsConfig {
+                certificate_file: generated.certificate_file,
+                private_key_file: Some(generated.private_key_file),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    });
+
+    let ca = httprust::self_signed(&cfg)
+        .expect("reuse certificate")
+        .ca_certificate_file;
+    (ca, ports[0], ports[1])
+}
+
+// gets its certificate for localhost from a pebble acme server, configured through PEBBLE_DIRECTORY
+// and PEBBLE_CA (its test/certs/pebble.minica.pem). Pebble checks http-01 challenges on port 5002.
+pub fn acme_server() -> u16 {
+    static PORT: OnceLock<u16> = OnceLock::new();
+    *PORT.get_or_init(|| {
+        let acme = httprust::AcmeConfig {
+            directory_url: std::env::var("PEBBLE_DIRECTORY")
+                .unwrap_or_else(|_| String::from("https://localhost:14000/dir")),
+            domains: vec![String::from("localhost")],
+            agree_tos: true,
+            cache_dir: std::env::temp_dir()
+                .join(format!("httprust-test-acme-{}", std::process::id()))
+                .to_string_lossy()
+                .into_owned(),
+            http_port: 5002,
+            directory_ca_file: Some(std::env::var("PEBBLE_CA").expect("PEBBLE_CA to be set")),
+            ..Default::default()
+        };
+        serve(httprust::Config {
+            port: 0,
+            local_only: true,
+            root: sample_root(),
+            tls: Some(httprust::TlsConfig {
+                certificate_file: acme.certificate_file(),
+                private_key_file: Some(acme.private_key_file()),
+                ..Default::default()
+            }),
+            acme: Some(acme),
+            ..Default::default()
+        })[0]
+    })
+}
+
+// alpha.test and beta.test have their own certificate, anything else gets the localhost one
+pub fn sni_server() -> u16 {
+    static PORT: OnceLock<u16> = OnceLock::new();
+    *PORT.get_or_init(|| {
+        let server_name = |name: &str| httprust::ServerCertificate {
+            server_name: format!("{}.test", name),
+            certificate_file: sample_tls(&format!("httprust-test-{}-cert.pem", name)),
+            private_key_file: Some(sample_tls(&format!("httprust-test-{}-key.pem", name))),
+            pkcs12_password: None,
+        };
+
+        serve(httprust::Config {
+            port: 0,
+            local_only: true,
+            root: sample_root(),
+            tls: Some(httprust::TlsConfig {
+                certificate_file: sample_tls("httprust-test-ec-cert.pem"),
+                private_key_file: Some(sample_tls("httprust-test-ec-key.pem")),
+                server_names: vec![server_name("alpha"), server_name("beta")],
+                ..Default::default()
+            }),
+            ..Default::default()
+        })[0]
+    })
+}
+
+// gets index.html from the sni server over a plain rustls client trusting the test ca, so the
+// server name is verified. Returns the status line and the certificate the server presented.
+// Without a server name no SNI is sent, the certificate is then checked against localhost.
+pub fn sni_get(server_name: Option<&str>) -> (String, rustls::Certificate) {
+    use std::io::{Read, Write};
+
+    let mut ca = std::io::BufReader::new(
+        std::fs::File::open(sample_tls("httprust-test-ca-cert.pem")).expect("open ca"),
+    );
+    let mut cfg = rustls::ClientConfig::new();
+    cfg.root_store.add_pem_file(&mut ca).expect("load ca");
+    cfg.enable_sni = server_name.is_some();
+
+    let name = server_name.unwrap_or("localhost");
+    let dns_name = webpki::DNSNameRef::try_from_ascii_str(name).expect("dns name");
+    let mut session = rustls::ClientSession::new(&std::sync::Arc::new(cfg), dns_name);
+    let mut socket = std::net::TcpStream::connect(("127.0.0.1", sni_server())).expect("connect");
+    let mut stream = rustls::Stream::new(&mut session, &mut socket);
+
+    write!(
+        stream,
+        "GET /index.html HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        name
+    )
+    .expect("send request");
+
+    let mut response = vec![];
+    let mut buffer = [0u8; 1024];
+    while !response.windows(2).any(|w| w == b"\r\n") {
+        let n = stream.read(&mut buffer).expect("read response");
+        assert!(n > 0, "connection closed early");
+        response.extend_from_slice(&buffer[..n]);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap().to_string();
+    let cert =
+        rustls::Session::get_peer_certificates(&session).expect("server certificate")[0].clone();
+    (status, cert)
+}
+
+pub fn sample_certificate(name: &str) -> rustls::Certificate {
+    let mut reader = std::io::BufReader::new(std::fs::File::open(sample_tls(name)).unwrap());
+    rustls::internal::pemfile::certs(&mut reader).unwrap()[0].clone()
+}
+
+pub fn sample_root() -> String {
+    let cargo_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR to be set");
+    let root = std::path::PathBuf::from(cargo_dir)
+        .join("tests")
+        .join("sample_root");
+    String::from(root.to_str().unwrap())
+}
+
+fn sample_tls(name: &str) -> String {
+    let cargo_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR to be set");
+    let path = std::path::PathBuf::from(cargo_dir)
+        .join("tests")
+        .join("sample_tls")
+        .join(name);
+    String::from(path.to_str().unwrap())
+}
+
+// runs the server on its own thread on free ports, returning them once it is ready. Listeners
+// bound to several addresses share a port, so there is one per listener.
+pub fn serve(cfg: httprust::Config) -> Vec<u16> {
+    let (tx, rx) = std::sync::mpsc::channel();
 
-        rx.recv().expect("to be ready");
+    std::thread::spawn(move || {
+        httprust::run_notify(cfg, move |addresses| {
+            tx.send(addresses).expect("no notify readyness");
+        })
+        .expect("start server");
     });
+
+    let mut ports: Vec<u16> = Vec::new();
+    for address in rx.recv().expect("to be ready") {
+        if !ports.contains(&address.port()) {
+            ports.push(address.port());
+        }
+    }
+    ports
+}
+
+pub fn client_identity() -> reqwest::Identity {
+    let cargo_dir = std::env::var("CARGO_MANIFEST_DIR").expect("CARGO_MANIFEST_DIR to be set");
+    let p12 = std::path::PathBuf::from(cargo_dir)
+        .join("tests")
+        .join("sample_tls")
+        .join("httprust-test-client.p12");
+    let der = std::fs::read(p12).expect("read client certificate");
+    reqwest::Identity::from_pkcs12_der(&der, "httprust").expect("client identity")
+}
+
+pub fn make_mtls_uri(resource: &str) -> String {
+    format!("https://{}:{}/{}", ADDRESS, mtls_server(), resource)
+}
+
+pub fn make_https_uri(port: u16, resource: &str) -> String {
+    format!("https://{}:{}/{}", ADDRESS, port, resource)
+}
+
+// serves a fresh, writable root with tus uploads enabled at /files, returns the root
+pub fn upload_server() -> std::path::PathBuf {
+    upload().0.clone()
+}
+
+fn upload() -> &'static (std::path::PathBuf, u16) {
+    static SERVER: OnceLock<(std::path::PathBuf, u16)> = OnceLock::new();
+    SERVER.get_or_init(|| {
+        let root = std::env::temp_dir().join(format!("httprust-uploads-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("sub")).expect("create upload root");
+
+        let ports = serve(httprust::Config {
+            port: 0,
+            local_only: true,
+            root: String::from(root.to_str().unwrap()),
+            limits: httprust::LimitsConfig {
+                max_body_size: Some(1024),
+                ..Default::default()
+            },
+            uploads: Some(httprust::UploadConfig::default()),
+            ..Default::default()
+        });
+        (root, ports[0])
+    })
+}
+
+// a server that drops every connection from localhost, on both its https and its redirect port
+pub fn denying_server() -> (u16, u16) {
+    static PORTS: OnceLock<(u16, u16)> = OnceLock::new();
+    *PORTS.get_or_init(|| {
+        let ports = serve(httprust::Config {
+            port: 0,
+            local_only: true,
+            root: sample_root(),
+            tls: Some(httprust::TlsConfig {
+                certificate_file: sample_tls("httprust-test-cert.pem"),
+                private_key_file: Some(sample_tls("httprust-test-key.pem")),
+                ..Default::default()
+            }),
+            redirect_port: Some(0),
+            access: httprust::AccessConfig {
+                global: httprust::AccessRules {
+                    deny: vec![String::from("127.0.0.0/8"), String::from("::1")],
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        (ports[0], ports[1])
+    })
+}
+
+pub fn make_upload_uri(resource: &str) -> String {
+    format!("http://{}:{}/{}", ADDRESS, upload().1, resource)
 }
 
 pub fn make_uri(resource: &str) -> String {
-    format!("http://{}:{}/{}", ADDRESS, PORT, resource)
+    format!("http://{}:{}/{}", ADDRESS, server(), resource)
 }
 
 pub fn make_tls_uri(resource: &str) -> String {
-    format!("https://{}:{}/{}", ADDRESS, TLS_PORT, resource)
+    format!("https://{}:{}/{}", ADDRESS, tls_server(), resource)
 }
 
 pub fn get(resource: &str) -> Result<Response, Error> {
@@ -137,3 +137,358 @@ fn tls_connect_reject_unkown() {
         .send()
         .expect_err("this should fail");
 }
+
+#[test]
+fn tls_connect_pkcs12() {
+    let port = pkcs12_server();
+
+    let response = Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .expect("build client")
+        .get(make_https_uri(port, "index.html").as_str())
+        .send()
+        .expect("fail send");
+
+    assert_eq!(StatusCode::OK, response.status());
+}
+
+#[test]
+fn tls_connect_ecdsa() {
+    let port = ec_server();
+
+    let response = Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .expect("build client")
+        .get(make_https_uri(port, "index.html").as_str())
+        .send()
+        .expect("fail send");
+
+    assert_eq!(StatusCode::OK, response.status());
+}
+
+#[test]
+fn plain_and_tls_listeners() {
+    let ports = multi_server();
+
+    let client = Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .expect("build client");
+    let uris = vec![
+        format!("http://{}:{}/index.html", ADDRESS, ports[0]),
+        make_https_uri(ports[1], "index.html"),
+        make_https_uri(ports[2], "index.html"),
+    ];
+
+    for uri in uris {
+        let response = client.get(uri.as_str()).send().expect("fail send");
+        assert_eq!(StatusCode::OK, response.status());
+    }
+}
+
+#[test]
+fn ipv4_and_ipv6() {
+    let port = bind_server();
+
+    for host in &["127.0.0.1", "[::1]"] {
+        let uri = format!("http://{}:{}/index.html", host, port);
+        let response = reqwest::get(uri.as_str()).expect("fail send");
+        assert_eq!(StatusCode::OK, response.status());
+    }
+}
+
+#[test]
+fn embedded_server() {
+    use futures::Future;
+
+    let mut runtime = tokio::runtime::Runtime::new().expect("runtime");
+    let (server, handle) = httprust::serve(httprust::Config {
+        port: 0,
+        local_only: true,
+        root: sample_root(),
+        ..Default::default()
+    })
+    .expect("serve");
+    let port = handle.addresses()[0].port();
+    runtime.spawn(server);
+
+    let uri = format!("http://{}:{}/index.html", ADDRESS, port);
+    let response = reqwest::get(uri.as_str()).expect("fail send");
+    assert_eq!(StatusCode::OK, response.status());
+
+    handle.shutdown();
+    handle.wait();
+    std::net::TcpStream::connect(("127.0.0.1", port)).expect_err("should be closed");
+
+    runtime.shutdown_on_idle().wait().unwrap();
+}
+
+#[test]
+fn reload_configuration() {
+    use futures::Future;
+
+    let dir = std::env::temp_dir().join(format!("httprust-reload-{}", std::process::id()));
+    for name in &["first", "second"] {
+        std::fs::create_dir_all(dir.join(name)).unwrap();
+        std::fs::write(dir.join(name).join("which.txt"), name).unwrap();
+    }
+    let config = |root: &str| httprust::Config {
+        port: 0,
+        local_only: true,
+        root: dir.join(root).to_str().unwrap().to_string(),
+        ..Default::default()
+    };
+
+    let mut runtime = tokio::runtime::Runtime::new().expect("runtime");
+    let (server, handle) = httprust::serve(config("first")).expect("serve");
+    let uri = format!(
+        "http://{}:{}/which.txt",
+        ADDRESS,
+        handle.addresses()[0].port()
+    );
+    runtime.spawn(server);
+    let which = || reqwest::get(uri.as_str()).unwrap().text().unwrap();
+    assert_eq!("first", which());
+
+    handle.reload(config("second")).expect("reload");
+    assert_eq!("second", which());
+
+    handle.reload(config("missing")).expect_err("invalid root");
+    assert_eq!("second", which());
+
+    // listeners only change with a restart, so neither does anything else
+    let mut moved = config("first");
+    moved.listeners.push(httprust::ListenerConfig {
+        port: 0,
+        local_only: true,
+        ..Default::default()
+    });
+    handle.reload(moved).expect_err("changed listeners");
+    assert_eq!("second", which());
+
+    handle.shutdown();
+    runtime.shutdown_on_idle().wait().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn startup_errors() {
+    let missing = httprust::run(httprust::Config {
+        port: 0,
+        root: String::from("/no/such/directory"),
+        ..Default::default()
+    });
+    match missing {
+        Err(httprust::Error::Config(_)) => {}
+        other => panic!("expected a configuration error, got {:?}", other),
+    }
+
+    let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let in_use = httprust::run(httprust::Config {
+        port: taken.local_addr().unwrap().port(),
+        bind: vec![String::from("127.0.0.1")],
+        root: sample_root(),
+        ..Default::default()
+    });
+    match in_use {
+        Err(httprust::Error::Listen(_)) => {}
+        other => panic!("expected a listen error, got {:?}", other),
+    }
+}
+
+#[test]
+fn unix_socket() {
+    let path = unix_server();
+
+    let response = unix_get(&path, "index.html");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("<title>Hello!</title>"));
+
+    let response = unix_get(&path, "no_such_thing");
+    assert!(
+        response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+        "{}",
+        response
+    );
+}
+
+#[test]
+fn tls_connect_self_signed() {
+    let (ca, port, _) = self_signed_server();
+    let ca = std::fs::read(ca).expect("read ca");
+
+    let response = Client::builder()
+        .add_root_certificate(reqwest::Certificate::from_pem(&ca).expect("ca certificate"))
+        .build()
+        .expect("build client")
+        .get(make_https_uri(port, "index.html").as_str())
+        .send()
+        .expect("fail send");
+
+    assert_eq!(StatusCode::OK, response.status());
+}
+
+#[test]
+fn redirect_to_https() {
+    let (ca, https_port, port) = self_signed_server();
+    let ca = std::fs::read(ca).expect("read ca");
+    let uri = format!("http://{}:{}/index.html?x=1", ADDRESS, port);
+
+    let response = Client::builder()
+        .redirect(reqwest::RedirectPolicy::none())
+        .build()
+        .expect("build client")
+        .get(uri.as_str())
+        .send()
+        .expect("fail send");
+    assert_eq!(StatusCode::PERMANENT_REDIRECT, response.status());
+    assert_eq!(
+        format!("https://{}:{}/index.html?x=1", ADDRESS, https_port),
+        response.headers()[reqwest::header::LOCATION]
+    );
+
+    let response = Client::builder()
+        .add_root_certificate(reqwest::Certificate::from_pem(&ca).expect("ca certificate"))
+        .build()
+        .expect("build client")
+        .get(uri.as_str())
+        .send()
+        .expect("fail send");
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!("https", response.url().scheme());
+}
+
+// needs a running pebble, see acme_server: cargo test -- --ignored acme
+#[test]
+#[ignore]
+fn acme_certificate_from_pebble() {
+    let port = acme_server();
+
+    // pebble generates its root at startup, the management interface hands it out
+    let roots = std::env::var("PEBBLE_ROOTS")
+        .unwrap_or_else(|_| String::from("https://localhost:15000/roots/0"));
+    let root = Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .expect("build client")
+        .get(roots.as_str())
+        .send()
+        .and_then(|mut r| r.text())
+        .expect("pebble root");
+    let client = Client::builder()
+        .add_root_certificate(reqwest::Certificate::from_pem(root.as_bytes()).expect("root"))
+        .build()
+        .expect("build client");
+
+    // until the certificate is issued a placeholder is served, which fails verification
+    let status = (0..60)
+        .filter_map(|_| {
+            let response = client
+                .get(make_https_uri(port, "index.html").as_str())
+                .send();
+            if response.is_err() {
+                std::thread::sleep(std::time::Duration::from_secs(1));
+            }
+            response.ok()
+        })
+        .map(|r| r.status())
+        .next();
+
+    assert_eq!(Some(StatusCode::OK), status);
+}
+
+#[test]
+fn sni_selects_certificate() {
+    sni_server();
+
+    for name in &["alpha", "beta"] {
+        let (status, cert) = sni_get(Some(&format!("{}.test", name)));
+        assert_eq!("HTTP/1.1 200 OK", status);
+        assert_eq!(
+            sample_certificate(&format!("httprust-test-{}-cert.pem", name)),
+            cert
+        );
+    }
+}
+
+#[test]
+fn sni_default_certificate() {
+    sni_server();
+
+    let expected = sample_certificate("httprust-test-ec-cert.pem");
+
+    let (status, cert) = sni_get(None);
+    assert_eq!("HTTP/1.1 200 OK", status);
+    assert_eq!(expected, cert);
+
+    let (status, cert) = sni_get(Some("localhost"));
+    assert_eq!("HTTP/1.1 200 OK", status);
+    assert_eq!(expected, cert);
+}
+
+#[test]
+fn denied_connection_is_dropped() {
+    let (port, redirect_port) = denying_server();
+    let client = Client::builder()
+        .danger_accept_invalid_certs(true)
+        .redirect(reqwest::RedirectPolicy::none())
+        .build()
+        .expect("build client");
+
+    for uri in &[
+        make_https_uri(port, "index.html"),
+        format!("http://{}:{}/index.html", ADDRESS, redirect_port),
+    ] {
+        client
+            .get(uri.as_str())
+            .send()
+            .expect_err("connection should be dropped");
+    }
+}
+
+#[test]
+fn mtls_connect() {
+    mtls_server();
+
+    let response = Client::builder()
+        .danger_accept_invalid_certs(true)
+        .identity(client_identity())
+        .build()
+        .expect("build client")
+        .get(make_mtls_uri("index.html").as_str())
+        .send()
+        .expect("fail send");
+
+    assert_eq!(StatusCode::OK, response.status());
+}
+
+#[test]
+fn mtls_reject_without_certificate() {
+    mtls_server();
+
+    Client::builder()
+        .danger_accept_invalid_certs(true)
+        .build()
+        .expect("build client")
+        .get(make_mtls_uri("index.html").as_str())
+        .send()
+        .expect_err("this should fail");
+}
+
+#[test]
+fn mtls_subject_rules() {
+    mtls_server();
+
+    let response = Client::builder()
+        .danger_accept_invalid_certs(true)
+        .identity(client_identity())
+        .build()
+        .expect("build client")
+        .get(make_mtls_uri("hello.txt").as_str())
+        .send()
+        .expect("fail send");
+
+    assert_eq!(StatusCode::FORBIDDEN, response.status());
+}
@@ -0,0 +1,45 @@
+mod common;
+
+use common::*;
+
+// the service manager is found through the environment, which is only safe to change while no
+// other test runs: keep this the only test in its binary.
+//
+// notifies a fake service manager listening on the returned socket, asking for watchdog
+// keepalives every 100ms. Returns that socket and the port served on.
+fn systemd_server() -> (std::os::unix::net::UnixDatagram, u16) {
+    let path = std::env::temp_dir().join(format!("httprust-test-notify-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let manager = std::os::unix::net::UnixDatagram::bind(&path).expect("bind notify socket");
+    manager
+        .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+        .unwrap();
+
+    std::env::set_var("NOTIFY_SOCKET", &path);
+    std::env::set_var("WATCHDOG_USEC", "200000");
+    let ports = serve(httprust::Config {
+        port: 0,
+        local_only: true,
+        systemd: true,
+        root: sample_root(),
+        ..Default::default()
+    });
+
+    (manager, ports[0])
+}
+
+#[test]
+fn systemd_notifications() {
+    let (manager, port) = systemd_server();
+    let mut buffer = [0; 64];
+
+    let n = manager.recv(&mut buffer).expect("ready notification");
+    assert_eq!("READY=1", std::str::from_utf8(&buffer[..n]).unwrap());
+
+    let n = manager.recv(&mut buffer).expect("watchdog keepalive");
+    assert_eq!("WATCHDOG=1", std::str::from_utf8(&buffer[..n]).unwrap());
+
+    let response = reqwest::get(format!("http://{}:{}/index.html", ADDRESS, port).as_str())
+        .expect("fail send");
+    assert_eq!(StatusCode::OK, response.status());
+}
@@ -0,0 +1,176 @@
+mod common;
+
+use common::*;
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE, LOCATION};
+use reqwest::Method;
+
+fn tus_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert("tus-resumable", HeaderValue::from_static("1.0.0"));
+    headers
+}
+
+fn create(filename: &str, length: usize) -> Response {
+    let mut headers = tus_headers();
+    headers.insert("upload-length", HeaderValue::from(length));
+    headers.insert(
+        "upload-metadata",
+        HeaderValue::from_str(&format!("filename {}", base64::encode(filename))).unwrap(),
+    );
+
+    Client::new()
+        .post(make_upload_uri("files").as_str())
+        .headers(headers)
+        .send()
+        .expect("create upload")
+}
+
+fn location(response: &Response) -> String {
+    let location = response
+        .headers()
+        .get(LOCATION)
+        .expect("location")
+        .to_str()
+        .unwrap();
+    make_upload_uri(location.trim_start_matches('/'))
+}
+
+fn patch(location: &str, offset: usize, data: &'static [u8]) -> Response {
+    let mut headers = tus_headers();
+    headers.insert("upload-offset", HeaderValue::from(offset));
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("application/offset+octet-stream"),
+    );
+
+    Client::new()
+        .request(Method::PATCH, location)
+        .headers(headers)
+        .body(data)
+        .send()
+        .expect("patch upload")
+}
+
+fn head(location: &str) -> Response {
+    Client::new()
+        .head(location)
+        .headers(tus_headers())
+        .send()
+        .expect("head upload")
+}
+
+#[test]
+fn options_advertises_protocol() {
+    upload_server();
+
+    let response = Client::new()
+        .request(Method::OPTIONS, make_upload_uri("files").as_str())
+        .send()
+        .expect("options");
+
+    assert_eq!(StatusCode::NO_CONTENT, response.status());
+    assert_eq!("1.0.0", response.headers()["tus-version"]);
+    assert_eq!("creation,termination", response.headers()["tus-extension"]);
+    assert_eq!("1024", response.headers()["tus-max-size"]);
+}
+
+#[test]
+fn resumable_upload() {
+    let root = upload_server();
+
+    let response = create("sub/resumed.txt", 11);
+    assert_eq!(StatusCode::CREATED, response.status());
+    let location = location(&response);
+
+    let response = patch(&location, 0, b"hello ");
+    assert_eq!(StatusCode::NO_CONTENT, response.status());
+    assert_eq!("6", response.headers()["upload-offset"]);
+
+    let response = head(&location);
+    assert_eq!(StatusCode::OK, response.status());
+    assert_eq!("6", response.headers()["upload-offset"]);
+    assert_eq!("11", response.headers()["upload-length"]);
+    assert!(!root.join("sub").join("resumed.txt").exists());
+
+    let response = patch(&location, 3, b"world");
+    assert_eq!(StatusCode::CONFLICT, response.status());
+
+    let response = patch(&location, 6, b"world");
+    assert_eq!(StatusCode::NO_CONTENT, response.status());
+    assert_eq!("11", response.headers()["upload-offset"]);
+
+    let content = std::fs::read_to_string(root.join("sub").join("resumed.txt")).unwrap();
+    assert_eq!("hello world", content);
+
+    assert_eq!(StatusCode::NOT_FOUND, head(&location).status());
+}
+
+#[test]
+fn upload_can_not_exceed_its_length() {
+    upload_server();
+
+    let response = create("too-much.txt", 3);
+    let location = location(&response);
+
+    let response = patch(&location, 0, b"1234");
+    assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+}
+
+#[test]
+fn upload_larger_than_limit_is_refused() {
+    upload_server();
+
+    let response = create("huge.txt", 1025);
+    assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+}
+
+#[test]
+fn terminate_upload() {
+    upload_server();
+
+    let response = create("terminated.txt", 10);
+    let location = location(&response);
+
+    let response = Client::new()
+        .delete(location.as_str())
+        .headers(tus_headers())
+        .send()
+        .expect("delete upload");
+    assert_eq!(StatusCode::NO_CONTENT, response.status());
+
+    assert_eq!(StatusCode::NOT_FOUND, head(&location).status());
+}
+
+#[test]
+fn upload_requires_tus_version() {
+    upload_server();
+
+    let response = Client::new()
+        .post(make_upload_uri("files").as_str())
+        .send()
+        .expect("create upload");
+    assert_eq!(StatusCode::PRECONDITION_FAILED, response.status());
+}
+
+#[test]
+fn no_upload_outside_root() {
+    upload_server();
+
+    let response = create("../escaped.txt", 10);
+    assert_eq!(StatusCode::FORBIDDEN, response.status());
+}
+
+#[test]
+fn staging_area_is_not_served() {
+    upload_server();
+
+    let response = create("staged.txt", 10);
+    let location = location(&response);
+    let id = location.rsplit('/').next().unwrap();
+
+    let response = Client::new()
+        .get(make_upload_uri(&format!(".tus-staging/{}.info", id)).as_str())
+        .send()
+        .expect("get");
+    assert_eq!(StatusCode::NOT_FOUND, response.status());
+}